@@ -0,0 +1,447 @@
+//! Builder-style library API.
+//!
+//! A backup is described with `Backup::new(src, dest)`, configured with `BackupOptions`, and run with a `Reporter`
+//! that receives an event for every entry. The run returns a `BackupReport` instead of printing what happened:
+//!
+//! ```text
+//! let report = Backup::new("photos", "/media/me/RED/photos")
+//!     .options(BackupOptions::new().include_hidden(true).conflict(ConflictPolicy::Newer))
+//!     .run(&NoReporter)?;
+//! ```
+//!
+//! `TwoWaySync` and `SyncOptions` do the same for `ebod sync`.
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::checksum::{HashAlgorithm, VerifyMode};
+use crate::config::{ConflictPolicy, DirConfig, MtimeWindow};
+use crate::fat::FsCompat;
+use crate::filter::Filters;
+use crate::output::{Event, Reporter};
+use crate::{BackupSummary, backup_with_reporter, initialize_dir_with_config, sync_with_reporter};
+
+/// The side of a backup, named in the copies of conflicting files: `ebod-src-<name>` or `ebod-dest-<name>`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Side {
+    #[default]
+    Src,
+    Dest,
+}
+
+impl Side {
+    /// The name of the side in the copies of conflicting files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Src => "src",
+            Side::Dest => "dest",
+        }
+    }
+}
+
+/// The options of a `Backup`. The defaults are the ones of `ebod backup` without any flag.
+#[derive(Debug, Clone)]
+pub struct BackupOptions {
+    include_hidden: bool,
+    filters: Filters,
+    conflict: ConflictPolicy,
+    hash: HashAlgorithm,
+    verify: VerifyMode,
+    xattrs: bool,
+    acls: bool,
+    fs_compat: FsCompat,
+    mtime_window: Duration,
+    initialize: bool,
+    side: Side,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions::new()
+    }
+}
+
+impl BackupOptions {
+    /// The default options.
+    pub fn new() -> BackupOptions {
+        BackupOptions::from_config(&DirConfig::default())
+    }
+
+    /// The options set in a `.ebod/config.toml`.
+    pub fn from_config(config: &DirConfig) -> BackupOptions {
+        BackupOptions {
+            include_hidden: config.include_hidden,
+            filters: Filters::default(),
+            conflict: config.conflict,
+            hash: config.hash,
+            verify: config.verify,
+            xattrs: config.xattrs,
+            acls: config.acls,
+            fs_compat: config.fs_compat,
+            mtime_window: config.mtime_window.0,
+            initialize: true,
+            side: Side::Src,
+        }
+    }
+
+    /// Whether the hidden files and directories are backed up.
+    pub fn include_hidden(mut self, include_hidden: bool) -> BackupOptions {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// The filters that leave entries out of the backup.
+    ///
+    /// They apply when the backup is planned, not when the directories are indexed: `.ebod/metadata.json` describes
+    /// the whole directory, whatever the filters of a run are. So a later run with other filters finds every entry
+    /// of both sides, and the entries left out are reported as excluded instead of being missing.
+    pub fn filters(mut self, filters: Filters) -> BackupOptions {
+        self.filters = filters;
+        self
+    }
+
+    /// What is done with files that exist in the destination with a different modified time.
+    pub fn conflict(mut self, conflict: ConflictPolicy) -> BackupOptions {
+        self.conflict = conflict;
+        self
+    }
+
+    /// The hash algorithm used to verify the copies.
+    pub fn hash(mut self, hash: HashAlgorithm) -> BackupOptions {
+        self.hash = hash;
+        self
+    }
+
+    /// How the copies are verified.
+    pub fn verify(mut self, verify: VerifyMode) -> BackupOptions {
+        self.verify = verify;
+        self
+    }
+
+    /// Whether the extended attributes of the files are copied (Default = false).
+    pub fn xattrs(mut self, xattrs: bool) -> BackupOptions {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Whether the POSIX ACLs of the files are copied (Default = false).
+    pub fn acls(mut self, acls: bool) -> BackupOptions {
+        self.acls = acls;
+        self
+    }
+
+    /// The compatibility with a FAT32 or exFAT destination (Default = `FsCompat::Auto`).
+    pub fn fs_compat(mut self, fs_compat: FsCompat) -> BackupOptions {
+        self.fs_compat = fs_compat;
+        self
+    }
+
+    /// The tolerance of the comparison of modified times (Default = 0). Files whose modified times are at most this
+    /// far apart are considered unchanged.
+    pub fn mtime_window(mut self, mtime_window: Duration) -> BackupOptions {
+        self.mtime_window = mtime_window;
+        self
+    }
+
+    /// Whether the metadata of both directories is recorded again before the backup (Default = true). It can be
+    /// turned off when both directories were just initialized.
+    pub fn initialize(mut self, initialize: bool) -> BackupOptions {
+        self.initialize = initialize;
+        self
+    }
+
+    /// The side named in the copies of conflicting files (Default = `Side::Src`).
+    pub fn side(mut self, side: Side) -> BackupOptions {
+        self.side = side;
+        self
+    }
+
+    pub(crate) fn config(&self) -> DirConfig {
+        DirConfig {
+            include_hidden: self.include_hidden,
+            conflict: self.conflict,
+            hash: self.hash,
+            verify: self.verify,
+            xattrs: self.xattrs,
+            acls: self.acls,
+            fs_compat: self.fs_compat,
+            mtime_window: MtimeWindow(self.mtime_window),
+            ..DirConfig::default()
+        }
+    }
+}
+
+/// The options of a `TwoWaySync`. The defaults are the ones of `ebod sync` without any flag.
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    backup: BackupOptions,
+    remove_copies: bool,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions::new()
+    }
+}
+
+impl SyncOptions {
+    /// The default options.
+    pub fn new() -> SyncOptions {
+        SyncOptions { backup: BackupOptions::new(), remove_copies: true }
+    }
+
+    /// The options set in a `.ebod/config.toml`.
+    pub fn from_config(config: &DirConfig) -> SyncOptions {
+        SyncOptions { backup: BackupOptions::from_config(config), remove_copies: true }
+    }
+
+    /// Whether the hidden files and directories are synced.
+    pub fn include_hidden(mut self, include_hidden: bool) -> SyncOptions {
+        self.backup = self.backup.include_hidden(include_hidden);
+        self
+    }
+
+    /// The filters that leave entries out of both directions. Like for a backup, they apply when the sync is planned.
+    pub fn filters(mut self, filters: Filters) -> SyncOptions {
+        self.backup = self.backup.filters(filters);
+        self
+    }
+
+    /// What is done with files that exist on the other side with a different modified time.
+    pub fn conflict(mut self, conflict: ConflictPolicy) -> SyncOptions {
+        self.backup = self.backup.conflict(conflict);
+        self
+    }
+
+    /// The hash algorithm used to verify the copies.
+    pub fn hash(mut self, hash: HashAlgorithm) -> SyncOptions {
+        self.backup = self.backup.hash(hash);
+        self
+    }
+
+    /// How the copies are verified.
+    pub fn verify(mut self, verify: VerifyMode) -> SyncOptions {
+        self.backup = self.backup.verify(verify);
+        self
+    }
+
+    /// Whether the extended attributes of the files are copied (Default = false).
+    pub fn xattrs(mut self, xattrs: bool) -> SyncOptions {
+        self.backup = self.backup.xattrs(xattrs);
+        self
+    }
+
+    /// Whether the POSIX ACLs of the files are copied (Default = false).
+    pub fn acls(mut self, acls: bool) -> SyncOptions {
+        self.backup = self.backup.acls(acls);
+        self
+    }
+
+    /// The compatibility with a FAT32 or exFAT destination (Default = `FsCompat::Auto`).
+    pub fn fs_compat(mut self, fs_compat: FsCompat) -> SyncOptions {
+        self.backup = self.backup.fs_compat(fs_compat);
+        self
+    }
+
+    /// The tolerance of the comparison of modified times (Default = 0).
+    pub fn mtime_window(mut self, mtime_window: Duration) -> SyncOptions {
+        self.backup = self.backup.mtime_window(mtime_window);
+        self
+    }
+
+    /// Whether the `ebod-src-` copies that came back into the source are deleted after the sync (Default = true).
+    pub fn remove_copies(mut self, remove_copies: bool) -> SyncOptions {
+        self.remove_copies = remove_copies;
+        self
+    }
+}
+
+/// The outcome of a `Backup` or a `TwoWaySync`.
+///
+/// # Members
+/// * `src: PathBuf` -> The source directory
+/// * `dest: PathBuf` -> The destination directory
+/// * `summary: BackupSummary` -> The counts of what was done
+/// * `renamed: Vec<(String, String)>` -> The conflicting files that were copied under a new name, with that name
+/// * `duration: Duration` -> How long the run took
+#[derive(Debug, Clone)]
+pub struct BackupReport {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    pub summary: BackupSummary,
+    pub renamed: Vec<(String, String)>,
+    pub duration: Duration,
+}
+
+// Passes the events on to the reporter of the user, and keeps what goes into the report
+struct Collector<'a> {
+    inner: &'a dyn Reporter,
+    renamed: RefCell<Vec<(String, String)>>,
+}
+
+impl Reporter for Collector<'_> {
+    fn report(&self, event: &Event) {
+        if let Event::Renamed { path, to, .. } = event {
+            self.renamed.borrow_mut().push((path.clone(), to.clone()));
+        }
+        self.inner.report(event);
+    }
+}
+
+fn check_dirs(src: &Path, dest: &Path) -> Result<(), String> {
+    for dir in [src, dest] {
+        if !dir.is_dir() {
+            return Err(format!("The directory {} doesn't exist", dir.to_string_lossy()));
+        }
+    }
+    Ok(())
+}
+
+/// A backup of a source directory into a destination directory.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    src: PathBuf,
+    dest: PathBuf,
+    options: BackupOptions,
+}
+
+impl Backup {
+    /// A backup of `src` into `dest` with the default options.
+    pub fn new(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Backup {
+        Backup { src: src.as_ref().to_path_buf(), dest: dest.as_ref().to_path_buf(), options: BackupOptions::new() }
+    }
+
+    /// Sets the options of the backup.
+    pub fn options(mut self, options: BackupOptions) -> Backup {
+        self.options = options;
+        self
+    }
+
+    /// Runs the backup. Both directories must exist.
+    ///
+    /// # Input
+    /// * `reporter: &dyn Reporter` -> Receives an event for every entry of the source
+    ///
+    /// # Output: `Result<BackupReport, String>`
+    /// What was done, or the error that stopped the backup.
+    pub fn run(&self, reporter: &dyn Reporter) -> Result<BackupReport, String> {
+        check_dirs(&self.src, &self.dest)?;
+        let start = Instant::now();
+        let options = &self.options;
+        if options.initialize {
+            // Both sides are indexed without the filters, which are applied to the source by the plan
+            initialize_dir_with_config(&self.src, &Filters::default(), &options.config());
+            initialize_dir_with_config(&self.dest, &Filters::default(), &options.config());
+        }
+        let collector = Collector { inner: reporter, renamed: RefCell::new(vec![]) };
+        let summary = backup_with_reporter(&self.src, &self.dest, options.side.as_str(), &options.filters, &options.config(), &collector)?;
+        Ok(BackupReport {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            summary,
+            renamed: collector.renamed.into_inner(),
+            duration: start.elapsed(),
+        })
+    }
+}
+
+/// A sync of two directories: each one is backed up into the other.
+#[derive(Debug, Clone)]
+pub struct TwoWaySync {
+    src: PathBuf,
+    dest: PathBuf,
+    options: SyncOptions,
+}
+
+impl TwoWaySync {
+    /// A sync of `src` and `dest` with the default options.
+    pub fn new(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> TwoWaySync {
+        TwoWaySync { src: src.as_ref().to_path_buf(), dest: dest.as_ref().to_path_buf(), options: SyncOptions::new() }
+    }
+
+    /// Sets the options of the sync.
+    pub fn options(mut self, options: SyncOptions) -> TwoWaySync {
+        self.options = options;
+        self
+    }
+
+    /// Runs the sync. Both directories must exist.
+    ///
+    /// # Input
+    /// * `reporter: &dyn Reporter` -> Receives an event for every entry of both directions
+    ///
+    /// # Output: `Result<BackupReport, String>`
+    /// What was done in both directions, or the error that stopped the sync.
+    pub fn run(&self, reporter: &dyn Reporter) -> Result<BackupReport, String> {
+        check_dirs(&self.src, &self.dest)?;
+        let start = Instant::now();
+        let options = &self.options.backup;
+        if options.initialize {
+            // Both sides are indexed without the filters, which are applied to the source by the plan
+            initialize_dir_with_config(&self.src, &Filters::default(), &options.config());
+            initialize_dir_with_config(&self.dest, &Filters::default(), &options.config());
+        }
+        let collector = Collector { inner: reporter, renamed: RefCell::new(vec![]) };
+        let summary = sync_with_reporter(&self.src, &self.dest, &options.filters, &options.config(), self.options.remove_copies, &collector)?;
+        Ok(BackupReport {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            summary,
+            renamed: collector.renamed.into_inner(),
+            duration: start.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::NoReporter;
+    use std::fs::{self, File};
+    use std::time::SystemTime;
+
+    // Writes `content` into `path`, modified `age` seconds ago
+    fn write(path: &Path, content: &str, age: u64) {
+        fs::write(path, content).unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+    }
+
+    #[test]
+    fn backups_report_what_they_did() {
+        let root = std::env::temp_dir().join(format!("ebod-api-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (src, dest) = (root.join("src"), root.join("dest"));
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        write(&src.join("a.txt"), "new a", 60);
+        write(&src.join("docs/b.txt"), "bb", 60);
+        write(&src.join("debug.log"), "log", 60);
+        write(&src.join(".hidden"), "h", 60);
+        write(&dest.join("a.txt"), "old a", 3600);
+
+        let filters = Filters::new(&[], &[String::from("*.log")], None, None, None, None).unwrap();
+        let options = BackupOptions::new().filters(filters.clone()).conflict(ConflictPolicy::Rename);
+        let report = Backup::new(&src, &dest).options(options).run(&NoReporter).unwrap();
+        assert_eq!((report.src.as_path(), report.dest.as_path()), (src.as_path(), dest.as_path()));
+        let summary = &report.summary;
+        assert_eq!((summary.copied, summary.renamed, summary.dirs_created, summary.excluded), (1, 1, 1, 1));
+        assert_eq!((summary.overwritten, summary.kept, summary.identical), (0, 0, 0));
+        assert_eq!(summary.bytes, 7);
+        assert_eq!(report.renamed, [(String::from("a.txt"), String::from("ebod-src-a.txt"))]);
+        assert_eq!(fs::read_to_string(dest.join("ebod-src-a.txt")).unwrap(), "new a");
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "old a");
+        assert!(!dest.join("debug.log").exists() && !dest.join(".hidden").exists());
+
+        // Run again with the files of the source taking precedence: only the conflicting file is copied
+        let options = BackupOptions::new().filters(filters).conflict(ConflictPolicy::Newer);
+        let report = Backup::new(&src, &dest).options(options).run(&NoReporter).unwrap();
+        let summary = &report.summary;
+        assert_eq!((summary.copied, summary.renamed, summary.overwritten, summary.kept), (0, 0, 1, 0));
+        assert_eq!((summary.identical, summary.excluded, summary.bytes), (1, 1, 5));
+        assert!(report.renamed.is_empty());
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "new a");
+
+        assert!(Backup::new(root.join("missing"), &dest).run(&NoReporter).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+//! Tar archive output target.
+//!
+//! Writes the files of an initialized directory into a single tar stream, optionally compressed with zstd when the
+//! archive name ends with `.zst`. Paths, modification times, modes and symlinks are kept as they are on disk. The
+//! metadata of the whole source directory is embedded into the archive as the member `.ebod/metadata.json`, so that a
+//! later incremental archive can be created with just the changes since this one.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::output::{self, Event};
+use crate::{EntryType, FileEntry, LogType, log, read_metadata};
+
+/// The name of the member in which the metadata of the source directory is embedded.
+pub const METADATA_MEMBER: &str = ".ebod/metadata.json";
+
+fn is_zstd(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "zst")
+}
+
+/// Reads the metadata embedded into an archive that was written by `backup_to_archive`.
+///
+/// # Input
+/// * `archive: &Path` -> The path of the `.tar` or `.tar.zst` archive
+///
+/// # Output: `Result<Vec<FileEntry>, String>`
+/// The embedded metadata or an error message if the archive doesn't contain it.
+pub fn read_archive_metadata(archive: &Path) -> Result<Vec<FileEntry>, String> {
+    let file = File::open(archive).map_err(|_| format!("Couldn't open the archive {}", archive.to_string_lossy()))?;
+    if is_zstd(archive) {
+        let decoder = zstd::Decoder::new(file).map_err(|_| String::from("Error in reading the zstd stream"))?;
+        find_metadata(tar::Archive::new(decoder))
+    } else {
+        find_metadata(tar::Archive::new(file))
+    }
+}
+
+fn find_metadata<R: Read>(mut archive: tar::Archive<R>) -> Result<Vec<FileEntry>, String> {
+    let entries = archive.entries().map_err(|_| String::from("Error in reading the archive"))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|_| String::from("Error in reading an archive member"))?;
+        if entry.path().is_ok_and(|path| path == Path::new(METADATA_MEMBER)) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).map_err(|_| String::from("Error in reading the embedded metadata"))?;
+            return serde_json::from_str(&content).map_err(|_| String::from("Error in parsing the embedded metadata"));
+        }
+    }
+    Err(String::from("The archive doesn't contain ebod metadata"))
+}
+
+/// Backs up the initialized `src` directory into a tar archive.
+///
+/// # Input
+/// * `src: &Path` -> The source directory. It must be initialized
+/// * `archive: &Path` -> The path of the archive to write. It is compressed with zstd if it ends with `.zst`
+/// * `previous: Option<&Path>` -> A previous archive. If given, only the entries that changed since it are written
+///
+/// # Output: `Result<(), String>`
+///
+/// # Rules followed:
+/// 1. Entries under `.ebod/` of the source are never archived, as the metadata is embedded separately.
+/// 2. In an incremental archive, an entry is written if it isn't in the previous metadata, or if its type,
+///    `modified_at` or `length` differs from it.
+/// 3. The embedded metadata always describes the whole source directory, so incremental archives can be chained.
+pub fn backup_to_archive(src: &Path, archive: &Path, previous: Option<&Path>) -> Result<(), String> {
+    let src_meta: Vec<FileEntry> = read_metadata(&src.join(".ebod/metadata.json"))?
+        .into_iter()
+        .filter(|file| !Path::new(&file.name).starts_with(".ebod") && !inside_symlink(src, &file.name))
+        .collect();
+    let prev_meta = match previous {
+        Some(prev) => {
+            let meta = read_archive_metadata(prev)?;
+            log(LogType::Info, &format!("Writing the changes since {}", prev.to_string_lossy()));
+            meta
+        }
+        None => vec![],
+    };
+
+    let prev_by_name: HashMap<&str, &FileEntry> = prev_meta.iter().map(|old| (old.name.as_str(), old)).collect();
+    let changed: Vec<&FileEntry> = src_meta
+        .iter()
+        .filter(|file| {
+            !prev_by_name.get(file.name.as_str()).is_some_and(|old| {
+                old.e_type == file.e_type && old.mtime_diff(file).is_zero() && old.length == file.length
+            })
+        })
+        .collect();
+    let metadata = serde_json::to_vec_pretty(&src_meta).map_err(|_| String::from("Error during serializing metadata"))?;
+
+    let mut tmp_name = archive.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+    let file = File::create(&tmp).map_err(|_| format!("Couldn't create the archive {}", archive.to_string_lossy()))?;
+    let result = if is_zstd(archive) {
+        zstd::Encoder::new(file, 0)
+            .map_err(|_| String::from("Error in creating the zstd stream"))
+            .and_then(|encoder| write_members(encoder, src, &changed, &metadata))
+            .and_then(|encoder| encoder.finish().map_err(|_| String::from("Error in finishing the zstd stream")))
+            .map(|_| ())
+    } else {
+        write_members(file, src, &changed, &metadata).map(|_| ())
+    };
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    fs::rename(&tmp, archive).map_err(|_| format!("Couldn't write the archive {}", archive.to_string_lossy()))?;
+
+    log(LogType::Ok, &format!("{} entries were written into {}", changed.len(), archive.to_string_lossy()));
+    if previous.is_some() {
+        log(LogType::Info, &format!("{} entries were unchanged and hence were not archived", src_meta.len() - changed.len()));
+    }
+    Ok(())
+}
+
+// The listing follows symlinked directories, but the archive stores the symlink itself. So their contents are skipped.
+pub(crate) fn inside_symlink(src: &Path, name: &str) -> bool {
+    Path::new(name)
+        .ancestors()
+        .skip(1)
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| src.join(ancestor).is_symlink())
+}
+
+fn write_members<W: Write>(writer: W, src: &Path, files: &[&FileEntry], metadata: &[u8]) -> Result<W, String> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(metadata.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    header.set_entry_type(tar::EntryType::Regular);
+    builder
+        .append_data(&mut header, METADATA_MEMBER, metadata)
+        .map_err(|_| String::from("Error in embedding the metadata"))?;
+
+    for file in files {
+        let path = src.join(&file.name);
+        let result = if file.e_type == EntryType::Dir && !path.is_symlink() {
+            builder.append_dir(&file.name, &path)
+        } else {
+            builder.append_path_with_name(&path, &file.name)
+        };
+        if result.is_err() {
+            return Err(format!("Error in archiving {}", &file.name));
+        }
+        log(LogType::Ok, &format!("Archived: {}", &file.name));
+        output::emit(if file.e_type == EntryType::Dir {
+            Event::DirCreated { path: file.name.clone() }
+        } else {
+            Event::Copied { path: file.name.clone(), bytes: file.length, method: None }
+        });
+    }
+    builder.into_inner().map_err(|_| String::from("Error in finishing the archive"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialize_dir;
+    use std::time::Duration;
+
+    // The members of `archive` with their types, and the target of the symlinks
+    fn members(archive: &Path) -> Vec<(String, tar::EntryType, Option<String>)> {
+        let file = File::open(archive).unwrap();
+        let reader: Box<dyn Read> = if is_zstd(archive) { Box::new(zstd::Decoder::new(file).unwrap()) } else { Box::new(file) };
+        let mut members: Vec<_> = tar::Archive::new(reader)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let target = entry.link_name().unwrap().map(|target| target.to_string_lossy().to_string());
+                (entry.path().unwrap().to_string_lossy().to_string(), entry.header().entry_type(), target)
+            })
+            .collect();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        members
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn incremental_archives_only_hold_the_changes() {
+        let root = std::env::temp_dir().join(format!("ebod-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let src = root.join("src");
+        fs::create_dir_all(src.join("docs")).unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for (name, content) in [("a.txt", "a"), ("docs/b.txt", "b")] {
+            fs::write(src.join(name), content).unwrap();
+            File::options().write(true).open(src.join(name)).unwrap().set_modified(an_hour_ago).unwrap();
+        }
+        std::os::unix::fs::symlink("a.txt", src.join("link")).unwrap();
+
+        for name in ["full.tar", "full.tar.zst"] {
+            initialize_dir(&src, false);
+            let full = root.join(name);
+            backup_to_archive(&src, &full, None).unwrap();
+            let names: Vec<String> = members(&full).into_iter().map(|(name, _, _)| name).collect();
+            assert_eq!(names, [METADATA_MEMBER, "a.txt", "docs", "docs/b.txt", "link"]);
+            let link = members(&full).into_iter().find(|(name, _, _)| name == "link").unwrap();
+            assert_eq!((link.1, link.2.as_deref()), (tar::EntryType::Symlink, Some("a.txt")));
+            assert_eq!(read_archive_metadata(&full).unwrap().len(), 4);
+
+            fs::write(src.join("docs/b.txt"), format!("b changed for {}", name)).unwrap();
+            initialize_dir(&src, false);
+            let incremental = root.join(format!("incremental-{}", name));
+            backup_to_archive(&src, &incremental, Some(&full)).unwrap();
+            let names: Vec<String> = members(&incremental).into_iter().map(|(name, _, _)| name).collect();
+            assert_eq!(names, [METADATA_MEMBER, "docs/b.txt"]);
+            // The embedded metadata still describes the whole source
+            assert_eq!(read_archive_metadata(&incremental).unwrap().len(), 4);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
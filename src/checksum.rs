@@ -0,0 +1,158 @@
+//! Hashing of file contents and verification of copied files.
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::vfs::{LocalFs, Vfs};
+
+/// The hash algorithm used to compare file contents.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+/// How a copied file is verified against its source.
+///
+/// # Values in Enum
+/// * `None` -> The copy is not verified
+/// * `Size` -> The size of the copy is compared with the size of the source
+/// * `Hash` -> The contents of the copy are hashed and compared with the hash of the source
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    #[default]
+    None,
+    Size,
+    Hash,
+}
+
+/// Hashes the contents of the file at `path` and returns the digest as a hex string.
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    hash_reader(File::open(path)?, algorithm)
+}
+
+/// Hashes everything read from `reader` and returns the digest as a hex string.
+pub fn hash_reader(mut reader: impl Read, algorithm: HashAlgorithm) -> io::Result<String> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            loop {
+                let n = reader.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+            }
+            Ok(hex::encode(hasher.finalize()))
+        }
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            loop {
+                let n = reader.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+            }
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+    }
+}
+
+// Hashes the file `path` of `vfs`
+fn hash_in<V: Vfs + ?Sized>(vfs: &V, path: &Path, algorithm: HashAlgorithm) -> Result<String, String> {
+    vfs.open(path).and_then(|reader| hash_reader(reader, algorithm)).map_err(|_| format!("Couldn't hash {}", path.to_string_lossy()))
+}
+
+/// Verifies the file copied from `src` to `dest` with the given mode.
+///
+/// # Output: `Result<(), String>`
+/// An error message describing the mismatch, if the copy doesn't match its source.
+pub fn verify_copy(src: &Path, dest: &Path, mode: VerifyMode, algorithm: HashAlgorithm) -> Result<(), String> {
+    verify_copy_in(&LocalFs, src, &LocalFs, dest, mode, algorithm)
+}
+
+/// Same as `verify_copy`, with the source read from `src_vfs` and the copy from `dest_vfs`.
+pub fn verify_copy_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(
+    src_vfs: &S,
+    src: &Path,
+    dest_vfs: &D,
+    dest: &Path,
+    mode: VerifyMode,
+    algorithm: HashAlgorithm,
+) -> Result<(), String> {
+    match mode {
+        VerifyMode::None => Ok(()),
+        VerifyMode::Size => {
+            let src_len = src_vfs.stat(src).map(|s| s.length).map_err(|_| format!("Couldn't read {}", src.to_string_lossy()))?;
+            let dest_len = dest_vfs.stat(dest).map(|s| s.length).map_err(|_| format!("Couldn't read {}", dest.to_string_lossy()))?;
+            if src_len != dest_len {
+                return Err(format!("Size of {} doesn't match its source ({} != {} bytes)", dest.to_string_lossy(), dest_len, src_len));
+            }
+            Ok(())
+        }
+        VerifyMode::Hash => {
+            let src_hash = hash_in(src_vfs, src, algorithm)?;
+            let dest_hash = hash_in(dest_vfs, dest, algorithm)?;
+            if src_hash != dest_hash {
+                return Err(format!("Contents of {} don't match its source", dest.to_string_lossy()));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Same as `verify_copy_in`, for a file stored in parts on either side, like the files split for FAT32: the parts
+/// `srcs` are compared, one after the other, with the parts `dests`.
+pub fn verify_parts_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(
+    src_vfs: &S,
+    srcs: &[PathBuf],
+    dest_vfs: &D,
+    dests: &[PathBuf],
+    mode: VerifyMode,
+    algorithm: HashAlgorithm,
+) -> Result<(), String> {
+    if let ([src], [dest]) = (srcs, dests) {
+        return verify_copy_in(src_vfs, src, dest_vfs, dest, mode, algorithm);
+    }
+    let name = dests.first().map(|dest| dest.to_string_lossy().to_string()).unwrap_or_default();
+    match mode {
+        VerifyMode::None => Ok(()),
+        VerifyMode::Size => {
+            let length = |vfs: &dyn Fn(&Path) -> io::Result<u64>, paths: &[PathBuf]| -> Result<u64, String> {
+                paths.iter().map(|path| vfs(path).map_err(|_| format!("Couldn't read {}", path.to_string_lossy()))).sum()
+            };
+            let src_len = length(&|path| src_vfs.stat(path).map(|s| s.length), srcs)?;
+            let dest_len = length(&|path| dest_vfs.stat(path).map(|s| s.length), dests)?;
+            if src_len != dest_len {
+                return Err(format!("Size of {} doesn't match its source ({} != {} bytes)", name, dest_len, src_len));
+            }
+            Ok(())
+        }
+        VerifyMode::Hash => {
+            let src_hash = hash_parts_in(src_vfs, srcs, algorithm)?;
+            let dest_hash = hash_parts_in(dest_vfs, dests, algorithm)?;
+            if src_hash != dest_hash {
+                return Err(format!("Contents of {} don't match its source", name));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Hashes the files `paths` of `vfs` one after the other, as if they were a single file
+fn hash_parts_in<V: Vfs + ?Sized>(vfs: &V, paths: &[PathBuf], algorithm: HashAlgorithm) -> Result<String, String> {
+    let mut reader: Box<dyn Read + '_> = Box::new(io::empty());
+    for path in paths {
+        reader = Box::new(reader.chain(vfs.open(path).map_err(|_| format!("Couldn't hash {}", path.to_string_lossy()))?));
+    }
+    hash_reader(reader, algorithm).map_err(|_| String::from("Couldn't hash the parts of a file"))
+}
@@ -0,0 +1,298 @@
+//! Per-directory configuration stored in `.ebod/config.toml`.
+//!
+//! The file is created with the default values by `ebod init`. `backup` and `sync` read the configuration of the source
+//! directory and merge it with the command line, in this order of precedence:
+//! 1. The flags given on the command line
+//! 2. The values in `<SRC>/.ebod/config.toml`
+//! 3. The built-in defaults
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::checksum::{HashAlgorithm, VerifyMode};
+use crate::drive::MOUNTINFO_PATH;
+use crate::fat::{DestFs, FsCompat};
+use crate::vfs::{LocalFs, Vfs};
+use crate::xattrs::Preserve;
+
+/// What the backup does with a file that exists in the destination with a different `modified_at`.
+///
+/// # Values in Enum
+/// * `Rename` -> The file is copied under the name `ebod-<src|dest>-<file_name>` (the default)
+/// * `Skip` -> The file in the destination is kept and the file is not copied
+/// * `Overwrite` -> The file in the destination is overwritten
+/// * `Newer` -> The file in the destination is overwritten only if the source file is newer
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    #[default]
+    Rename,
+    Skip,
+    Overwrite,
+    Newer,
+}
+
+/// The tolerance of the comparison of modified times, set with `--mtime-window`. Two files whose modified times are
+/// at most this far apart are considered unchanged. It is written as a number and a unit: `2s`, `500ms`, `1.5s`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub struct MtimeWindow(pub Duration);
+
+impl FromStr for MtimeWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<MtimeWindow, String> {
+        let value = value.trim();
+        let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: f64 = number.parse().map_err(|_| format!("Invalid mtime window: {}", value))?;
+        let nanos_per_unit = match unit {
+            "ns" => 1.0,
+            "us" => 1e3,
+            "ms" => 1e6,
+            "" | "s" => 1e9,
+            "m" => 60e9,
+            _ => return Err(format!("Invalid unit in the mtime window {}. Use ns, us, ms, s or m", value)),
+        };
+        Ok(MtimeWindow(Duration::from_nanos((number * nanos_per_unit).round() as u64)))
+    }
+}
+
+impl fmt::Display for MtimeWindow {
+    // Written with the largest unit that keeps it exact
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nanos = self.0.as_nanos();
+        match nanos {
+            _ if nanos.is_multiple_of(1_000_000_000) => write!(f, "{}s", nanos / 1_000_000_000),
+            _ if nanos.is_multiple_of(1_000_000) => write!(f, "{}ms", nanos / 1_000_000),
+            _ if nanos.is_multiple_of(1_000) => write!(f, "{}us", nanos / 1_000),
+            _ => write!(f, "{}ns", nanos),
+        }
+    }
+}
+
+impl Serialize for MtimeWindow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MtimeWindow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MtimeWindow, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The settings stored in `.ebod/config.toml`.
+///
+/// # Members
+/// * `include_hidden: bool` -> Includes the hidden files and directories
+/// * `ignore: Vec<String>` -> Patterns in the `.ebodignore` syntax, applied in addition to the `.ebodignore` files
+/// * `conflict: ConflictPolicy` -> What is done with conflicting files
+/// * `hash: HashAlgorithm` -> The algorithm used to hash file contents
+/// * `verify: VerifyMode` -> How copied files are verified
+/// * `xattrs: bool` -> Copies the extended attributes of the files
+/// * `acls: bool` -> Copies the POSIX ACLs of the files
+/// * `fs_compat: FsCompat` -> The compatibility with a FAT or exFAT destination
+/// * `mtime_window: MtimeWindow` -> The tolerance of the comparison of modified times
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct DirConfig {
+    pub include_hidden: bool,
+    pub ignore: Vec<String>,
+    pub conflict: ConflictPolicy,
+    pub hash: HashAlgorithm,
+    pub verify: VerifyMode,
+    pub xattrs: bool,
+    pub acls: bool,
+    pub fs_compat: FsCompat,
+    pub mtime_window: MtimeWindow,
+}
+
+/// The keys that can be used with `ebod config get/set`.
+pub const CONFIG_KEYS: [&str; 9] = ["include_hidden", "ignore", "conflict", "hash", "verify", "xattrs", "acls", "fs_compat", "mtime_window"];
+
+/// The path of the configuration file of the directory.
+pub fn config_path(dir: &Path) -> PathBuf {
+    dir.join(".ebod/config.toml")
+}
+
+fn enum_value<T: ValueEnum>(value: &T) -> String {
+    value.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+}
+
+fn parse_enum<T: ValueEnum>(key: &str, value: &str) -> Result<T, String> {
+    T::from_str(value, true).map_err(|_| {
+        let values: Vec<String> = T::value_variants().iter().map(enum_value).collect();
+        format!("Invalid value {} for {}. Possible values: {}", value, key, values.join(", "))
+    })
+}
+
+impl DirConfig {
+    /// The attributes copied by the backup.
+    pub fn preserve(&self) -> Preserve {
+        Preserve { xattrs: self.xattrs, acls: self.acls }
+    }
+
+    /// The tolerance of the comparison of modified times with the destination on `dest_fs`: `mtime_window`, or more
+    /// if the destination stores coarser times.
+    pub fn mtime_window(&self, dest_fs: DestFs) -> Duration {
+        self.mtime_window.0.max(dest_fs.mtime_tolerance())
+    }
+
+    /// The filesystem of the local destination `dest`, detected or set by `fs_compat`.
+    pub fn dest_fs(&self, dest: &Path) -> DestFs {
+        self.fs_compat.resolve(dest, Path::new(MOUNTINFO_PATH))
+    }
+
+    /// Loads the configuration of the directory. The defaults are returned if the directory has no `config.toml`.
+    pub fn load(dir: &Path) -> Result<DirConfig, String> {
+        DirConfig::load_in(&LocalFs, dir)
+    }
+
+    /// Same as `load`, with the `config.toml` read from `vfs`.
+    pub fn load_in<V: Vfs + ?Sized>(vfs: &V, dir: &Path) -> Result<DirConfig, String> {
+        let path = config_path(dir);
+        if !vfs.exists(&path) {
+            return Ok(DirConfig::default());
+        }
+        let content = vfs.read(&path).map_err(|_| format!("Error in reading {}", path.to_string_lossy()))?;
+        toml::from_str(&String::from_utf8_lossy(&content)).map_err(|e| format!("Error in parsing {}: {}", path.to_string_lossy(), e.message()))
+    }
+
+    /// Writes the configuration into `.ebod/config.toml` of the directory.
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        let path = config_path(dir);
+        let content = toml::to_string_pretty(self).map_err(|_| String::from("Error during serializing data into toml"))?;
+        let content = format!("# ebod configuration. Values given on the command line take precedence over this file.\n{}", content);
+        fs::create_dir_all(dir.join(".ebod")).map_err(|_| String::from("Couldn't create the .ebod directory"))?;
+        fs::write(&path, content).map_err(|_| format!("Error in writing {}", path.to_string_lossy()))
+    }
+
+    /// Returns the value of `key` as it is shown by `ebod config get`.
+    pub fn get(&self, key: &str) -> Result<String, String> {
+        match key {
+            "include_hidden" => Ok(self.include_hidden.to_string()),
+            "ignore" => Ok(self.ignore.join(",")),
+            "conflict" => Ok(enum_value(&self.conflict)),
+            "hash" => Ok(enum_value(&self.hash)),
+            "verify" => Ok(enum_value(&self.verify)),
+            "xattrs" => Ok(self.xattrs.to_string()),
+            "acls" => Ok(self.acls.to_string()),
+            "fs_compat" => Ok(enum_value(&self.fs_compat)),
+            "mtime_window" => Ok(self.mtime_window.to_string()),
+            _ => Err(format!("Unknown key {}. Possible keys: {}", key, CONFIG_KEYS.join(", "))),
+        }
+    }
+
+    /// Sets `key` from the value given to `ebod config set`. `ignore` takes a comma separated list of patterns.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "include_hidden" => {
+                self.include_hidden = value.parse().map_err(|_| format!("Invalid value {} for include_hidden. Use true or false", value))?
+            }
+            "ignore" => {
+                self.ignore = value.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect()
+            }
+            "conflict" => self.conflict = parse_enum("conflict", value)?,
+            "hash" => self.hash = parse_enum("hash", value)?,
+            "verify" => self.verify = parse_enum("verify", value)?,
+            "xattrs" => self.xattrs = value.parse().map_err(|_| format!("Invalid value {} for xattrs. Use true or false", value))?,
+            "acls" => self.acls = value.parse().map_err(|_| format!("Invalid value {} for acls. Use true or false", value))?,
+            "fs_compat" => self.fs_compat = parse_enum("fs_compat", value)?,
+            "mtime_window" => self.mtime_window = value.parse()?,
+            _ => return Err(format!("Unknown key {}. Possible keys: {}", key, CONFIG_KEYS.join(", "))),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_set_are_read_back() {
+        let mut config = DirConfig::default();
+        let values = [
+            ("include_hidden", "true"),
+            ("ignore", "*.tmp,build/"),
+            ("conflict", "newer"),
+            ("hash", "blake3"),
+            ("verify", "hash"),
+            ("xattrs", "true"),
+            ("acls", "true"),
+            ("fs_compat", "exfat"),
+            ("mtime_window", "2s"),
+        ];
+        assert_eq!(values.len(), CONFIG_KEYS.len());
+        for (key, value) in values {
+            config.set(key, value).unwrap();
+            assert_eq!(config.get(key).unwrap(), value);
+        }
+        config.set("ignore", " a , ,b").unwrap();
+        assert_eq!(config.ignore, ["a", "b"]);
+
+        assert!(config.set("conflict", "sometimes").is_err());
+        assert!(config.set("xattrs", "yes").is_err());
+        assert!(config.set("mtime_window", "2 days").is_err());
+        assert!(config.set("colour", "red").is_err());
+        assert!(config.get("colour").is_err());
+    }
+
+    #[test]
+    fn mtime_windows_are_parsed_and_written_back() {
+        for (value, duration, written) in [
+            ("1.5s", Duration::from_millis(1500), "1500ms"),
+            ("250ms", Duration::from_millis(250), "250ms"),
+            ("2", Duration::from_secs(2), "2s"),
+            ("2s", Duration::from_secs(2), "2s"),
+            ("1m", Duration::from_secs(60), "60s"),
+            ("0.5us", Duration::from_nanos(500), "500ns"),
+            (" 10us ", Duration::from_micros(10), "10us"),
+        ] {
+            let window: MtimeWindow = value.parse().unwrap();
+            assert_eq!(window.0, duration, "{}", value);
+            assert_eq!(window.to_string(), written);
+            assert_eq!(written.parse::<MtimeWindow>().unwrap(), window);
+        }
+        for value in ["", "s", "2 s", "2h", "-1s"] {
+            assert!(value.parse::<MtimeWindow>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn the_config_file_is_saved_and_loaded() {
+        let dir = std::env::temp_dir().join(format!("ebod-config-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(DirConfig::load(&dir).unwrap(), DirConfig::default());
+
+        let mut config = DirConfig::default();
+        config.set("conflict", "skip").unwrap();
+        config.set("mtime_window", "1.5s").unwrap();
+        config.set("ignore", "*.iso").unwrap();
+        config.save(&dir).unwrap();
+        assert_eq!(DirConfig::load(&dir).unwrap(), config);
+
+        // Missing keys keep their defaults, and unknown values are reported
+        fs::write(config_path(&dir), "acls = true\n").unwrap();
+        assert_eq!(DirConfig::load(&dir).unwrap(), DirConfig { acls: true, ..DirConfig::default() });
+        fs::write(config_path(&dir), "conflict = \"sometimes\"\n").unwrap();
+        assert!(DirConfig::load(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn init_records_include_hidden() {
+        let dir = std::env::temp_dir().join(format!("ebod-config-init-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        crate::initialize_dir_with_filters(&dir, true, &crate::filter::Filters::default());
+        assert!(DirConfig::load(&dir).unwrap().include_hidden);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
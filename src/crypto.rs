@@ -0,0 +1,504 @@
+//! Client-side encryption of backups.
+//!
+//! An encrypted backup is stored as a *repository* instead of a plain copy of the directory tree:
+//!
+//! ```text
+//! <dest>/.ebod/repository.json   -> KDF salt and parameters (not secret)
+//! <dest>/.ebod/index.enc         -> The encrypted metadata index (Vec<RepoEntry> as JSON)
+//! <dest>/data/<ab>/<object-id>   -> The encrypted contents of each file
+//! ```
+//!
+//! The key is derived from a passphrase with Argon2id. File contents are encrypted with XChaCha20-Poly1305 in 64 KiB
+//! chunks (STREAM construction), so that large files never have to be held in memory. Object ids are a keyed hash of
+//! the relative file name, so neither the file names nor the directory structure are visible in the repository.
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::output::{self, Event};
+use crate::{EntryType, FileEntry, LogType, log, read_metadata};
+
+/// Environment variable from which the passphrase is read when no key file is given.
+pub const PASSPHRASE_ENV: &str = "EBOD_PASSPHRASE";
+
+const REPO_VERSION: u32 = 1;
+const MAGIC: &[u8; 5] = b"EBOD1";
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const STREAM_NONCE_SIZE: usize = 19;
+const INDEX_NONCE_SIZE: usize = 24;
+const INDEX_AAD: &[u8] = b"ebod-index";
+// The index is written again after this many stored files or bytes, so that an interrupted backup only has to encrypt
+// the last few files again
+const INDEX_SAVE_FILES: usize = 100;
+const INDEX_SAVE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The non-secret parameters of an encrypted repository, stored in `.ebod/repository.json`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RepoConfig {
+    pub version: u32,
+    pub kdf: String,
+    pub salt: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub cipher: String,
+}
+
+/// An entry of the encrypted index. It maps the metadata of a file to the object that holds its contents.
+///
+/// # Members
+/// * `entry: FileEntry` -> The metadata of the file as it was recorded in the source directory
+/// * `object: Option<String>` -> The id of the object in `data/`. `None` for directories.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RepoEntry {
+    pub entry: FileEntry,
+    pub object: Option<String>,
+}
+
+/// The keys derived from the passphrase of a repository.
+pub struct RepoKey {
+    content: [u8; 32],
+    names: [u8; 32],
+}
+
+/// An opened encrypted repository.
+pub struct Repository {
+    root: PathBuf,
+    key: RepoKey,
+}
+
+/// Reads the passphrase from the given key file, or from the `EBOD_PASSPHRASE` environment variable.
+///
+/// # Input
+/// * `key_file: Option<&Path>` -> The path of a file holding the passphrase. A trailing newline is ignored.
+///
+/// # Output: `Result<String, String>`
+/// The passphrase or an error message if it couldn't be found.
+pub fn read_passphrase(key_file: Option<&Path>) -> Result<String, String> {
+    let passphrase = if let Some(path) = key_file {
+        fs::read_to_string(path)
+            .map_err(|_| format!("Couldn't read the key file {}", path.to_string_lossy()))?
+            .trim_end_matches(['\r', '\n'])
+            .to_string()
+    } else if let Ok(value) = std::env::var(PASSPHRASE_ENV) {
+        value
+    } else {
+        return Err(format!("No key given. Use --key-file or set the {} environment variable", PASSPHRASE_ENV));
+    };
+    if passphrase.is_empty() {
+        return Err(String::from("The passphrase must not be empty"));
+    }
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &str, config: &RepoConfig) -> Result<RepoKey, String> {
+    let salt = hex::decode(&config.salt).map_err(|_| String::from("Invalid salt in repository.json"))?;
+    let params = Params::new(config.m_cost, config.t_cost, config.p_cost, Some(64))
+        .map_err(|e| format!("Invalid key derivation parameters: {}", e))?;
+    let mut output = [0u8; 64];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut output)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    let mut key = RepoKey { content: [0u8; 32], names: [0u8; 32] };
+    key.content.copy_from_slice(&output[..32]);
+    key.names.copy_from_slice(&output[32..]);
+    Ok(key)
+}
+
+/// Checks whether the directory holds an encrypted repository.
+pub fn is_repository(path: &Path) -> bool {
+    path.join(".ebod/repository.json").exists()
+}
+
+impl Repository {
+    /// Opens the encrypted repository at `root`. If `create` is set and no repository exists, a new one is created
+    /// with a fresh salt.
+    ///
+    /// # Input
+    /// * `root: &Path` -> The root directory of the repository
+    /// * `passphrase: &str` -> The passphrase from which the key is derived
+    /// * `create: bool` -> Whether a missing repository should be created
+    ///
+    /// # Output: `Result<Repository, String>`
+    pub fn open(root: &Path, passphrase: &str, create: bool) -> Result<Repository, String> {
+        let config_path = root.join(".ebod/repository.json");
+        let config: RepoConfig = if config_path.exists() {
+            let content = fs::read_to_string(&config_path).map_err(|_| String::from("Error in reading repository.json"))?;
+            serde_json::from_str(&content).map_err(|_| String::from("Error in parsing repository.json"))?
+        } else if create {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let defaults = Params::default();
+            let config = RepoConfig {
+                version: REPO_VERSION,
+                kdf: String::from("argon2id"),
+                salt: hex::encode(salt),
+                m_cost: defaults.m_cost(),
+                t_cost: defaults.t_cost(),
+                p_cost: defaults.p_cost(),
+                cipher: String::from("xchacha20poly1305"),
+            };
+            fs::create_dir_all(root.join(".ebod")).map_err(|_| String::from("Couldn't create the .ebod directory"))?;
+            let data = serde_json::to_string_pretty(&config).map_err(|_| String::from("Error during serializing repository.json"))?;
+            fs::write(&config_path, data).map_err(|_| String::from("Error in writing repository.json"))?;
+            log(LogType::Ok, &format!("Created encrypted repository at {}", root.to_string_lossy()));
+            config
+        } else {
+            return Err(format!("{} is not an encrypted ebod repository", root.to_string_lossy()));
+        };
+        if config.version != REPO_VERSION || config.kdf != "argon2id" || config.cipher != "xchacha20poly1305" {
+            return Err(String::from("Unsupported repository format"));
+        }
+        let key = derive_key(passphrase, &config)?;
+        Ok(Repository { root: root.to_path_buf(), key })
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.root.join(".ebod/index.enc")
+    }
+
+    /// Computes the object id of a file from its relative name. The id is keyed, so it doesn't leak the name.
+    pub fn object_id(&self, name: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key.names).expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// The path of an object inside the `data/` directory of the repository.
+    pub fn object_path(&self, object: &str) -> PathBuf {
+        self.root.join("data").join(&object[..2]).join(object)
+    }
+
+    /// Decrypts and returns the index of the repository. An empty index is returned for a new repository.
+    pub fn read_index(&self) -> Result<Vec<RepoEntry>, String> {
+        let path = self.index_path();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let content = fs::read(&path).map_err(|_| String::from("Error in reading the encrypted index"))?;
+        if content.len() < INDEX_NONCE_SIZE {
+            return Err(String::from("The encrypted index is truncated"));
+        }
+        let (nonce, ciphertext) = content.split_at(INDEX_NONCE_SIZE);
+        let cipher = XChaCha20Poly1305::new((&self.key.content).into());
+        let plain = cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: INDEX_AAD })
+            .map_err(|_| String::from("Couldn't decrypt the index. The passphrase is wrong or the index is corrupted"))?;
+        serde_json::from_slice(&plain).map_err(|_| String::from("Error in parsing the decrypted index"))
+    }
+
+    /// Encrypts the index and atomically replaces `.ebod/index.enc` with it.
+    pub fn write_index(&self, index: &[RepoEntry]) -> Result<(), String> {
+        let plain = serde_json::to_vec(index).map_err(|_| String::from("Error during serializing the index"))?;
+        let mut nonce = [0u8; INDEX_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = XChaCha20Poly1305::new((&self.key.content).into());
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: &plain, aad: INDEX_AAD })
+            .map_err(|_| String::from("Error in encrypting the index"))?;
+        let tmp = self.root.join(".ebod/index.enc.tmp");
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        fs::write(&tmp, data).map_err(|_| String::from("Error in writing the encrypted index"))?;
+        fs::rename(&tmp, self.index_path()).map_err(|_| String::from("Error in replacing the encrypted index"))
+    }
+
+    /// Encrypts the file at `src` into the object `object`. The object is first written to a temporary file and then
+    /// renamed, so an interrupted backup never leaves a half written object behind.
+    pub fn store(&self, src: &Path, object: &str) -> Result<u64, String> {
+        let dest = self.object_path(object);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|_| format!("Couldn't create directory {}", parent.to_string_lossy()))?;
+        }
+        let tmp = dest.with_extension("tmp");
+        let input = File::open(src).map_err(|_| format!("Error in opening file {}", src.to_string_lossy()))?;
+        let output = File::create(&tmp).map_err(|_| format!("Error in creating object {}", object))?;
+        let written = self
+            .encrypt_stream(input, output, object)
+            .map_err(|e| format!("Error in encrypting file {}: {}", src.to_string_lossy(), e))?;
+        fs::rename(&tmp, &dest).map_err(|_| format!("Error in storing object {}", object))?;
+        Ok(written)
+    }
+
+    /// Decrypts the object `object` into `writer`. Returns the number of plaintext bytes written.
+    pub fn load(&self, object: &str, writer: &mut impl Write) -> Result<u64, String> {
+        let input = File::open(self.object_path(object)).map_err(|_| format!("Object {} is missing", object))?;
+        self.decrypt_stream(input, writer, object)
+    }
+
+    fn encrypt_stream(&self, mut input: impl Read, mut output: impl Write, object: &str) -> io::Result<u64> {
+        let mut nonce = [0u8; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        output.write_all(MAGIC)?;
+        output.write_all(&nonce)?;
+
+        let cipher = XChaCha20Poly1305::new((&self.key.content).into());
+        let mut encryptor = EncryptorBE32::from_aead(cipher, (&nonce).into());
+        let mut current = vec![0u8; CHUNK_SIZE];
+        let mut next = vec![0u8; CHUNK_SIZE];
+        let mut len = read_full(&mut input, &mut current)?;
+        let mut total = len as u64;
+        loop {
+            let next_len = read_full(&mut input, &mut next)?;
+            let payload = Payload { msg: &current[..len], aad: object.as_bytes() };
+            if next_len == 0 {
+                let chunk = encryptor.encrypt_last(payload).map_err(|_| io::Error::other("encryption failed"))?;
+                output.write_all(&chunk)?;
+                break;
+            }
+            let chunk = encryptor.encrypt_next(payload).map_err(|_| io::Error::other("encryption failed"))?;
+            output.write_all(&chunk)?;
+            std::mem::swap(&mut current, &mut next);
+            len = next_len;
+            total += len as u64;
+        }
+        output.flush()?;
+        Ok(total)
+    }
+
+    fn decrypt_stream(&self, mut input: impl Read, output: &mut impl Write, object: &str) -> Result<u64, String> {
+        let corrupted = || format!("Object {} is corrupted or was encrypted with another key", object);
+        let mut header = [0u8; MAGIC.len() + STREAM_NONCE_SIZE];
+        input.read_exact(&mut header).map_err(|_| corrupted())?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(corrupted());
+        }
+        let nonce = &header[MAGIC.len()..];
+
+        let cipher = XChaCha20Poly1305::new((&self.key.content).into());
+        let mut decryptor = DecryptorBE32::from_aead(cipher, nonce.into());
+        let mut current = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+        let mut next = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+        let mut len = read_full(&mut input, &mut current).map_err(|_| corrupted())?;
+        let mut total = 0u64;
+        loop {
+            let next_len = read_full(&mut input, &mut next).map_err(|_| corrupted())?;
+            let payload = Payload { msg: &current[..len], aad: object.as_bytes() };
+            if next_len == 0 {
+                let chunk = decryptor.decrypt_last(payload).map_err(|_| corrupted())?;
+                output.write_all(&chunk).map_err(|_| String::from("Error in writing decrypted data"))?;
+                total += chunk.len() as u64;
+                break;
+            }
+            let chunk = decryptor.decrypt_next(payload).map_err(|_| corrupted())?;
+            output.write_all(&chunk).map_err(|_| String::from("Error in writing decrypted data"))?;
+            total += chunk.len() as u64;
+            std::mem::swap(&mut current, &mut next);
+            len = next_len;
+        }
+        Ok(total)
+    }
+}
+
+// Fills `buf` as far as possible and returns the number of bytes read. Only returns less than `buf.len()` at EOF.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Backs up the `src` directory into the encrypted repository at `dest`. The metadata in `src/.ebod/metadata.json` is
+/// compared with the encrypted index, and only new or modified files are encrypted and stored.
+///
+/// Unlike the plain `backup`, a modified file replaces the older version in the repository, as the repository is never
+/// edited by hand.
+///
+/// # Input
+/// * `src: &Path` -> The source directory. It must be initialized
+/// * `dest: &Path` -> The root of the encrypted repository. It is created if it doesn't exist
+/// * `passphrase: &str` -> The passphrase of the repository
+///
+/// # Output: `Result<(), String>`
+pub fn encrypted_backup(src: &Path, dest: &Path, passphrase: &str) -> Result<(), String> {
+    let src_meta = read_metadata(&src.join(".ebod/metadata.json"))?;
+    let repo = Repository::open(dest, passphrase, true)?;
+    let mut index = repo.read_index()?;
+    // The position of every entry of the index by name
+    let mut positions: HashMap<String, usize> = index.iter().enumerate().map(|(idx, item)| (item.entry.name.clone(), idx)).collect();
+    let mut unchanged = 0;
+    let (mut unsaved_files, mut unsaved_bytes) = (0, 0);
+
+    for file in src_meta {
+        let existing = positions.get(&file.name).copied();
+        if let Some(idx) = existing {
+            let stored = &index[idx].entry;
+            if stored.e_type == file.e_type && stored.mtime_diff(&file).is_zero() && stored.length == file.length {
+                unchanged += 1;
+                continue;
+            }
+        }
+        let object = if file.e_type == EntryType::File {
+            let object = repo.object_id(&file.name);
+            if let Err(err) = repo.store(&src.join(&file.name), &object) {
+                // The files stored so far are kept in the index
+                repo.write_index(&index)?;
+                return Err(err);
+            }
+            log(LogType::Ok, &format!("Encrypted file: {} into repository", &file.name));
+            output::emit(Event::Copied { path: file.name.clone(), bytes: file.length, method: None });
+            unsaved_files += 1;
+            unsaved_bytes += file.length;
+            Some(object)
+        } else {
+            None
+        };
+        let entry = RepoEntry { entry: file, object };
+        match existing {
+            Some(idx) => index[idx] = entry,
+            None => {
+                positions.insert(entry.entry.name.clone(), index.len());
+                index.push(entry);
+            }
+        }
+        if unsaved_files >= INDEX_SAVE_FILES || unsaved_bytes >= INDEX_SAVE_BYTES {
+            repo.write_index(&index)?;
+            (unsaved_files, unsaved_bytes) = (0, 0);
+        }
+    }
+    repo.write_index(&index)?;
+    if unchanged > 0 {
+        log(LogType::Info, &format!("{} entries were unchanged and hence were not encrypted again", unchanged));
+    }
+    Ok(())
+}
+
+/// Restores the contents of the encrypted repository at `repo_path` into the `target` directory.
+///
+/// # Input
+/// * `repo_path: &Path` -> The root of the encrypted repository
+/// * `target: &Path` -> The directory into which the files are decrypted
+/// * `passphrase: &str` -> The passphrase of the repository
+///
+/// # Output: `Result<(), String>`
+pub fn restore(repo_path: &Path, target: &Path, passphrase: &str) -> Result<(), String> {
+    let repo = Repository::open(repo_path, passphrase, false)?;
+    let index = repo.read_index()?;
+    let mut error = false;
+    for item in &index {
+        let path = target.join(&item.entry.name);
+        match &item.object {
+            None => {
+                if fs::create_dir_all(&path).is_err() {
+                    log(LogType::Err, &format!("Couldn't create directory {}", &item.entry.name));
+                    error = true;
+                }
+            }
+            Some(object) => {
+                if let Some(parent) = path.parent() {
+                    let _ = fs::create_dir_all(parent);
+                }
+                let result = File::create(&path)
+                    .map_err(|_| format!("Couldn't create file {}", &item.entry.name))
+                    .and_then(|mut file| {
+                        repo.load(object, &mut file)?;
+                        file.set_modified(item.entry.modified()).map_err(|_| format!("Couldn't set the modified time of {}", &item.entry.name))
+                    });
+                match result {
+                    Ok(()) => {
+                        log(LogType::Ok, &format!("Restored file: {}", &item.entry.name));
+                        output::emit(Event::Copied { path: item.entry.name.clone(), bytes: item.entry.length, method: None });
+                    },
+                    Err(e) => {
+                        log(LogType::Err, &e);
+                        error = true;
+                    }
+                }
+            }
+        }
+    }
+    if error {
+        return Err(String::from("Some files couldn't be restored"));
+    }
+    Ok(())
+}
+
+/// Verifies the encrypted repository at `repo_path`. Every object is decrypted and authenticated, and its length is
+/// checked against the index. Nothing is written to disk.
+///
+/// # Input
+/// * `repo_path: &Path` -> The root of the encrypted repository
+/// * `passphrase: &str` -> The passphrase of the repository
+///
+/// # Output: `Result<(), String>`
+pub fn verify(repo_path: &Path, passphrase: &str) -> Result<(), String> {
+    let repo = Repository::open(repo_path, passphrase, false)?;
+    let index = repo.read_index()?;
+    let mut failed = 0;
+    for item in &index {
+        if let Some(object) = &item.object {
+            match repo.load(object, &mut io::sink()) {
+                Ok(len) if len == item.entry.length => {}
+                Ok(len) => {
+                    log(LogType::Err, &format!("{} has {} bytes in the repository, expected {}", &item.entry.name, len, item.entry.length));
+                    failed += 1;
+                }
+                Err(e) => {
+                    log(LogType::Err, &format!("{}: {}", &item.entry.name, e));
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} entries failed verification", failed, index.len()));
+    }
+    log(LogType::Ok, &format!("All {} entries of the repository were verified", index.len()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialize_dir;
+
+    #[test]
+    fn files_are_backed_up_and_restored() {
+        let root = std::env::temp_dir().join(format!("ebod-crypto-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (src, repo, target) = (root.join("src"), root.join("repo"), root.join("target"));
+        fs::create_dir_all(src.join("docs")).unwrap();
+        // More files than are stored between two writes of the index
+        for i in 0..INDEX_SAVE_FILES + 20 {
+            fs::write(src.join(format!("docs/{}.txt", i)), format!("file {}", i)).unwrap();
+        }
+        fs::write(src.join("missing.txt"), "gone").unwrap();
+        initialize_dir(&src, false);
+
+        // The file that can't be read is moved last, and the files stored before it are still in the index
+        let metadata = src.join(".ebod/metadata.json");
+        let mut entries = read_metadata(&metadata).unwrap();
+        entries.sort_by_key(|entry| entry.name == "missing.txt");
+        fs::write(&metadata, serde_json::to_string(&entries).unwrap()).unwrap();
+        fs::remove_file(src.join("missing.txt")).unwrap();
+        assert!(encrypted_backup(&src, &repo, "secret").is_err());
+        let index = Repository::open(&repo, "secret", false).unwrap().read_index().unwrap();
+        assert_eq!(index.len(), entries.len() - 1);
+
+        entries.pop();
+        fs::write(&metadata, serde_json::to_string(&entries).unwrap()).unwrap();
+        encrypted_backup(&src, &repo, "secret").unwrap();
+        verify(&repo, "secret").unwrap();
+        assert!(verify(&repo, "wrong").is_err());
+        restore(&repo, &target, "secret").unwrap();
+        assert_eq!(fs::read_to_string(target.join("docs/7.txt")).unwrap(), "file 7");
+        assert_eq!(fs::read_dir(target.join("docs")).unwrap().count(), INDEX_SAVE_FILES + 20);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! Automatic backups when a registered drive is plugged in.
+//!
+//! `ebod daemon` polls the table of mounted filesystems (`/proc/self/mountinfo`). When a filesystem is mounted whose
+//! root holds the identity file of a registered drive, the profiles that target the drive (`drive = "..."`) are run,
+//! and a desktop notification is sent when they finish.
+//!
+//! Any file in the mountinfo format can be watched instead of `/proc/self/mountinfo`, so the daemon can be tried out
+//! with bind mounts or with a hand-written file.
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::drive::{DriveIdentity, MountInfo, read_mounts};
+use crate::profile::{ProfileResult, Profiles, default_state_dir, print_summary_table, run_profile};
+use crate::{LogType, log};
+
+// A mount is identified by its device and mount point, so that a drive plugged into another port is seen as new
+fn mount_key(mount: &MountInfo) -> (String, PathBuf) {
+    (mount.device.clone(), mount.mount_point.clone())
+}
+
+/// Waits for registered drives to be mounted and runs their profiles. Runs until the process is stopped.
+///
+/// # Input
+/// * `profiles_path: &Path` -> The profiles file. It is read again every time a drive is mounted
+/// * `mountinfo: &Path` -> The table of mounted filesystems that is polled
+/// * `interval: Duration` -> How often the table is read
+///
+/// # Rules followed:
+/// 1. Only drives mounted after the start of the daemon trigger a run. The drives that are already mounted don't.
+/// 2. A drive that is unmounted and mounted again triggers another run.
+pub fn run_daemon(profiles_path: &Path, mountinfo: &Path, interval: Duration) {
+    let state_dir = default_state_dir();
+    let mut known: BTreeSet<(String, PathBuf)> = read_mounts(mountinfo).iter().map(mount_key).collect();
+    log(LogType::Info, &format!("Waiting for registered drives to be mounted (reading {})", mountinfo.to_string_lossy()));
+    loop {
+        sleep(interval);
+        let mounts = read_mounts(mountinfo);
+        for mount in &mounts {
+            if !known.contains(&mount_key(mount))
+                && let Some((summary, body)) = on_mount(mount, profiles_path, mountinfo, state_dir.as_deref())
+            {
+                send_notification(&summary, &body);
+            }
+        }
+        known = mounts.iter().map(mount_key).collect();
+    }
+}
+
+// Runs the profiles that target the drive mounted at `mount`, if it is a registered drive, and saves their states into
+// `state_dir`. Returns the summary and body of the notification to send, if profiles were run
+fn on_mount(mount: &MountInfo, profiles_path: &Path, mountinfo: &Path, state_dir: Option<&Path>) -> Option<(String, String)> {
+    let identity = DriveIdentity::read(&mount.mount_point)?;
+    let drive_name = identity.label.clone().unwrap_or(identity.id.clone());
+    log(LogType::Info, &format!("The drive {} was mounted at {}", drive_name, mount.mount_point.to_string_lossy()));
+
+    let profiles = match Profiles::load(profiles_path) {
+        Ok(profiles) => profiles,
+        Err(err) => {
+            log(LogType::Err, &err);
+            return None;
+        }
+    };
+    let names: Vec<&String> = profiles
+        .profiles
+        .iter()
+        .filter(|(_, profile)| profile.drive.as_deref().is_some_and(|drive| identity.matches(drive)))
+        .map(|(name, _)| name)
+        .collect();
+    if names.is_empty() {
+        log(LogType::Info, &format!("No profile targets the drive {}", drive_name));
+        return None;
+    }
+
+    let results: Vec<ProfileResult> = names.into_iter().map(|name| run_profile(name, &profiles.profiles[name], mountinfo, state_dir)).collect();
+    print_summary_table(&results);
+    let failed = results.iter().filter(|r| r.result.is_err()).count();
+    let body = if failed == 0 {
+        format!("{} profiles finished", results.len())
+    } else {
+        format!("{} of {} profiles failed", failed, results.len())
+    };
+    Some((format!("ebod: backup to {}", drive_name), body))
+}
+
+/// Sends a desktop notification with `notify-send`. If it can't be sent, the message is only logged.
+pub fn send_notification(summary: &str, body: &str) {
+    match Command::new("notify-send").args(["--app-name=ebod", summary, body]).status() {
+        Ok(status) if status.success() => {}
+        _ => log(LogType::Info, &format!("{}: {}", summary, body)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::DRIVE_ID_FILE;
+    use crate::profile::ProfileState;
+    use std::fs;
+
+    #[test]
+    fn mounting_a_registered_drive_runs_its_profiles() {
+        let root = std::env::temp_dir().join(format!("ebod-daemon-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (src, drive, other) = (root.join("src"), root.join("my drive"), root.join("other"));
+        for dir in [&src, &drive.join("backup"), &other.join("backup")] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(src.join("photo.jpg"), "photo").unwrap();
+        let identity = DriveIdentity { id: String::from("ebod-test-drive"), label: None, fs_uuid: None, created_at: 0 };
+        fs::write(drive.join(DRIVE_ID_FILE), serde_json::to_string(&identity).unwrap()).unwrap();
+
+        // The mount point has a space, escaped like the kernel does
+        let mountinfo = root.join("mountinfo");
+        let escaped = |path: &Path| path.to_string_lossy().replace(' ', "\\040");
+        let table = format!(
+            "50 22 8:17 / {} rw - vfat /dev/sdb1 rw\n51 22 8:33 / {} rw - ext4 /dev/sdc1 rw\n",
+            escaped(&drive),
+            escaped(&other)
+        );
+        fs::write(&mountinfo, table).unwrap();
+        let name = "photos";
+        let profiles = root.join("profiles.toml");
+        let profile = format!("[profiles.{}]\nsrc = {:?}\ndest = \"backup\"\ndrive = \"ebod-test-drive\"\n", name, src.to_string_lossy());
+        fs::write(&profiles, profile).unwrap();
+
+        let mounts = read_mounts(&mountinfo);
+        assert_eq!(mounts[0].mount_point, drive);
+        let state_dir = root.join("state");
+        // A drive without an identity file is left alone
+        assert_eq!(on_mount(&mounts[1], &profiles, &mountinfo, Some(&state_dir)), None);
+        assert!(!other.join("backup/photo.jpg").exists());
+        let notification = on_mount(&mounts[0], &profiles, &mountinfo, Some(&state_dir));
+        assert_eq!(notification, Some((String::from("ebod: backup to ebod-test-drive"), String::from("1 profiles finished"))));
+        assert_eq!(fs::read_to_string(drive.join("backup/photo.jpg")).unwrap(), "photo");
+        assert!(ProfileState::load_in(&state_dir, name).is_some_and(|state| state.ok));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+//! Delta transfer of modified files, in the manner of rsync.
+//!
+//! When a file of at least `MIN_DELTA_SIZE` bytes overwrites an older copy in the destination, only the parts that
+//! changed are sent to it:
+//! 1. `Signature::of` splits the copy in the destination (the basis) into blocks, and records a rolling checksum and a
+//!    strong hash of every block
+//! 2. `diff` reads the new file and looks for the blocks of the basis at every offset with the rolling checksum. The
+//!    new file is described as a series of `Op`s: blocks of the basis and literal data
+//! 3. The ops are written into a temporary file next to the basis, opened with `Vfs::patch`: the blocks are copied
+//!    from the basis by the backend itself, and only the literal data is read from the source. The temporary file is
+//!    then renamed over the basis, so an interrupted update leaves the old copy as it was, and other hard links to the
+//!    basis keep their contents
+//!
+//! The local filesystem doesn't implement `Vfs::patch`: reading both files to rebuild the new one costs more than
+//! copying it, so local files get a full copy, which can use reflinks or `copy_file_range`. S3 and WebDAV can't copy
+//! part of a file either. On SFTP, the blocks are copied with the `copy-data` extension of OpenSSH, and the basis is
+//! downloaded once to compute its signature, so a delta trades a download of the old copy for the upload of the
+//! blocks that didn't change.
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::vfs::{FilePatch, Vfs};
+
+/// Files of at least this size are updated with a delta when they overwrite an older copy.
+pub const MIN_DELTA_SIZE: u64 = 1024 * 1024;
+
+// The bounds of the block size. Between them, the block size is the square root of the length, like rsync.
+const MIN_BLOCK: usize = 4 * 1024;
+const MAX_BLOCK: usize = 1024 * 1024;
+
+/// The block size used for a basis of `length` bytes: the square root of the length rounded up to 4 KiB, between
+/// 4 KiB and 1 MiB.
+pub fn block_size(length: u64) -> usize {
+    let root = (length as f64).sqrt() as usize;
+    root.div_ceil(MIN_BLOCK).saturating_mul(MIN_BLOCK).clamp(MIN_BLOCK, MAX_BLOCK)
+}
+
+// The rolling checksum of rsync, over a window of `length` bytes
+#[derive(Debug, Default, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl Rolling {
+    fn of(data: &[u8]) -> Rolling {
+        let mut rolling = Rolling::default();
+        for &byte in data {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling.b.wrapping_add(rolling.a);
+        }
+        rolling.length = data.len() as u32;
+        rolling
+    }
+
+    // Drops `out` from the start of the window, and appends `incoming` to its end if there is one
+    fn roll(&mut self, out: u8, incoming: Option<u8>) {
+        self.a = self.a.wrapping_sub(out as u32);
+        self.b = self.b.wrapping_sub(self.length.wrapping_mul(out as u32));
+        match incoming {
+            Some(byte) => {
+                self.a = self.a.wrapping_add(byte as u32);
+                self.b = self.b.wrapping_add(self.a);
+            },
+            None => self.length -= 1,
+        }
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+fn strong_hash(data: &[u8]) -> [u8; 16] {
+    let mut hash = [0u8; 16];
+    hash.copy_from_slice(&blake3::hash(data).as_bytes()[..16]);
+    hash
+}
+
+// Reads until `buffer` is full or the end of `reader`. Returns the number of bytes read.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// The signature of a basis: the checksums of its blocks.
+///
+/// # Members
+/// * `block_size: usize` -> The size of the blocks. The last block may be shorter
+/// * `length: u64` -> The length of the basis
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub block_size: usize,
+    pub length: u64,
+    strong: Vec<[u8; 16]>,
+    // The blocks of every rolling checksum
+    weak: HashMap<u32, Vec<usize>>,
+}
+
+impl Signature {
+    /// Reads the basis from `reader` and computes its signature with blocks of `block_size` bytes.
+    pub fn of(mut reader: impl Read, block_size: usize) -> io::Result<Signature> {
+        let mut signature = Signature { block_size, length: 0, strong: vec![], weak: HashMap::new() };
+        let mut buffer = vec![0u8; block_size];
+        loop {
+            let read = read_full(&mut reader, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            let block = &buffer[..read];
+            signature.weak.entry(Rolling::of(block).digest()).or_default().push(signature.strong.len());
+            signature.strong.push(strong_hash(block));
+            signature.length += read as u64;
+            if read < block_size {
+                break;
+            }
+        }
+        Ok(signature)
+    }
+
+    fn block_length(&self, index: usize) -> usize {
+        (self.length - (index * self.block_size) as u64).min(self.block_size as u64) as usize
+    }
+
+    // The block of the basis that holds `window`
+    fn find(&self, rolling: &Rolling, window: &[u8]) -> Option<usize> {
+        let candidates = self.weak.get(&rolling.digest())?;
+        let strong = strong_hash(window);
+        candidates.iter().copied().find(|&index| self.block_length(index) == window.len() && self.strong[index] == strong)
+    }
+}
+
+/// A piece of the new file.
+///
+/// # Values in Enum
+/// * `Copy { offset, length }` -> The bytes of the basis at `offset`
+/// * `Data(Vec<u8>)` -> Bytes that aren't in the basis
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Op {
+    Copy { offset: u64, length: u64 },
+    Data(Vec<u8>),
+}
+
+/// Reads the new file from `source` and describes it against `signature`, by calling `emit` with the ops in the
+/// order of the new file. The literal data is emitted in pieces of at most one block.
+pub fn diff(signature: &Signature, source: impl Read, mut emit: impl FnMut(Op) -> io::Result<()>) -> io::Result<()> {
+    let block_size = signature.block_size;
+    let mut bytes = BufReader::new(source).bytes();
+    let mut next = || bytes.next().transpose();
+    let mut window: VecDeque<u8> = VecDeque::with_capacity(block_size);
+    let mut literal: Vec<u8> = vec![];
+
+    'blocks: loop {
+        while window.len() < block_size {
+            match next()? {
+                Some(byte) => window.push_back(byte),
+                None => break,
+            }
+        }
+        let mut rolling = Rolling::of(window.make_contiguous());
+        while !window.is_empty() {
+            if let Some(index) = signature.find(&rolling, window.make_contiguous()) {
+                if !literal.is_empty() {
+                    emit(Op::Data(std::mem::take(&mut literal)))?;
+                }
+                emit(Op::Copy { offset: (index * block_size) as u64, length: window.len() as u64 })?;
+                window.clear();
+                continue 'blocks;
+            }
+            let Some(out) = window.pop_front() else {
+                break;
+            };
+            literal.push(out);
+            let incoming = next()?;
+            if let Some(byte) = incoming {
+                window.push_back(byte);
+            }
+            rolling.roll(out, incoming);
+            if literal.len() >= block_size {
+                emit(Op::Data(std::mem::take(&mut literal)))?;
+            }
+        }
+        break;
+    }
+    if !literal.is_empty() {
+        emit(Op::Data(literal))?;
+    }
+    Ok(())
+}
+
+/// What a delta update did.
+///
+/// # Members
+/// * `matched: u64` -> Bytes copied from the basis
+/// * `literal: u64` -> Bytes read from the source and written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub matched: u64,
+    pub literal: u64,
+}
+
+// Writes the ops of `diff` into the file opened with `Vfs::patch`. Blocks that follow each other in the basis are
+// copied with a single request.
+struct Patcher<'a> {
+    file: Box<dyn FilePatch + 'a>,
+    // The range of the basis still to be copied
+    copy: Option<(u64, u64)>,
+    stats: DeltaStats,
+}
+
+impl Patcher<'_> {
+    fn copy_pending(&mut self) -> io::Result<()> {
+        if let Some((offset, length)) = self.copy.take() {
+            self.file.copy_from_basis(offset, length)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, op: Op) -> io::Result<()> {
+        match op {
+            Op::Copy { offset, length } => {
+                self.stats.matched += length;
+                match &mut self.copy {
+                    Some((start, pending)) if *start + *pending == offset => *pending += length,
+                    _ => {
+                        self.copy_pending()?;
+                        self.copy = Some((offset, length));
+                    },
+                }
+            },
+            Op::Data(data) => {
+                self.copy_pending()?;
+                self.file.write_all(&data)?;
+                self.stats.literal += data.len() as u64;
+            },
+        }
+        Ok(())
+    }
+}
+
+// The temporary file that a delta update of `dest` is written into
+fn temp_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!(".{}.ebod-delta", name))
+}
+
+// Writes the contents of `src` into `file` with the blocks of the basis `dest`
+fn write_patched<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, file: Box<dyn FilePatch + '_>, on_read: &mut impl FnMut(u64)) -> io::Result<DeltaStats> {
+    let basis_length = dest_vfs.stat(dest)?.length;
+    let signature = Signature::of(dest_vfs.open(dest)?, block_size(basis_length))?;
+    let mut patcher = Patcher { file, copy: None, stats: DeltaStats::default() };
+    let source = CountingReader { inner: src_vfs.open(src)?, on_read };
+    diff(&signature, source, |op| patcher.apply(op))?;
+    patcher.copy_pending()?;
+    patcher.file.flush()?;
+    Ok(patcher.stats)
+}
+
+/// Replaces the file `dest` of `dest_vfs`, an older copy of `src`, with the contents of `src`. Only the literal data
+/// is sent to `dest_vfs`: the blocks that didn't change are copied from `dest` by the backend. The new contents are
+/// written into a temporary file next to `dest`, which is then renamed over it. `on_read` is called with the number of
+/// bytes read from `src` as it is read.
+///
+/// # Output: `io::Result<DeltaStats>`
+/// What was done, or the error that stopped the update. `ErrorKind::Unsupported` is returned before anything is read
+/// if `dest_vfs` can't copy ranges of `dest`. On error, `dest` is left as it was and the temporary file is removed.
+pub fn update_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, mut on_read: impl FnMut(u64)) -> io::Result<DeltaStats> {
+    let temp = temp_path(dest);
+    let file = dest_vfs.patch(dest, &temp)?;
+    // The file is closed before it is renamed
+    let result = write_patched(src_vfs, src, dest_vfs, dest, file, &mut on_read).and_then(|stats| dest_vfs.rename(&temp, dest).map(|_| stats));
+    if result.is_err() {
+        let _ = dest_vfs.remove(&temp);
+    }
+    result
+}
+
+// Reports the bytes read from `inner` to `on_read`
+struct CountingReader<'a, R, F> {
+    inner: R,
+    on_read: &'a mut F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for CountingReader<'_, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        (self.on_read)(read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    // Deterministic data that doesn't repeat within a block
+    fn data(length: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn update_replaces_the_basis_through_a_temporary_file() {
+        let fs = MemoryFs::new();
+        let old = data(64 * 1024, 1);
+        let mut new = old.clone();
+        new[10_000..10_100].copy_from_slice(&[7; 100]);
+        new.extend_from_slice(b"appended");
+        fs.write(Path::new("old"), &old).unwrap();
+        fs.write(Path::new("new"), &new).unwrap();
+
+        let stats = update_in(&fs, Path::new("new"), &fs, Path::new("old"), |_| {}).unwrap();
+        assert_eq!(fs.read(Path::new("old")).unwrap(), new);
+        assert!(!fs.exists(Path::new(".old.ebod-delta")));
+        assert_eq!(stats.matched + stats.literal, new.len() as u64);
+        assert!(stats.literal < 2 * 4096 + 8, "{:?}", stats);
+    }
+
+    #[test]
+    fn moved_blocks_are_copied_from_the_basis() {
+        let fs = MemoryFs::new();
+        let old = data(32 * 1024, 2);
+        // The two halves are swapped
+        let new = [&old[16 * 1024..], &old[..16 * 1024]].concat();
+        fs.write(Path::new("old"), &old).unwrap();
+        fs.write(Path::new("new"), &new).unwrap();
+
+        let stats = update_in(&fs, Path::new("new"), &fs, Path::new("old"), |_| {}).unwrap();
+        assert_eq!(fs.read(Path::new("old")).unwrap(), new);
+        assert_eq!(stats, DeltaStats { matched: new.len() as u64, literal: 0 });
+    }
+
+    #[test]
+    fn a_failed_update_keeps_the_basis() {
+        let fs = MemoryFs::new();
+        fs.write(Path::new("old"), b"old contents").unwrap();
+        assert!(update_in(&fs, Path::new("missing"), &fs, Path::new("old"), |_| {}).is_err());
+        assert_eq!(fs.read(Path::new("old")).unwrap(), b"old contents");
+        assert!(!fs.exists(Path::new(".old.ebod-delta")));
+    }
+
+    #[test]
+    fn local_files_are_not_patched() {
+        let dir = std::env::temp_dir().join(format!("ebod-delta-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), b"a").unwrap();
+        let err = update_in(&crate::vfs::LocalFs, &dir.join("a"), &crate::vfs::LocalFs, &dir.join("a"), |_| {}).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
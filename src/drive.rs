@@ -0,0 +1,247 @@
+//! Identification of destination drives.
+//!
+//! Removable drives are mounted at different paths from one machine (or one plug-in) to the next. So a drive is
+//! identified by the identity file `.ebod-drive.json` at the root of its filesystem, and by the UUID of the
+//! filesystem, which is read from `/dev/disk/by-uuid` or from the output of `blkid`.
+//!
+//! The mounted filesystems are read from `/proc/self/mountinfo`.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{LogType, log};
+
+/// The name of the identity file at the root of a drive.
+pub const DRIVE_ID_FILE: &str = ".ebod-drive.json";
+/// The default location of the table of mounted filesystems.
+pub const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// A filesystem mounted on the system, as listed in `/proc/self/mountinfo`.
+///
+/// # Members
+/// * `device: String` -> The `major:minor` number of the device
+/// * `mount_point: PathBuf` -> Where the filesystem is mounted
+/// * `fs_type: String` -> The type of the filesystem, like `ext4` or `vfat`
+/// * `source: String` -> The mounted device, like `/dev/sdb1`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MountInfo {
+    pub device: String,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+}
+
+/// The contents of the identity file of a drive.
+///
+/// # Members
+/// * `id: String` -> A random id generated when the drive was registered
+/// * `label: Option<String>` -> A name given to the drive by the user
+/// * `fs_uuid: Option<String>` -> The UUID of the filesystem when the drive was registered
+/// * `created_at: u64` -> The timestamp in seconds of the registration
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct DriveIdentity {
+    pub id: String,
+    pub label: Option<String>,
+    pub fs_uuid: Option<String>,
+    pub created_at: u64,
+}
+
+// Mount points are escaped in mountinfo: space, tab, newline and backslash are written as octal escapes.
+fn unescape(field: &str) -> String {
+    let mut out = String::new();
+    let mut chars = field.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let code: String = chars.clone().take(3).collect();
+            if code.len() == 3
+                && let Ok(byte) = u8::from_str_radix(&code, 8)
+            {
+                out.push(byte as char);
+                chars.nth(2);
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Parses the contents of a `mountinfo` file.
+pub fn parse_mountinfo(content: &str) -> Vec<MountInfo> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (left, right) = line.split_once(" - ")?;
+            let left: Vec<&str> = left.split_whitespace().collect();
+            let mut right = right.split_whitespace();
+            Some(MountInfo {
+                device: left.get(2)?.to_string(),
+                mount_point: PathBuf::from(unescape(left.get(4)?)),
+                fs_type: right.next()?.to_string(),
+                source: unescape(right.next()?),
+            })
+        })
+        .collect()
+}
+
+/// Reads the mounted filesystems from the given `mountinfo` file. An empty list is returned if it can't be read.
+pub fn read_mounts(mountinfo: &Path) -> Vec<MountInfo> {
+    fs::read_to_string(mountinfo).map(|content| parse_mountinfo(&content)).unwrap_or_default()
+}
+
+/// Finds the filesystem on which `path` is stored, which is the mount with the longest mount point containing it.
+pub fn mount_for<'a>(path: &Path, mounts: &'a [MountInfo]) -> Option<&'a MountInfo> {
+    let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+    mounts.iter().filter(|m| path.starts_with(&m.mount_point)).max_by_key(|m| m.mount_point.as_os_str().len())
+}
+
+/// Reads the UUID of the filesystem of a mount. `/dev/disk/by-uuid` is looked up first, then `blkid` is asked.
+pub fn fs_uuid(mount: &MountInfo) -> Option<String> {
+    let device = fs::canonicalize(&mount.source).ok()?;
+    if let Ok(entries) = fs::read_dir("/dev/disk/by-uuid") {
+        for entry in entries.flatten() {
+            if fs::canonicalize(entry.path()).is_ok_and(|target| target == device) {
+                return Some(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+    let output = Command::new("blkid").args(["-s", "UUID", "-o", "value"]).arg(&device).output().ok()?;
+    let uuid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !uuid.is_empty() { Some(uuid) } else { None }
+}
+
+impl DriveIdentity {
+    /// Reads the identity file at the root `root` of a drive, if there is one.
+    pub fn read(root: &Path) -> Option<DriveIdentity> {
+        let content = fs::read_to_string(root.join(DRIVE_ID_FILE)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Checks whether the identity matches `id`, which can be the id of the identity file or the filesystem UUID.
+    pub fn matches(&self, id: &str) -> bool {
+        self.id == id || self.fs_uuid.as_deref() == Some(id) || self.label.as_deref() == Some(id)
+    }
+}
+
+/// Registers the drive on which `path` is stored, by writing an identity file at the root of its filesystem. If the
+/// drive was already registered, its identity is kept and only the label is updated.
+///
+/// # Input
+/// * `path: &Path` -> A path on the drive
+/// * `label: Option<&str>` -> A name for the drive
+/// * `mountinfo: &Path` -> The `mountinfo` file listing the mounted filesystems
+///
+/// # Output: `Result<(PathBuf, DriveIdentity), String>`
+/// The root of the drive and its identity.
+pub fn register_drive(path: &Path, label: Option<&str>, mountinfo: &Path) -> Result<(PathBuf, DriveIdentity), String> {
+    let mounts = read_mounts(mountinfo);
+    let mount = mount_for(path, &mounts);
+    let root = mount.map(|m| m.mount_point.clone()).unwrap_or(path.to_path_buf());
+    let uuid = mount.and_then(fs_uuid);
+    if uuid.is_none() {
+        log(LogType::Info, &format!("Couldn't read the filesystem UUID of {}. The drive is identified by its identity file only", root.to_string_lossy()));
+    }
+
+    let identity = match DriveIdentity::read(&root) {
+        Some(mut identity) => {
+            if label.is_some() {
+                identity.label = label.map(String::from);
+            }
+            identity.fs_uuid = uuid.or(identity.fs_uuid);
+            identity
+        }
+        None => {
+            let mut bytes = [0u8; 16];
+            getrandom::fill(&mut bytes).map_err(|_| String::from("Couldn't generate an id for the drive"))?;
+            DriveIdentity {
+                id: hex::encode(bytes),
+                label: label.map(String::from),
+                fs_uuid: uuid,
+                created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            }
+        }
+    };
+    let data = serde_json::to_string_pretty(&identity).map_err(|_| String::from("Error during serializing the drive identity"))?;
+    fs::write(root.join(DRIVE_ID_FILE), data).map_err(|_| format!("Couldn't write {} at {}", DRIVE_ID_FILE, root.to_string_lossy()))?;
+    Ok((root, identity))
+}
+
+/// Lists the mounted drives that have an identity file at their root.
+pub fn registered_drives(mountinfo: &Path) -> Vec<(MountInfo, DriveIdentity)> {
+    read_mounts(mountinfo)
+        .into_iter()
+        .filter_map(|mount| DriveIdentity::read(&mount.mount_point).map(|identity| (mount, identity)))
+        .collect()
+}
+
+/// Finds where the drive `id` is mounted. `id` can be the id of the identity file, the filesystem UUID or the label.
+pub fn find_drive(id: &str, mountinfo: &Path) -> Option<PathBuf> {
+    registered_drives(mountinfo).into_iter().find(|(_, identity)| identity.matches(id)).map(|(mount, _)| mount.mount_point)
+}
+
+/// Checks that `path` is stored on the drive `id`. Returns an error describing the drive found there otherwise.
+pub fn check_drive(path: &Path, id: &str, mountinfo: &Path) -> Result<(), String> {
+    let mounts = read_mounts(mountinfo);
+    let root = mount_for(path, &mounts).map(|m| m.mount_point.clone()).unwrap_or(path.to_path_buf());
+    match DriveIdentity::read(&root) {
+        Some(identity) if identity.matches(id) => Ok(()),
+        Some(identity) => Err(format!(
+            "{} is on the drive {} ({}), not on the drive {}",
+            path.to_string_lossy(),
+            identity.id,
+            identity.label.unwrap_or_default(),
+            id
+        )),
+        None => Err(format!("{} is not on a registered drive. Expected the drive {}", path.to_string_lossy(), id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount(point: &str) -> MountInfo {
+        MountInfo { device: String::from("8:1"), mount_point: PathBuf::from(point), fs_type: String::from("ext4"), source: String::from("/dev/sda1") }
+    }
+
+    #[test]
+    fn mountinfo_lines_are_parsed() {
+        let content = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+36 35 98:0 /mnt1 /media/me/My\\040Drive rw,noatime master:1 shared:7 - vfat /dev/sdb1 rw,fmask=0022
+40 22 8:17 / /mnt/back\\134slash\\011tab rw - exfat /dev/sdc\\0401 rw
+not a mountinfo line
+";
+        let mounts = parse_mountinfo(content);
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(mounts[0], MountInfo { device: String::from("8:2"), mount_point: PathBuf::from("/"), fs_type: String::from("ext4"), source: String::from("/dev/sda2") });
+        // The optional fields before the separator can be many
+        assert_eq!(mounts[1].device, "98:0");
+        assert_eq!(mounts[1].mount_point, PathBuf::from("/media/me/My Drive"));
+        assert_eq!(mounts[1].fs_type, "vfat");
+        assert_eq!(mounts[2].mount_point, PathBuf::from("/mnt/back\\slash\ttab"));
+        assert_eq!(mounts[2].source, "/dev/sdc 1");
+    }
+
+    #[test]
+    fn escapes_that_are_not_octal_are_kept() {
+        assert_eq!(unescape("a\\b"), "a\\b");
+        assert_eq!(unescape("a\\09"), "a\\09");
+        assert_eq!(unescape("end\\"), "end\\");
+    }
+
+    #[test]
+    fn the_deepest_mount_holding_a_path_is_found() {
+        let mounts = [mount("/"), mount("/media/ebod-test/RED"), mount("/media/ebod-test/RED2"), mount("/media/ebod-test/RED/inner")];
+        let point = |path: &str| mount_for(Path::new(path), &mounts).map(|m| m.mount_point.to_string_lossy().to_string());
+        assert_eq!(point("/media/ebod-test/RED/photos").as_deref(), Some("/media/ebod-test/RED"));
+        // A mount point only holds the paths below it, not the ones that share a prefix
+        assert_eq!(point("/media/ebod-test/RED2/photos").as_deref(), Some("/media/ebod-test/RED2"));
+        assert_eq!(point("/media/ebod-test/RED/inner/a").as_deref(), Some("/media/ebod-test/RED/inner"));
+        assert_eq!(point("/media/ebod-test/REDX").as_deref(), Some("/"));
+        assert_eq!(mount_for(Path::new("/media"), &mounts[1..]), None);
+    }
+}
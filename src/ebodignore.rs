@@ -0,0 +1,163 @@
+//! Support for `.ebodignore` files.
+//!
+//! A `.ebodignore` file can be placed at any level of the directory tree. Its patterns follow the gitignore syntax,
+//! including negation (`!keep.tmp`), anchoring (`/build`) and directory-only patterns (`target/`). Patterns are relative
+//! to the directory that holds the `.ebodignore` file, and the file closest to an entry takes precedence.
+use std::path::Path;
+
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::vfs::{FileKind, LocalFs, Vfs};
+use crate::{LogType, log};
+
+/// The name of the ignore file that is looked up in every directory.
+pub const IGNORE_FILE: &str = ".ebodignore";
+
+/// The stack of the `.ebodignore` files that apply to the directory being traversed.
+#[derive(Default)]
+pub struct IgnoreRules {
+    stack: Vec<Option<Gitignore>>,
+}
+
+impl IgnoreRules {
+    /// Creates an empty set of rules.
+    pub fn new() -> IgnoreRules {
+        IgnoreRules::default()
+    }
+
+    /// Loads the `.ebodignore` of `dir` (if it exists) on top of the stack. Must be paired with `leave`.
+    pub fn enter(&mut self, dir: &Path) {
+        self.enter_in(&LocalFs, dir);
+    }
+
+    /// Same as `enter`, with the `.ebodignore` read from `vfs`.
+    pub fn enter_in<V: Vfs + ?Sized>(&mut self, vfs: &V, dir: &Path) {
+        let file = dir.join(IGNORE_FILE);
+        if !vfs.stat(&file).is_ok_and(|stat| stat.kind == FileKind::File) {
+            self.stack.push(None);
+            return;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        match vfs.read(&file) {
+            Ok(content) => {
+                for line in String::from_utf8_lossy(&content).lines() {
+                    if let Err(err) = builder.add_line(Some(file.clone()), line) {
+                        log(LogType::Err, &format!("Error in reading {}: {}", file.to_string_lossy(), err));
+                    }
+                }
+            }
+            Err(err) => log(LogType::Err, &format!("Error in reading {}: {}", file.to_string_lossy(), err)),
+        }
+        match builder.build() {
+            Ok(gitignore) => self.stack.push(Some(gitignore)),
+            Err(err) => {
+                log(LogType::Err, &format!("Error in parsing {}: {}", file.to_string_lossy(), err));
+                self.stack.push(None);
+            }
+        }
+    }
+
+    /// Adds the patterns (in the `.ebodignore` syntax) relative to `root` on top of the stack. Used for the `ignore`
+    /// setting of `.ebod/config.toml`, which is added below the `.ebodignore` files so that they take precedence.
+    pub fn add_patterns(&mut self, root: &Path, patterns: &[String]) {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            if let Err(err) = builder.add_line(None, pattern) {
+                log(LogType::Err, &format!("Invalid ignore pattern {}: {}", pattern, err));
+            }
+        }
+        match builder.build() {
+            Ok(gitignore) => self.stack.push(Some(gitignore)),
+            Err(err) => {
+                log(LogType::Err, &format!("Error in parsing the ignore patterns: {}", err));
+                self.stack.push(None);
+            }
+        }
+    }
+
+    /// Removes the rules of the directory that was entered last.
+    pub fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    /// Checks whether the entry at `path` is ignored. The closest `.ebodignore` with a matching pattern decides.
+    ///
+    /// # Input
+    /// * `path: &Path` -> The path of the entry, below the directories that were entered
+    /// * `is_dir: bool` -> Whether the entry is a directory, for directory-only patterns
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.stack.iter().rev().flatten() {
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filters;
+    use crate::recursive_listing_in;
+    use crate::vfs::MemoryFs;
+    use crate::xattrs::Preserve;
+
+    fn source() -> MemoryFs {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("src/sub/build")).unwrap();
+        fs.create_dir_all(Path::new("src/build")).unwrap();
+        fs.write(Path::new("src/.ebodignore"), b"# Temporary files\n*.tmp\nbuild/\n/top.log\n").unwrap();
+        fs.write(Path::new("src/sub/.ebodignore"), b"!keep.tmp\n*.txt\n").unwrap();
+        for file in ["a.tmp", "a.txt", "top.log", "build/out.o", "sub/keep.tmp", "sub/other.tmp", "sub/b.txt", "sub/top.log", "sub/build/out.o"] {
+            fs.write(&Path::new("src").join(file), b"data").unwrap();
+        }
+        fs
+    }
+
+    #[test]
+    fn the_closest_ignore_file_decides() {
+        let fs = source();
+        let mut rules = IgnoreRules::new();
+        rules.enter_in(&fs, Path::new("src"));
+        assert!(rules.is_ignored(Path::new("src/a.tmp"), false));
+        assert!(!rules.is_ignored(Path::new("src/a.txt"), false));
+        // Anchored patterns only match in the directory of the ignore file
+        assert!(rules.is_ignored(Path::new("src/top.log"), false));
+        assert!(!rules.is_ignored(Path::new("src/sub/top.log"), false));
+        // Directory-only patterns don't match files
+        assert!(rules.is_ignored(Path::new("src/build"), true));
+        assert!(!rules.is_ignored(Path::new("src/build"), false));
+        assert!(rules.is_ignored(Path::new("src/sub/build"), true));
+
+        rules.enter_in(&fs, Path::new("src/sub"));
+        assert!(!rules.is_ignored(Path::new("src/sub/keep.tmp"), false));
+        assert!(rules.is_ignored(Path::new("src/sub/other.tmp"), false));
+        assert!(rules.is_ignored(Path::new("src/sub/b.txt"), false));
+        rules.leave();
+        assert!(rules.is_ignored(Path::new("src/sub/keep.tmp"), false));
+        assert!(!rules.is_ignored(Path::new("src/sub/b.txt"), false));
+    }
+
+    #[test]
+    fn ignored_entries_are_left_out_of_the_listing() {
+        let fs = source();
+        let mut data = vec![];
+        recursive_listing_in(&fs, Path::new("src"), Path::new("src"), &mut data, true, Preserve::default(), &Filters::default());
+        let mut names: Vec<String> = data.into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        assert_eq!(names, [".ebodignore", "a.txt", "sub", "sub/.ebodignore", "sub/keep.tmp", "sub/top.log"]);
+
+        // The `ignore` setting is below the ignore files, which can bring entries back
+        fs.create_dir_all(Path::new("src/.ebod")).unwrap();
+        fs.write(Path::new("src/.ebod/config.toml"), b"ignore = [\"*.log\", \"keep.tmp\"]\n").unwrap();
+        let mut data = vec![];
+        recursive_listing_in(&fs, Path::new("src"), Path::new("src"), &mut data, false, Preserve::default(), &Filters::default());
+        let mut names: Vec<String> = data.into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        assert_eq!(names, ["a.txt", "sub", "sub/keep.tmp"]);
+    }
+}
@@ -0,0 +1,536 @@
+//! Compatibility with FAT32 and exFAT destinations.
+//!
+//! USB sticks and memory cards are usually formatted with FAT32 or exFAT. When the destination is on one of them, the
+//! backup works around their limits:
+//! * Modified times are stored with a resolution of 2 seconds, so they are compared with a tolerance of 2 seconds
+//! * Names can't hold `"`, `*`, `:`, `<`, `>`, `?`, `\`, `|` or control characters, nor end with a dot or a space.
+//!   These characters are stored as `%XX`, the bytes of the character in hexadecimal: `a:b` is stored as `a%3Ab`
+//! * Names are case-insensitive, so a name that only differs by case from another name of the destination gets a
+//!   `~N` suffix
+//! * FAT32 files are at most 4 GiB. Larger files are split into parts of `PART_SIZE` bytes, named `<name>.000`,
+//!   `<name>.001`… They can be joined with `cat <name>.0* > <name>`
+//!
+//! The names under which entries are stored are recorded with their original names in `.ebod/names.json`. The listing
+//! of the destination gives back the original names, and the parts of a split file are listed as a single file, so
+//! that the destination can be compared with the source. `sync` joins the parts when it copies a split file back.
+//!
+//! The filesystem of the destination is detected from `/proc/self/mountinfo` (`vfat`, `msdos` and `exfat`), or set
+//! with `--fs-compat`. exFAT mounted with FUSE is listed as `fuseblk`, and isn't detected.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::drive::{mount_for, read_mounts};
+use crate::vfs::Vfs;
+use crate::{EntryType, FileEntry};
+
+/// The file of `.ebod` in which the names of the destination are recorded.
+pub const NAMES_FILE: &str = "names.json";
+/// The largest file that FAT32 can store.
+pub const FAT32_MAX_SIZE: u64 = 4 * 1024 * 1024 * 1024 - 1;
+/// The size of the parts of a file split for FAT32.
+pub const PART_SIZE: u64 = 4095 * 1024 * 1024;
+
+// The characters that FAT and exFAT don't allow in names, besides the control characters
+const FORBIDDEN: [char; 8] = ['"', '*', ':', '<', '>', '?', '\\', '|'];
+
+/// The compatibility with the filesystem of the destination, set with `--fs-compat`.
+///
+/// # Values in Enum
+/// * `Auto` -> The filesystem of the destination is detected (the default)
+/// * `Fat32` -> The destination is handled as FAT32
+/// * `Exfat` -> The destination is handled as exFAT
+/// * `Off` -> The destination is handled as a filesystem without limits
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FsCompat {
+    #[default]
+    Auto,
+    Fat32,
+    Exfat,
+    Off,
+}
+
+/// The filesystem of a destination, as far as the backup is concerned.
+///
+/// # Values in Enum
+/// * `Native` -> A filesystem without the limits of FAT
+/// * `Fat32` -> FAT32 (or FAT16)
+/// * `Exfat` -> exFAT, which has the limits of FAT32 except the size of the files
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DestFs {
+    #[default]
+    Native,
+    Fat32,
+    Exfat,
+}
+
+impl FsCompat {
+    /// The filesystem of the local destination `dest`. With `Auto`, it is detected from the `mountinfo` file.
+    pub fn resolve(self, dest: &Path, mountinfo: &Path) -> DestFs {
+        match self {
+            FsCompat::Auto => DestFs::detect(dest, mountinfo),
+            FsCompat::Fat32 => DestFs::Fat32,
+            FsCompat::Exfat => DestFs::Exfat,
+            FsCompat::Off => DestFs::Native,
+        }
+    }
+}
+
+impl DestFs {
+    /// Detects the filesystem on which `dest` is stored from the `mountinfo` file.
+    pub fn detect(dest: &Path, mountinfo: &Path) -> DestFs {
+        let mounts = read_mounts(mountinfo);
+        match mount_for(dest, &mounts).map(|mount| mount.fs_type.as_str()) {
+            Some("vfat" | "msdos") => DestFs::Fat32,
+            Some("exfat") => DestFs::Exfat,
+            _ => DestFs::Native,
+        }
+    }
+
+    /// The tolerance of the comparison of modified times. FAT and exFAT round them to 2 seconds.
+    pub fn mtime_tolerance(self) -> Duration {
+        if self == DestFs::Native { Duration::ZERO } else { Duration::from_secs(2) }
+    }
+
+    /// The size of the largest file, if the filesystem limits it.
+    pub fn max_file_size(self) -> Option<u64> {
+        (self == DestFs::Fat32).then_some(FAT32_MAX_SIZE)
+    }
+}
+
+/// Escapes the characters of the file name `name` that FAT and exFAT don't allow, as `%XX`. The dots and spaces at
+/// the end of the name are escaped too, since they would be dropped.
+pub fn escape_name(name: &str) -> String {
+    let kept = name.trim_end_matches(['.', ' ']).len();
+    let mut escaped = String::with_capacity(name.len());
+    for (index, c) in name.char_indices() {
+        if FORBIDDEN.contains(&c) || c < ' ' || index >= kept {
+            let mut buffer = [0u8; 4];
+            for byte in c.encode_utf8(&mut buffer).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// `name` with the suffix `~n`, before its extension
+fn with_suffix(name: &str, n: u32) -> String {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{}~{}{}", &name[..dot], n, &name[dot..]),
+        _ => format!("{}~{}", name, n),
+    }
+}
+
+// The path of the part `part` of a file stored at `stored`
+fn part_path(stored: &str, part: u32) -> String {
+    format!("{}.{:03}", stored, part)
+}
+
+/// Picks the paths under which the new entries of a backup are stored in the destination.
+pub struct Namer {
+    fs: DestFs,
+    // The stored paths of the destination, in lowercase
+    taken: HashSet<String>,
+    // The stored paths of the directories that are stored under another path, by name
+    dirs: HashMap<String, String>,
+}
+
+impl Namer {
+    /// A namer for a destination on `fs` that holds the entries `dest_meta`.
+    pub fn new(fs: DestFs, dest_meta: &[FileEntry]) -> Namer {
+        let mut namer = Namer { fs, taken: HashSet::new(), dirs: HashMap::new() };
+        if fs == DestFs::Native {
+            return namer;
+        }
+        for entry in dest_meta {
+            namer.taken.insert(entry.stored_name().to_lowercase());
+            if let Some(stored) = entry.stored_as.as_ref().filter(|_| entry.e_type == EntryType::Dir) {
+                namer.dirs.insert(entry.name.clone(), stored.clone());
+            }
+        }
+        namer
+    }
+
+    /// The path under which the new entry `name` is stored, if it isn't `name`. `entry` is the entry of the source.
+    pub fn store(&mut self, name: &str, entry: &FileEntry) -> Option<String> {
+        if self.fs == DestFs::Native {
+            return None;
+        }
+        let path = Path::new(name);
+        let file_name = escape_name(&path.file_name()?.to_string_lossy());
+        let parent = path.parent().map(|parent| parent.to_string_lossy().to_string()).unwrap_or_default();
+        let stored_parent = self.dirs.get(&parent).cloned().unwrap_or(parent);
+        let join = |file_name: &str| Path::new(&stored_parent).join(file_name).to_string_lossy().to_string();
+
+        let split = self.fs.max_file_size().is_some_and(|max| entry.length > max);
+        let taken = |stored: &str| {
+            let stored = stored.to_lowercase();
+            self.taken.contains(&stored) || (split && self.taken.contains(&part_path(&stored, 0)))
+        };
+        let mut stored = join(&file_name);
+        let mut n = 1;
+        while taken(&stored) {
+            n += 1;
+            stored = join(&with_suffix(&file_name, n));
+        }
+        if split {
+            for part in 0..entry.length.div_ceil(PART_SIZE) as u32 {
+                self.taken.insert(part_path(&stored, part).to_lowercase());
+            }
+        } else {
+            self.taken.insert(stored.to_lowercase());
+        }
+        if stored == name {
+            return None;
+        }
+        if entry.e_type == EntryType::Dir {
+            self.dirs.insert(name.to_string(), stored.clone());
+        }
+        Some(stored)
+    }
+}
+
+/// The original name of an entry stored under another path.
+///
+/// # Members
+/// * `name: String` -> The name of the entry in the source
+/// * `part: Option<u32>` -> The number of the part, for the parts of a split file
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct StoredName {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<u32>,
+}
+
+/// The entries of a destination stored under another path than their name, from `.ebod/names.json`. The keys are the
+/// stored paths.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(transparent)]
+pub struct NameMap {
+    names: BTreeMap<String, StoredName>,
+}
+
+impl NameMap {
+    /// Reads `.ebod/names.json` of `root` from `vfs`. The map is empty if there is no such file.
+    pub fn load_in<V: Vfs + ?Sized>(vfs: &V, root: &Path) -> NameMap {
+        vfs.read(&root.join(".ebod").join(NAMES_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the map into `.ebod/names.json` of `root`. The stored paths that don't exist anymore are dropped.
+    pub fn save_in<V: Vfs + ?Sized>(&mut self, vfs: &V, root: &Path) -> Result<(), String> {
+        self.names.retain(|stored, _| vfs.exists(&root.join(stored)));
+        let path = root.join(".ebod").join(NAMES_FILE);
+        if self.names.is_empty() && !vfs.exists(&path) {
+            return Ok(());
+        }
+        let content = serde_json::to_string_pretty(self).map_err(|_| String::from("Error during serializing the names of the destination"))?;
+        vfs.write(&path, content.as_bytes()).map_err(|err| format!("Couldn't write {}: {}", path.to_string_lossy(), err))
+    }
+
+    /// Records that the entry `name` was copied to the paths `stored`: one path, or the parts of a split file. The
+    /// parts of an older copy are forgotten.
+    pub fn record(&mut self, name: &str, stored: &[String]) {
+        self.names.retain(|_, stored_name| stored_name.name != name);
+        match stored {
+            [single] if single == name => {
+                self.names.remove(single);
+            },
+            [single] => {
+                self.names.insert(single.clone(), StoredName { name: name.to_string(), part: None });
+            },
+            parts => {
+                for (part, path) in parts.iter().enumerate() {
+                    self.names.insert(path.clone(), StoredName { name: name.to_string(), part: Some(part as u32) });
+                }
+            },
+        }
+    }
+
+    /// The stored paths of the parts of `name`, in order, if it was split.
+    pub fn parts(&self, name: &str) -> Vec<String> {
+        let mut parts: Vec<(u32, &String)> = self
+            .names
+            .iter()
+            .filter_map(|(stored, stored_name)| (stored_name.name == name).then_some((stored_name.part?, stored)))
+            .collect();
+        parts.sort();
+        parts.into_iter().map(|(_, stored)| stored.clone()).collect()
+    }
+
+    /// The stored paths of `name`: the path it is stored under, or the parts of a split file. Empty if it is stored
+    /// under its name.
+    pub fn stored_paths(&self, name: &str) -> Vec<String> {
+        let parts = self.parts(name);
+        if !parts.is_empty() {
+            return parts;
+        }
+        self.names.iter().filter(|(_, stored_name)| stored_name.name == name).map(|(stored, _)| stored.clone()).collect()
+    }
+
+    /// Gives back the original names to the `entries` listed from the destination. The entries stored under another
+    /// path get it in `stored_as`, and the parts of a split file are merged into a single entry.
+    pub fn restore(&self, entries: Vec<FileEntry>) -> Vec<FileEntry> {
+        if self.names.is_empty() {
+            return entries;
+        }
+        let mut restored: Vec<FileEntry> = Vec::with_capacity(entries.len());
+        let mut split: HashMap<String, usize> = HashMap::new();
+        for mut entry in entries {
+            let Some(stored_name) = self.names.get(&entry.name) else {
+                restored.push(entry);
+                continue;
+            };
+            if stored_name.part.is_some() {
+                if let Some(&index) = split.get(&stored_name.name) {
+                    restored[index].length += entry.length;
+                    continue;
+                }
+                split.insert(stored_name.name.clone(), restored.len());
+                let base = entry.name.rsplit_once('.').map(|(base, _)| base.to_string()).unwrap_or_default();
+                entry.stored_as = (base != stored_name.name).then_some(base);
+                entry.allocated = None;
+            } else {
+                entry.stored_as = Some(entry.name.clone());
+            }
+            entry.name = stored_name.name.clone();
+            restored.push(entry);
+        }
+        restored
+    }
+}
+
+/// The state of the compatibility layer during a backup.
+///
+/// # Members
+/// * `fs: DestFs` -> The filesystem of the destination
+/// * `names: NameMap` -> The names of the destination, updated as the entries are copied
+/// * `src_names: NameMap` -> The names of the source, which has some when it is a FAT destination synced back
+#[derive(Debug, Clone, Default)]
+pub struct Compat {
+    pub fs: DestFs,
+    pub names: NameMap,
+    pub src_names: NameMap,
+}
+
+impl Compat {
+    /// Reads the names of the source `src` and of the destination `dest`, on the filesystem `fs`.
+    pub fn load_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, fs: DestFs) -> Compat {
+        Compat { fs, names: NameMap::load_in(dest_vfs, dest), src_names: NameMap::load_in(src_vfs, src) }
+    }
+
+    /// The paths of the source file `file` of `src`: the parts of a split file, or the file itself.
+    pub fn sources(&self, src: &Path, file: &FileEntry) -> Vec<PathBuf> {
+        let parts = self.src_names.parts(&file.name);
+        if parts.is_empty() {
+            vec![src.join(file.stored_name())]
+        } else {
+            parts.iter().map(|part| src.join(part)).collect()
+        }
+    }
+
+    /// The size of the parts that `file` is split into, if it is too large for the destination.
+    pub fn part_size(&self, file: &FileEntry) -> Option<u64> {
+        self.fs.max_file_size().filter(|max| file.length > *max).map(|_| PART_SIZE)
+    }
+}
+
+/// Copies the files `sources` one after the other into `dest`, or into the parts `<dest>.000`, `<dest>.001`… of
+/// `part_size` bytes. Used to split the files that are too large for FAT32, and to join them back. `on_copied` is
+/// called with the number of bytes done as they are done.
+///
+/// # Output: `io::Result<(u64, Vec<PathBuf>)>`
+/// The number of bytes copied and the paths written, or the error that stopped the copy.
+pub fn copy_parts_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(
+    src_vfs: &S,
+    sources: &[PathBuf],
+    dest_vfs: &D,
+    dest: &Path,
+    part_size: Option<u64>,
+    mut on_copied: impl FnMut(u64),
+) -> io::Result<(u64, Vec<PathBuf>)> {
+    let mut input: Box<dyn Read + '_> = Box::new(io::empty());
+    for source in sources {
+        input = Box::new(input.chain(src_vfs.open(source)?));
+    }
+    let path = |part: u32| match part_size {
+        Some(_) => PathBuf::from(part_path(&dest.to_string_lossy(), part)),
+        None => dest.to_path_buf(),
+    };
+    let mut written = vec![path(0)];
+    let mut output = dest_vfs.create(&written[0])?;
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut bytes = 0;
+    let mut in_part = 0;
+    loop {
+        // A full part is only followed by another one if there is something left to write
+        let room = part_size.map_or(u64::MAX, |size| if in_part == size { size } else { size - in_part });
+        let limit = room.min(buffer.len() as u64) as usize;
+        let read = match input.read(&mut buffer[..limit]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if part_size == Some(in_part) {
+            output.flush()?;
+            written.push(path(written.len() as u32));
+            output = dest_vfs.create(&written[written.len() - 1])?;
+            in_part = 0;
+        }
+        output.write_all(&buffer[..read])?;
+        bytes += read as u64;
+        in_part += read as u64;
+        on_copied(read as u64);
+    }
+    output.flush()?;
+    Ok((bytes, written))
+}
+
+/// A hint on the cause of the failed copy of a file, for the errors of filesystems like FAT that are often
+/// mysterious.
+pub fn explain(err: &io::Error) -> Option<&'static str> {
+    match err.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidFilename => {
+            Some("the destination doesn't allow this name. FAT and exFAT destinations that aren't detected can be set with --fs-compat")
+        },
+        ErrorKind::FileTooLarge => Some("the file is too large for the destination. FAT32 destinations that aren't detected can be set with --fs-compat fat32"),
+        ErrorKind::StorageFull => Some("the destination is full"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    fn entry(name: &str, length: u64, e_type: EntryType) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            modified_at: 0,
+            modified_nanos: None,
+            length,
+            allocated: None,
+            e_type,
+            #[cfg(target_os = "linux")]
+            inode: 0,
+            #[cfg(target_os = "windows")]
+            file_attr: 0,
+            xattrs: None,
+            acls: None,
+            stored_as: None,
+        }
+    }
+
+    #[test]
+    fn forbidden_characters_and_trailing_dots_are_escaped() {
+        assert_eq!(escape_name("plain name.txt"), "plain name.txt");
+        assert_eq!(escape_name("a:b"), "a%3Ab");
+        assert_eq!(escape_name("what?*.txt"), "what%3F%2A.txt");
+        assert_eq!(escape_name("tab\there"), "tab%09here");
+        assert_eq!(escape_name("name. ."), "name%2E%20%2E");
+        // Only the dots and spaces at the end are escaped
+        assert_eq!(escape_name(".hidden file"), ".hidden file");
+    }
+
+    #[test]
+    fn case_collisions_get_a_suffix() {
+        let dest = [entry("Notes.txt", 1, EntryType::File)];
+        let mut namer = Namer::new(DestFs::Exfat, &dest);
+        assert_eq!(namer.store("notes.txt", &entry("notes.txt", 1, EntryType::File)), Some(String::from("notes~2.txt")));
+        assert_eq!(namer.store("NOTES.txt", &entry("NOTES.txt", 1, EntryType::File)), Some(String::from("NOTES~3.txt")));
+        assert_eq!(namer.store("other.txt", &entry("other.txt", 1, EntryType::File)), None);
+
+        // The entries of a directory stored under another path follow it
+        assert_eq!(namer.store("a:b", &entry("a:b", 0, EntryType::Dir)), Some(String::from("a%3Ab")));
+        assert_eq!(namer.store("a:b/c.txt", &entry("a:b/c.txt", 1, EntryType::File)), Some(String::from("a%3Ab/c.txt")));
+
+        let mut native = Namer::new(DestFs::Native, &dest);
+        assert_eq!(native.store("notes.txt", &entry("notes.txt", 1, EntryType::File)), None);
+    }
+
+    #[test]
+    fn the_parts_of_split_files_are_reserved() {
+        let mut namer = Namer::new(DestFs::Fat32, &[]);
+        let large = entry("big.iso", FAT32_MAX_SIZE + 1, EntryType::File);
+        assert_eq!(namer.store("big.iso", &large), None);
+        // `big.iso.000` and `big.iso.001` are taken by the parts
+        assert_eq!(namer.store("big.iso.001", &entry("big.iso.001", 1, EntryType::File)), Some(String::from("big.iso~2.001")));
+        assert_eq!(namer.store("BIG.iso", &large), Some(String::from("BIG~2.iso")));
+
+        // exFAT has no size limit, so nothing is split
+        let mut exfat = Namer::new(DestFs::Exfat, &[]);
+        assert_eq!(exfat.store("big.iso", &large), None);
+        assert_eq!(exfat.store("big.iso.001", &entry("big.iso.001", 1, EntryType::File)), None);
+    }
+
+    #[test]
+    fn files_are_split_into_parts_and_joined_back() {
+        let fs = MemoryFs::new();
+        let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        fs.write(Path::new("file"), &data).unwrap();
+
+        let mut reported = 0;
+        let (bytes, parts) = copy_parts_in(&fs, &[PathBuf::from("file")], &fs, Path::new("split"), Some(1000), |done| reported += done).unwrap();
+        assert_eq!((bytes, reported), (2500, 2500));
+        assert_eq!(parts, ["split.000", "split.001", "split.002"].map(PathBuf::from));
+        assert_eq!(fs.read(&parts[0]).unwrap(), &data[..1000]);
+        assert_eq!(fs.read(&parts[1]).unwrap(), &data[1000..2000]);
+        assert_eq!(fs.read(&parts[2]).unwrap(), &data[2000..]);
+
+        let (bytes, joined) = copy_parts_in(&fs, &parts, &fs, Path::new("joined"), None, |_| {}).unwrap();
+        assert_eq!((bytes, joined), (2500, vec![PathBuf::from("joined")]));
+        assert_eq!(fs.read(Path::new("joined")).unwrap(), data);
+
+        // A file of exactly two parts isn't followed by an empty third one
+        let (_, parts) = copy_parts_in(&fs, &[PathBuf::from("joined")], &fs, Path::new("even"), Some(1250), |_| {}).unwrap();
+        assert_eq!(parts, ["even.000", "even.001"].map(PathBuf::from));
+        assert!(!fs.exists(Path::new("even.002")));
+    }
+
+    #[test]
+    fn stored_names_are_restored() {
+        let mut names = NameMap::default();
+        names.record("a:b.txt", &[String::from("a%3Ab.txt")]);
+        names.record("big.iso", &[String::from("big.iso.000"), String::from("big.iso.001")]);
+        names.record("Big.iso", &[String::from("Big~2.iso.000"), String::from("Big~2.iso.001")]);
+        assert_eq!(names.stored_paths("a:b.txt"), ["a%3Ab.txt"]);
+        assert_eq!(names.parts("big.iso"), ["big.iso.000", "big.iso.001"]);
+
+        let listed = vec![
+            entry("a%3Ab.txt", 3, EntryType::File),
+            entry("big.iso.000", 1000, EntryType::File),
+            entry("big.iso.001", 500, EntryType::File),
+            entry("Big~2.iso.000", 1000, EntryType::File),
+            entry("Big~2.iso.001", 20, EntryType::File),
+            entry("other.txt", 5, EntryType::File),
+        ];
+        let restored = names.restore(listed);
+        let summary: Vec<(&str, u64, Option<&str>)> =
+            restored.iter().map(|entry| (entry.name.as_str(), entry.length, entry.stored_as.as_deref())).collect();
+        assert_eq!(
+            summary,
+            [
+                ("a:b.txt", 3, Some("a%3Ab.txt")),
+                ("big.iso", 1500, None),
+                ("Big.iso", 1020, Some("Big~2.iso")),
+                ("other.txt", 5, None),
+            ]
+        );
+
+        // A file copied again under its name is forgotten
+        names.record("a:b.txt", &[String::from("a:b.txt")]);
+        assert!(names.stored_paths("a:b.txt").is_empty());
+    }
+}
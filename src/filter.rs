@@ -0,0 +1,265 @@
+//! Ad-hoc filters given on the command line with `--include`, `--exclude`, `--min-size`, `--max-size`,
+//! `--newer-than` and `--older-than`.
+//!
+//! A backup or sync between two directories indexes both sides without the filters, and applies them while planning,
+//! so that `diff` and `--dry-run` can tell why each entry was left out. The other targets (archives, zip files,
+//! encrypted and remote destinations) write the metadata of the source, so they index it with the filters, and the
+//! excluded entries never make it into that metadata.
+use std::fmt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::{EntryType, FileEntry};
+
+/// The reason for which a filter left an entry out.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Exclusion {
+    NotIncluded,
+    Excluded(String),
+    TooSmall(u64),
+    TooLarge(u64),
+    TooOld(String),
+    TooNew(String),
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exclusion::NotIncluded => write!(f, "doesn't match any --include pattern"),
+            Exclusion::Excluded(pattern) => write!(f, "matches --exclude {}", pattern),
+            Exclusion::TooSmall(size) => write!(f, "smaller than --min-size {} bytes", size),
+            Exclusion::TooLarge(size) => write!(f, "larger than --max-size {} bytes", size),
+            Exclusion::TooOld(age) => write!(f, "not modified within --newer-than {}", age),
+            Exclusion::TooNew(age) => write!(f, "modified within --older-than {}", age),
+        }
+    }
+}
+
+/// The set of filters that decide which entries are indexed and backed up. The default value lets everything through.
+#[derive(Debug, Default, Clone)]
+pub struct Filters {
+    include: Option<(GlobSet, Vec<String>)>,
+    exclude: Option<(GlobSet, Vec<String>)>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<(u64, String)>,
+    older_than: Option<(u64, String)>,
+}
+
+fn build_globs(patterns: &[String]) -> Result<Option<(GlobSet, Vec<String>)>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| format!("Invalid glob pattern {}: {}", pattern, e))?);
+    }
+    let set = builder.build().map_err(|e| format!("Invalid glob patterns: {}", e))?;
+    Ok(Some((set, patterns.to_vec())))
+}
+
+fn cutoff(age: &str) -> Result<(u64, String), String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Ok((now.saturating_sub(parse_age(age)?), age.to_string()))
+}
+
+/// Parses a size such as `512`, `10K`, `1.5M` or `2GiB` into bytes. The units are powers of 1024.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("Invalid size: {}", size))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("Invalid size unit in {}. Use B, K, M, G or T", size)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses an age such as `30s`, `15m`, `12h`, `7d` or `2w` into seconds.
+pub fn parse_age(age: &str) -> Result<u64, String> {
+    let age = age.trim();
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (number, unit) = age.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("Invalid age: {}", age))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "" | "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Invalid age unit in {}. Use s, m, h, d or w", age)),
+    };
+    Ok(number * multiplier)
+}
+
+impl Filters {
+    /// Creates the filters from the values given on the command line.
+    ///
+    /// # Input
+    /// * `include: &[String]` -> Glob patterns. If any is given, only the files matching one of them are kept
+    /// * `exclude: &[String]` -> Glob patterns. Files and directories matching one of them are left out
+    /// * `min_size: Option<u64>`, `max_size: Option<u64>` -> Bounds on the size of files in bytes
+    /// * `newer_than: Option<&str>`, `older_than: Option<&str>` -> Bounds on the age of files, such as `7d`
+    ///
+    /// # Output: `Result<Filters, String>`
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        min_size: Option<u64>,
+        max_size: Option<u64>,
+        newer_than: Option<&str>,
+        older_than: Option<&str>,
+    ) -> Result<Filters, String> {
+        Ok(Filters {
+            include: build_globs(include)?,
+            exclude: build_globs(exclude)?,
+            min_size,
+            max_size,
+            newer_than: newer_than.map(cutoff).transpose()?,
+            older_than: older_than.map(cutoff).transpose()?,
+        })
+    }
+
+    /// Returns `true` if no filter was given.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none()
+            && self.exclude.is_none()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.newer_than.is_none()
+            && self.older_than.is_none()
+    }
+
+    /// Checks an entry against the filters.
+    ///
+    /// `--exclude` applies to files and directories, and an entry inside an excluded directory is excluded as well. All
+    /// the other filters only apply to files, so that directories are still traversed.
+    ///
+    /// # Output: `Result<(), Exclusion>`
+    /// `Ok(())` if the entry is kept, or the reason for which it is left out.
+    pub fn check(&self, entry: &FileEntry) -> Result<(), Exclusion> {
+        if let Some((set, patterns)) = &self.exclude {
+            for path in Path::new(&entry.name).ancestors().filter(|p| !p.as_os_str().is_empty()) {
+                if let Some(idx) = set.matches(path).first() {
+                    return Err(Exclusion::Excluded(patterns[*idx].clone()));
+                }
+            }
+        }
+        if entry.e_type == EntryType::Dir {
+            return Ok(());
+        }
+        if let Some((set, _)) = &self.include
+            && !set.is_match(&entry.name)
+        {
+            return Err(Exclusion::NotIncluded);
+        }
+        if let Some(min) = self.min_size
+            && entry.length < min
+        {
+            return Err(Exclusion::TooSmall(min));
+        }
+        if let Some(max) = self.max_size
+            && entry.length > max
+        {
+            return Err(Exclusion::TooLarge(max));
+        }
+        if let Some((since, age)) = &self.newer_than
+            && entry.modified_at < *since
+        {
+            return Err(Exclusion::TooOld(age.clone()));
+        }
+        if let Some((since, age)) = &self.older_than
+            && entry.modified_at >= *since
+        {
+            return Err(Exclusion::TooNew(age.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, length: u64, modified_at: u64, e_type: EntryType) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            modified_at,
+            modified_nanos: None,
+            length,
+            allocated: None,
+            e_type,
+            #[cfg(target_os = "linux")]
+            inode: 0,
+            #[cfg(target_os = "windows")]
+            file_attr: 0,
+            xattrs: None,
+            acls: None,
+            stored_as: None,
+        }
+    }
+
+    #[test]
+    fn sizes_are_parsed_in_powers_of_1024() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("10kb"), Ok(10 * 1024));
+        assert_eq!(parse_size("1.5M"), Ok(1024 * 1024 * 3 / 2));
+        assert_eq!(parse_size("0.5 KiB"), Ok(512));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        for size in ["", "M", "10X", "1.2.3K", "-5"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn ages_are_parsed_in_days_by_default() {
+        assert_eq!(parse_age("30s"), Ok(30));
+        assert_eq!(parse_age("15m"), Ok(15 * 60));
+        assert_eq!(parse_age("12h"), Ok(12 * 3600));
+        assert_eq!(parse_age("7d"), Ok(7 * 86400));
+        assert_eq!(parse_age("7"), Ok(7 * 86400));
+        assert_eq!(parse_age("2w"), Ok(14 * 86400));
+        for age in ["", "d", "1.5d", "3y", "5 D"] {
+            assert!(parse_age(age).is_err(), "{}", age);
+        }
+    }
+
+    #[test]
+    fn globs_include_and_exclude_entries() {
+        let filters = Filters::new(&[String::from("*.jpg")], &[String::from("tmp"), String::from("*.bak.jpg")], None, None, None, None).unwrap();
+        assert!(!filters.is_empty());
+        assert_eq!(filters.check(&entry("photos/a.jpg", 1, 0, EntryType::File)), Ok(()));
+        assert_eq!(filters.check(&entry("notes.txt", 1, 0, EntryType::File)), Err(Exclusion::NotIncluded));
+        assert_eq!(filters.check(&entry("a.bak.jpg", 1, 0, EntryType::File)), Err(Exclusion::Excluded(String::from("*.bak.jpg"))));
+        // The entries of an excluded directory are excluded too, and directories are never left out by --include
+        assert_eq!(filters.check(&entry("tmp", 0, 0, EntryType::Dir)), Err(Exclusion::Excluded(String::from("tmp"))));
+        assert_eq!(filters.check(&entry("tmp/b.jpg", 1, 0, EntryType::File)), Err(Exclusion::Excluded(String::from("tmp"))));
+        assert_eq!(filters.check(&entry("photos", 0, 0, EntryType::Dir)), Ok(()));
+
+        assert!(Filters::new(&[String::from("[")], &[], None, None, None, None).is_err());
+        assert!(Filters::default().is_empty());
+    }
+
+    #[test]
+    fn sizes_and_ages_only_apply_to_files() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let filters = Filters::new(&[], &[], Some(10), Some(100), Some("7d"), Some("1d")).unwrap();
+        let two_days_ago = now - 2 * 86400;
+        assert_eq!(filters.check(&entry("ok", 50, two_days_ago, EntryType::File)), Ok(()));
+        assert_eq!(filters.check(&entry("small", 5, two_days_ago, EntryType::File)), Err(Exclusion::TooSmall(10)));
+        assert_eq!(filters.check(&entry("large", 500, two_days_ago, EntryType::File)), Err(Exclusion::TooLarge(100)));
+        assert_eq!(filters.check(&entry("old", 50, now - 30 * 86400, EntryType::File)), Err(Exclusion::TooOld(String::from("7d"))));
+        assert_eq!(filters.check(&entry("new", 50, now, EntryType::File)), Err(Exclusion::TooNew(String::from("1d"))));
+        assert_eq!(filters.check(&entry("dir", 0, 0, EntryType::Dir)), Ok(()));
+    }
+}
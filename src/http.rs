@@ -0,0 +1,277 @@
+//! Helpers shared by the backends that talk HTTP: URL encoding, the dates of HTTP headers and listings, and the
+//! extraction of elements from the XML responses of S3 and WebDAV servers.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::vfs::{FileKind, Stat};
+
+// Percent-encodes everything but the unreserved characters of RFC 3986 (and `/` if `keep_slash` is set)
+pub(crate) fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Decodes the `%XX` sequences of a URL
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = value.get(index + 1..index + 3).filter(|_| bytes[index] == b'%').and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            },
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// The days since the epoch of a civil date (the inverse of `utc_datetime`)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn time_from_parts(year: i64, month: u32, day: u32, hour: u64, minute: u64, second: u64) -> SystemTime {
+    let days = days_from_civil(year, month, day).max(0) as u64;
+    UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+// Parses the `LastModified` of an S3 listing: `2026-01-31T23:59:59.000Z`
+pub(crate) fn parse_iso8601(value: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| value.get(range).and_then(|part| part.parse::<u64>().ok());
+    Some(time_from_parts(number(0..4)? as i64, number(5..7)? as u32, number(8..10)? as u32, number(11..13)?, number(14..16)?, number(17..19)?))
+}
+
+// Parses the `Last-Modified` header and the `getlastmodified` property: `Sat, 31 Jan 2026 23:59:59 GMT`
+pub(crate) fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (day, month, year, time) = (parts.get(1)?, parts.get(2)?, parts.get(3)?, parts.get(4)?);
+    let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+    let mut clock = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    Some(time_from_parts(year.parse().ok()?, month, day.parse().ok()?, hour, minute, second))
+}
+
+// Decodes the entities of an XML text
+pub(crate) fn unescape(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        plain.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find(';') else {
+            break;
+        };
+        let entity = &rest[start + 1..start + end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => plain.push(c),
+            None => plain.push_str(&rest[start..start + end + 1]),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    plain.push_str(rest);
+    plain
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// The contents of every `<tag>` element of `xml`, in order. The namespace prefix of the element is ignored
+// (`<d:href>` is a `href`), and an empty element (`<d:collection/>`) has empty contents. The responses of S3 and
+// WebDAV servers don't nest an element in another one of the same name.
+pub(crate) fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let name_end = rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/').unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        if name.is_empty() || name.rsplit(':').next() != Some(tag) {
+            continue;
+        }
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        if rest[..tag_end].ends_with('/') {
+            found.push("");
+            rest = &rest[tag_end + 1..];
+            continue;
+        }
+        let contents = &rest[tag_end + 1..];
+        let close = format!("</{}>", name);
+        let Some(end) = contents.find(&close) else {
+            break;
+        };
+        found.push(&contents[..end]);
+        rest = &contents[end + close.len()..];
+    }
+    found
+}
+
+pub(crate) fn element(xml: &str, tag: &str) -> Option<String> {
+    elements(xml, tag).first().map(|text| unescape(text))
+}
+
+// The metadata of a remote file, which has no inode nor permissions
+pub(crate) fn file_stat(length: u64, modified: SystemTime) -> Stat {
+    Stat {
+        kind: FileKind::File,
+        length,
+        allocated: length,
+        modified,
+        inode: 0,
+        mode: 0o644,
+        #[cfg(target_os = "windows")]
+        file_attr: 0,
+    }
+}
+
+pub(crate) fn dir_stat(modified: SystemTime) -> Stat {
+    Stat { kind: FileKind::Dir, mode: 0o755, ..file_stat(0, modified) }
+}
+
+/// A minimal HTTP/1.1 server for the tests of the S3 and WebDAV backends. Every connection is served by its own
+/// thread, and every request is answered by the handler.
+#[cfg(test)]
+pub(crate) mod test_server {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    /// A request received by the server. The names of the headers are in lowercase.
+    #[derive(Debug)]
+    pub(crate) struct TestRequest {
+        pub method: String,
+        pub path: String,
+        pub query: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl TestRequest {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+        }
+
+        /// The decoded value of the parameter `name` of the query.
+        pub fn param(&self, name: &str) -> Option<String> {
+            self.query.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name).map(|(_, value)| super::percent_decode(value))
+        }
+    }
+
+    /// The answer to a request. The body isn't sent for a `HEAD` request, but its length is.
+    #[derive(Debug)]
+    pub(crate) struct TestResponse {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl TestResponse {
+        pub fn new(status: u16) -> TestResponse {
+            TestResponse { status, headers: vec![], body: vec![] }
+        }
+
+        pub fn body(mut self, body: impl Into<Vec<u8>>) -> TestResponse {
+            self.body = body.into();
+            self
+        }
+
+        pub fn header(mut self, name: &str, value: &str) -> TestResponse {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    /// Starts a server on a free port of the loopback interface. Returns its address, like `127.0.0.1:34567`.
+    pub(crate) fn start(handler: impl Fn(TestRequest) -> TestResponse + Send + Sync + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("a free port");
+        let address = listener.local_addr().expect("a bound address").to_string();
+        let handler = Arc::new(handler);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = Arc::clone(&handler);
+                std::thread::spawn(move || serve(stream, handler.as_ref()));
+            }
+        });
+        address
+    }
+
+    fn serve(stream: TcpStream, handler: &dyn Fn(TestRequest) -> TestResponse) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        let mut reader = BufReader::new(stream);
+        while let Some(request) = read_request(&mut reader) {
+            let head = request.method == "HEAD";
+            let response = handler(request);
+            let mut text = format!("HTTP/1.1 {} Test\r\nContent-Length: {}\r\n", response.status, response.body.len());
+            for (name, value) in &response.headers {
+                text.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            text.push_str("\r\n");
+            let mut data = text.into_bytes();
+            if !head {
+                data.extend_from_slice(&response.body);
+            }
+            if writer.write_all(&data).and_then(|_| writer.flush()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn read_request(reader: &mut impl BufRead) -> Option<TestRequest> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok().filter(|read| *read > 0)?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (path, query) = (path.to_string(), query.to_string());
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).ok()?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':')?;
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+        let length = headers.iter().find(|(name, _)| name == "content-length").and_then(|(_, value)| value.parse().ok()).unwrap_or(0);
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).ok()?;
+        Some(TestRequest { method, path, query, headers, body })
+    }
+}
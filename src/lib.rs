@@ -1,13 +1,49 @@
 use colored::Colorize;
-use std::{fs::{self, create_dir_all}, path::PathBuf, process::exit};
-#[cfg(target_os = "windows")]
-use std::os::windows::fs::MetadataExt;
-#[cfg(target_os = "linux")]
-use std::os::unix::fs::MetadataExt;
-use std::{time::UNIX_EPOCH};
+use std::{fs, path::PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
-use std::{io::Write, path::{Path}};
+use std::{io::{Read, Write}, path::{Path}};
 use std::{fs::remove_file, io::ErrorKind};
+use ebodignore::IgnoreRules;
+use filter::Filters;
+use config::DirConfig;
+use plan::{Action, PlannedEntry, plan_dirs};
+use output::{Event, OutputReporter, Verbosity};
+use progress::Progress;
+use vfs::{FileKind, LocalFs, Stat, Vfs};
+use xattrs::Preserve;
+use fat::{Compat, NameMap};
+
+pub mod api;
+pub mod archive;
+pub mod checksum;
+pub mod config;
+pub mod crypto;
+pub mod daemon;
+pub mod delta;
+pub mod drive;
+pub mod ebodignore;
+pub mod fat;
+pub mod filter;
+mod http;
+pub mod output;
+pub mod plan;
+pub mod profile;
+pub mod progress;
+pub mod reflink;
+pub mod remote;
+pub mod s3;
+pub mod schedule;
+pub mod sftp;
+pub mod sparse;
+pub mod vfs;
+pub mod watch;
+pub mod webdav;
+pub mod xattrs;
+pub mod zip_archive;
+
+pub use api::{Backup, BackupOptions, BackupReport, Side, SyncOptions, TwoWaySync};
+pub use output::{NoReporter, Reporter};
 
 /// Enum to store either the file entry is a `File` or a `Dir` (Directory).
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -21,20 +57,153 @@ pub enum EntryType {
 /// # Members
 /// * `name: String` -> Stores the relative path of a file from the root directory where `ebod` is called.
 /// * `modified_at: u64` -> The timestamp is seconds when the file or directory was last modified.
+/// * `modified_nanos: Option<u32>` -> The nanoseconds of the modified time, if it has any. The `metadata.json` written
+///   by older versions has whole seconds only
 /// * `length: u64` -> The size of the file or directory in bytes
+/// * `allocated: Option<u64>` -> The bytes allocated to a sparse file on disk, if fewer than `length`
 /// * `e_type: EntryType` -> The type of the entry. Either `EntryType::File` or `EntryType::Dir`.
 /// * `inode: u64` **[LINUX ONLY]** -> Stores the Inode number of the file.
 /// * `file_attr: u32` **[WINDOWS ONLY]** -> Stores the File attribute of the file.
+/// * `xattrs: Option<String>` -> A hash of the extended attributes that aren't ACLs, if there are any
+/// * `acls: Option<String>` -> A hash of the POSIX ACLs, if there are any
+/// * `stored_as: Option<String>` -> The path under which the entry is stored, if it isn't `name`. Only set in the
+///   listing of a FAT or exFAT destination, for the names that it doesn't allow (see `fat`)
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct FileEntry {
     pub name: String,
     pub modified_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_nanos: Option<u32>,
     pub length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allocated: Option<u64>,
     pub e_type: EntryType,
     #[cfg(target_os = "linux")]
     pub inode: u64,
     #[cfg(target_os = "windows")]
     pub file_attr: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acls: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_as: Option<String>,
+}
+
+impl FileEntry {
+    /// The path under which the entry is stored, relative to its directory.
+    pub fn stored_name(&self) -> &str {
+        self.stored_as.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The modified time of the entry, to the nanosecond.
+    pub fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.modified_at, self.modified_nanos.unwrap_or(0))
+    }
+
+    /// The difference between the modified times of two entries. When either has whole seconds only, like the entries
+    /// of an older `metadata.json` or of a filesystem that doesn't store nanoseconds, the seconds are compared.
+    pub fn mtime_diff(&self, other: &FileEntry) -> Duration {
+        if self.modified_nanos.is_none() || other.modified_nanos.is_none() {
+            return Duration::from_secs(self.modified_at.abs_diff(other.modified_at));
+        }
+        let (a, b) = (self.modified(), other.modified());
+        a.duration_since(b).or_else(|_| b.duration_since(a)).unwrap_or_default()
+    }
+}
+
+/// The counts of what a backup did, returned by `backup_with_config` and `sync_dirs_with_config`.
+/// 
+/// # Members
+/// * `copied: u64` -> Files copied under the same name
+/// * `renamed: u64` -> Conflicting files copied under a new name
+/// * `overwritten: u64` -> Conflicting files that overwrote the file in the destination
+/// * `kept: u64` -> Conflicting files for which the file in the destination was kept
+/// * `dirs_created: u64` -> Directories created in the destination
+/// * `identical: u64` -> Entries that already existed in the destination
+/// * `excluded: u64` -> Entries left out by the filters
+/// * `bytes: u64` -> Bytes copied
+/// * `attrs_updated: u64` -> Entries whose extended attributes or ACLs alone were updated
+/// * `bytes_cloned: u64` -> Bytes shared with the source by a reflink instead of copied, counted in `bytes` too
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupSummary {
+    pub copied: u64,
+    pub renamed: u64,
+    pub overwritten: u64,
+    pub kept: u64,
+    pub dirs_created: u64,
+    pub identical: u64,
+    pub excluded: u64,
+    pub bytes: u64,
+    #[serde(default)]
+    pub attrs_updated: u64,
+    #[serde(default)]
+    pub bytes_cloned: u64,
+}
+
+impl BackupSummary {
+    /// Adds the counts of `other` to this summary. Used to combine both directions of a sync.
+    pub fn add(&mut self, other: &BackupSummary) {
+        self.copied += other.copied;
+        self.renamed += other.renamed;
+        self.overwritten += other.overwritten;
+        self.kept += other.kept;
+        self.dirs_created += other.dirs_created;
+        self.identical += other.identical;
+        self.excluded += other.excluded;
+        self.bytes += other.bytes;
+        self.attrs_updated += other.attrs_updated;
+        self.bytes_cloned += other.bytes_cloned;
+    }
+}
+
+/// How a file was copied.
+///
+/// # Values in Enum
+/// * `Reflink` -> The copy shares the blocks of the source (`FICLONE`), nothing was copied
+/// * `Sparse` -> Only the data ranges of a sparse file were copied, and its holes were kept
+/// * `CopyFileRange` -> The file was copied in the kernel with `copy_file_range`
+/// * `Delta` -> Only the blocks that changed were written over the older copy
+/// * `Userspace` -> The file was read and written by ebod
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyMethod {
+    Reflink,
+    Sparse,
+    CopyFileRange,
+    Delta,
+    Userspace,
+}
+
+impl std::fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::Sparse => "sparse copy",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::Delta => "delta",
+            CopyMethod::Userspace => "userspace copy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What copying a file did, returned by `copy_with_progress_in` and `update_with_progress_in`.
+///
+/// # Members
+/// * `bytes: u64` -> The bytes of the source that were copied, holes left out
+/// * `method: CopyMethod` -> How the file was copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Copied {
+    pub bytes: u64,
+    pub method: CopyMethod,
+}
+
+impl Copied {
+    /// The bytes that were cloned instead of copied.
+    pub fn cloned(&self) -> u64 {
+        if self.method == CopyMethod::Reflink { self.bytes } else { 0 }
+    }
 }
 
 /// A structure that holds the types of messages that can be displayed by the log function.
@@ -43,6 +212,8 @@ pub struct FileEntry {
 /// * `Info` -> Used when warnings or information messages are required to be displayed
 /// * `Ok` -> Used to display success messages
 /// * `Err` -> Used to display error messages
+/// * `Debug` -> Used for details that are only displayed with `-v`
+/// * `Trace` -> Used for details that are only displayed with `-vv`
 /// 
 /// # Macros Applied
 /// * `Debug`
@@ -53,45 +224,116 @@ pub enum LogType {
     Info,
     Ok,
     Err,
+    Debug,
+    Trace,
 }
 
-/// Function with which all the progress is logged in `ebod`. The `LogType` has three options: `Info`, `Ok` and `Err`. `Info` is mapped to yellow, `Ok` to green and `Err` to red for efficient and easy to read logging.
+/// Function with which all the progress is logged in `ebod`. `Info` is mapped to yellow, `Ok` to green and `Err` to red for efficient and easy to read logging. `Debug` and `Trace` are dimmed.
+/// 
+/// Which messages are displayed depends on the verbosity:
+/// * `-q` -> Only `Err`
+/// * Default -> `Err`, `Info` and `Ok`
+/// * `-v` -> `Debug` too
+/// * `-vv` -> `Trace` too
+///
+/// Every message is written to the `--log-file` and to the run history, whatever the verbosity. The log goes to
+/// stderr when `--output json|ndjson` is used, and every `Err` message is also recorded as an error event.
 /// 
 /// # Input
 /// * `logtype: LogType` -> The type of message that should be dispalyed
 /// * `msg: &str` -> The message to be displayed
 // Logging function for color-coded log messages
 pub fn log(logtype: LogType, msg: &str) {
+    let (label, level) = match logtype {
+        LogType::Info => ("INFO", Verbosity::Normal),
+        LogType::Ok => ("OK", Verbosity::Normal),
+        LogType::Err => ("ERR!", Verbosity::Quiet),
+        LogType::Debug => ("DEBUG", Verbosity::Verbose),
+        LogType::Trace => ("TRACE", Verbosity::Trace),
+    };
+    output::record(&format!("{:<5} {}", label, msg));
+    if output::verbosity() < level {
+        if logtype == LogType::Err {
+            output::emit(Event::Error { message: msg.to_string() });
+        }
+        return;
+    }
     if logtype == LogType::Info {
-        println!("{}: {}", " INFO ".on_yellow().bold(), msg);
+        output::print_unrecorded(&format!("{}: {}", " INFO ".on_yellow().bold(), msg));
     } else if logtype == LogType::Ok {
-        println!("{}: {}", "  OK  ".on_green().bold(), msg);
+        output::print_unrecorded(&format!("{}: {}", "  OK  ".on_green().bold(), msg));
     } else if logtype == LogType::Err {
-        println!("{}: {}", " ERR! ".on_red().bold(), msg.red());
+        output::print_unrecorded(&format!("{}: {}", " ERR! ".on_red().bold(), msg.red()));
+        output::emit(Event::Error { message: msg.to_string() });
+    } else {
+        output::print_unrecorded(&format!("{}: {}", format!(" {:<5}", label).dimmed(), msg.dimmed()));
     }
 }
 
+/// Converts a UNIX timestamp in seconds into the UTC date and time: `(year, month, day, hour, minute, second)`.
+pub fn utc_datetime(secs: u64) -> (u16, u8, u8, u8, u8, u8) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil date from the days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+    (year, month, day, (rem / 3600) as u8, (rem % 3600 / 60) as u8, (rem % 60) as u8)
+}
+
 /// A function to initalize the directory for `ebod`. This is the function that is called when the `ebod init` command is executed. It creates the `./.ebod/metadata.json` file. Scans the directory for information of files and loads the metadata into `metadata.json`. 
 /// 
 /// The function also hides the `./.ebod` directory in **Windows** only as files and directories starting with `.` are automatically hidden in **Linux** systems.
 /// 
 /// # Input
-/// * `path: &PathBuf` -> The path of the directory in which `ebod` should be initialized
+/// * `path: &Path` -> The path of the directory in which `ebod` should be initialized
 /// * include_hidden: bool` -> The boolean flag which tells whether to include or exclude hidden files
 // A function to create metadata about the directory in .ebod/metadata.json
-pub fn initialize_dir(path: &PathBuf, include_hidden: bool) {
+pub fn initialize_dir(path: &Path, include_hidden: bool) {
+    initialize_dir_with_filters(path, include_hidden, &Filters::default());
+}
+
+/// Same as `initialize_dir`, but the entries left out by `filters` are not recorded in the metadata.
+/// 
+/// # Input
+/// * `path: &Path` -> The path of the directory in which `ebod` should be initialized
+/// * `include_hidden: bool` -> The boolean flag which tells whether to include or exclude hidden files
+/// * `filters: &Filters` -> The filters given with `--include`, `--exclude`, `--min-size`, etc.
+pub fn initialize_dir_with_filters(path: &Path, include_hidden: bool, filters: &Filters) {
+    initialize_dir_with_config(path, filters, &DirConfig { include_hidden, ..DirConfig::default() });
+}
+
+/// Same as `initialize_dir_with_filters`, with `include_hidden` taken from `config`. The hashes of the extended
+/// attributes and ACLs are only recorded if `config` preserves them (`xattrs`, `acls`).
+///
+/// # Input
+/// * `path: &Path` -> The path of the directory in which `ebod` should be initialized
+/// * `filters: &Filters` -> The filters given with `--include`, `--exclude`, `--min-size`, etc.
+/// * `config: &DirConfig` -> The settings merged from `.ebod/config.toml` and the command line
+pub fn initialize_dir_with_config(path: &Path, filters: &Filters, config: &DirConfig) {
+    if crypto::is_repository(path) {
+        log(LogType::Err, &format!("{} is an encrypted repository and can't be initialized", path.to_string_lossy()));
+        return;
+    }
+
     let mut data: Vec<FileEntry> = vec![];
-    recursive_listing(&path, &path, &mut data, include_hidden);
+    recursive_listing_in(&LocalFs, path, path, &mut data, config.include_hidden, config.preserve(), filters);
 
     // pushing ".ebod/" into path
     let config_path = Path::new(path).join(".ebod");
 
-    // deleting previous metadata.json file if the .ebod folder exists
-    if config_path.exists() {
-        if let Ok(_success) = fs::remove_dir_all(&config_path) {
-            log(LogType::Info, "Cleaned the pre-existing .ebod directories");
+    // deleting previous metadata.json file if it exists. The rest of .ebod (config.toml) is kept
+    if config_path.join("metadata.json").exists() {
+        if let Ok(_success) = fs::remove_file(config_path.join("metadata.json")) {
+            log(LogType::Info, "Cleaned the pre-existing metadata.json");
         } else {
-            log(LogType::Err, "Error in deleting pre-exisiting .ebod directories");
+            log(LogType::Err, "Error in deleting pre-exisiting metadata.json");
         }
     }
 
@@ -102,6 +344,16 @@ pub fn initialize_dir(path: &PathBuf, include_hidden: bool) {
         log(LogType::Err, "Error Occurred during directory creation");
     }
 
+    // creating config.toml if it doesn't exist yet. It keeps `include_hidden`, so that the next backups list the same
+    // entries, and the defaults for the rest: the other settings given on the command line only apply to this run
+    if !config::config_path(path).exists() {
+        if let Err(err) = (DirConfig { include_hidden: config.include_hidden, ..DirConfig::default() }).save(path) {
+            log(LogType::Err, &err);
+        } else {
+            log(LogType::Info, &format!("Settings written to {}", config::config_path(path).to_string_lossy()));
+        }
+    }
+
     // adding metadata.json file to path
     let file_path = PathBuf::from(&config_path).join("metadata.json");
 
@@ -145,73 +397,280 @@ pub fn initialize_dir(path: &PathBuf, include_hidden: bool) {
 /// 
 /// # Rules followed:
 /// 1. A file in the src is checked for its existence in the dest by checking all of its stored metadata. If the file exists, then it is not copied.
-/// 2. If there is a file in src and dest with the same name, then the `modified_at` attribute of the files are checked. If they are equal then the file is not copied.
+/// 2. If there is a file in src and dest with the same name, then the modified times of the files are checked. If they are equal, within `mtime_window`, then the file is not copied.
 /// 3. If not, then the file from src is copied to dest with the filename `ebod-src-filename`. The user is prompted to change the file name at the end of the Backup process
 /// 
 // Backup the files in the src directory in to the dest directory
-pub fn backup(src: &PathBuf, dest: &PathBuf, dir :&str) -> Result<(), String> {
-    let src_path = src.join(PathBuf::from(".ebod/metadata.json"));
-    let dest_path = dest.join(PathBuf::from(".ebod/metadata.json"));
+#[deprecated(note = "use `Backup::new(src, dest).options(..).run(..)`, which returns a `BackupReport`")]
+pub fn backup(src: &Path, dest: &Path, dir :&str) -> Result<(), String> {
+    backup_with_config(src, dest, dir, &Filters::default(), &DirConfig::default()).map(|_| ())
+}
 
+/// Same as `backup`, but the entries left out by `filters` are not copied, and conflicts and verification are handled
+/// as set in `config`.
+/// 
+/// # Inputs
+/// * `src: &Path` -> `&Path` of the source directory
+/// * `dest: &Path` -> `&Path` of the destination directory
+/// * `dir: &str` -> The name (src/dest) used to rename the conflicting files
+/// * `filters: &Filters` -> The filters given with `--include`, `--exclude`, `--min-size`, etc.
+/// * `config: &DirConfig` -> The settings merged from `.ebod/config.toml` and the command line
+/// 
+/// # Output: `Result<BackupSummary, String>`
+/// The counts of what was done, or the error that stopped the backup.
+pub fn backup_with_config(src: &Path, dest: &Path, dir :&str, filters: &Filters, config: &DirConfig) -> Result<BackupSummary, String> {
+    backup_with_reporter(src, dest, dir, filters, config, &OutputReporter)
+}
+
+// Same as `backup_with_config`, with the events sent to `reporter`
+pub(crate) fn backup_with_reporter(src: &Path, dest: &Path, dir :&str, filters: &Filters, config: &DirConfig, reporter: &dyn Reporter) -> Result<BackupSummary, String> {
+    backup_in(&LocalFs, src, &LocalFs, dest, dir, filters, config, reporter)
+}
+
+/// Same as `backup_with_config`, with the source read from `src_vfs` and the destination written to `dest_vfs`. The
+/// metadata of both sides is read from their `.ebod/metadata.json`, so both must have been initialized.
+/// 
+/// # Inputs
+/// * `src_vfs: &S`, `src: &Path` -> The filesystem and the path of the source directory
+/// * `dest_vfs: &D`, `dest: &Path` -> The filesystem and the path of the destination directory
+/// * `dir: &str` -> The name (src/dest) used to rename the conflicting files
+/// * `filters: &Filters` -> The filters that leave entries out of the backup
+/// * `config: &DirConfig` -> The settings of the backup
+/// * `reporter: &dyn Reporter` -> Receives an event for every entry of the source
+/// 
+/// # Output: `Result<BackupSummary, String>`
+/// The counts of what was done, or the error that stopped the backup.
+#[allow(clippy::too_many_arguments)]
+pub fn backup_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, dir: &str, filters: &Filters, config: &DirConfig, reporter: &dyn Reporter) -> Result<BackupSummary, String> {
+    let src_meta = read_metadata_in(src_vfs, src).unwrap_or(vec![]);
+    let dest_meta = read_metadata_in(dest_vfs, dest).unwrap_or(vec![]);
+
+    execute_plan_in(src_vfs, src, dest_vfs, dest, plan_dirs(&src_meta, &dest_meta, dest_vfs, dest, dir, filters, config), config, reporter)
+}
+
+/// Executes a plan made by `plan_backup`, by copying the planned entries of `src` into `dest`.
+/// 
+/// # Inputs
+/// * `src: &Path` -> `&Path` of the source directory
+/// * `dest: &Path` -> `&Path` of the destination directory
+/// * `plan: Vec<PlannedEntry>` -> The plan of the backup
+/// * `config: &DirConfig` -> The settings used to verify the copies
+/// * `reporter: &dyn Reporter` -> Receives an event for every entry of the plan
+/// 
+/// # Output: `Result<BackupSummary, String>`
+/// The counts of what was done, or the error that stopped the backup.
+pub fn execute_plan(src: &Path, dest: &Path, plan: Vec<PlannedEntry>, config: &DirConfig, reporter: &dyn Reporter) -> Result<BackupSummary, String> {
+    execute_plan_in(&LocalFs, src, &LocalFs, dest, plan, config, reporter)
+}
+
+/// Same as `execute_plan`, with the files read from `src_vfs` and written to `dest_vfs`.
+pub fn execute_plan_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, plan: Vec<PlannedEntry>, config: &DirConfig, reporter: &dyn Reporter) -> Result<BackupSummary, String> {
     let mut redundant_files: Vec<FileEntry> = vec![];
     let mut copied_files_with_new_name: Vec<String> = vec![];
+    let mut summary = BackupSummary::default();
+    let preserve = config.preserve();
+    let mut attrs_warned = false;
+    let dest_fs = dest_vfs.local_path(dest).map(|path| config.dest_fs(&path)).unwrap_or_default();
+    let mut compat = Compat::load_in(src_vfs, src, dest_vfs, dest, dest_fs);
 
-    let src_meta = read_metadata(&src_path).unwrap_or(vec![]);
-    let dest_meta = read_metadata(&dest_path).unwrap_or(vec![]);
+    let to_copy: Vec<&PlannedEntry> = plan
+        .iter()
+        .filter(|p| matches!(p.action, Action::Copy | Action::CopyAs(_) | Action::Overwrite))
+        .collect();
+    let progress = Progress::new(to_copy.len() as u64, to_copy.iter().map(|p| p.entry.length).sum());
 
-    for file in src_meta {
-        if dest_meta.contains(&file) {
-            redundant_files.push(file.clone());
-            continue;
-        } else if file.e_type == EntryType::Dir && ! dest_meta.contains(&file) {
-            if let Err(_error) = create_dir_all(&dest.join(&file.name)) {
-                return Err(format!("Couldn't create directory {}", &file.name));
-            } else {
-                log(LogType::Ok, &format!("Created Directory: {} in destination", &file.name));
-            }
-        } else {
-            let idx = check_with_filename(&file.name, &dest_meta);
-            if idx != -1 {
-                if dest_meta.get(idx as usize).unwrap().modified_at != file.modified_at {
-                    let redundant_file_name = rename_redundant_files(&file.name, dir);
-                    if let Err(_error) = copy_file(&src.join(PathBuf::from(&file.name)), &dest.join(PathBuf::from(&redundant_file_name))) {
-                        return Err(format!("Error copying file: {}", &file.name));
-                    } else {
-                        log(LogType::Ok, &format!("Copied file: {} to destination", &file.name));
-                    }
-                    log(LogType::Info, &format!("{} found in destination is with varied modified time than {} in source. Hence it is copied under the name {}", file.name, dest_meta.get(idx as usize).unwrap().name, redundant_file_name));
-                    copied_files_with_new_name.push(redundant_file_name);
+    for planned in plan {
+        let file = planned.entry;
+        let src_file = src.join(file.stored_name());
+        let stored = planned.stored_as.as_deref().unwrap_or(&file.name);
+        match planned.action {
+            Action::Identical => {
+                summary.identical += 1;
+                reporter.report(&Event::Skipped { path: file.name.clone(), reason: String::from("identical") });
+                log(LogType::Debug, &format!("Unchanged: {}", file.name));
+                redundant_files.push(file);
+            },
+            Action::Excluded(reason) => {
+                summary.excluded += 1;
+                log(LogType::Debug, &format!("Excluded: {} ({})", file.name, reason));
+                reporter.report(&Event::Skipped { path: file.name.clone(), reason: reason.to_string() });
+            },
+            Action::KeepDest => {
+                summary.kept += 1;
+                reporter.report(&Event::Kept { path: file.name.clone() });
+                log(LogType::Info, &format!("{} found in destination is with varied modified time. The file in destination is kept", file.name));
+            },
+            Action::Overwrite => {
+                let target = Target { name: &file.name, stored, overwrite: true };
+                let copied = copy_entry_in(src_vfs, src, dest_vfs, dest, &file, target, config, &mut compat, &progress)?;
+                summary.overwritten += 1;
+                summary.bytes += copied.bytes;
+                summary.bytes_cloned += copied.cloned();
+                reporter.report(&Event::Overwritten { path: file.name.clone(), bytes: copied.bytes, method: Some(copied.method) });
+                log(LogType::Ok, &format!("Overwrote file: {} in destination", &file.name));
+                copy_attrs_in(src_vfs, &src_file, dest_vfs, &dest.join(stored), preserve, &mut attrs_warned);
+            },
+            Action::UpdateAttrs => {
+                if copy_attrs_in(src_vfs, &src_file, dest_vfs, &dest.join(stored), preserve, &mut attrs_warned) {
+                    summary.attrs_updated += 1;
+                    reporter.report(&Event::AttrsUpdated { path: file.name.clone() });
+                    log(LogType::Ok, &format!("Updated the attributes of {} in destination", &file.name));
+                }
+            },
+            Action::CreateDir => {
+                if let Err(error) = dest_vfs.create_dir_all(&dest.join(stored)) {
+                    return Err(copy_error(&format!("Couldn't create directory {}", &file.name), &error));
                 } else {
-                    redundant_files.push(file.clone());
+                    summary.dirs_created += 1;
+                    reporter.report(&Event::DirCreated { path: file.name.clone() });
+                    log(LogType::Ok, &format!("Created Directory: {} in destination", &file.name));
                 }
-                continue;
-            }
-            if let Err(_error) = copy_file(&src.join(PathBuf::from(&file.name)), &dest.join(PathBuf::from(&file.name))) {
-                return Err(format!("Error copying file: {}", &file.name));
-            } else {
+                if stored != file.name {
+                    compat.names.record(&file.name, &[stored.to_string()]);
+                }
+                copy_attrs_in(src_vfs, &src_file, dest_vfs, &dest.join(stored), preserve, &mut attrs_warned);
+            },
+            Action::CopyAs(redundant_file_name) => {
+                let stored = planned.stored_as.as_deref().unwrap_or(&redundant_file_name);
+                let target = Target { name: &redundant_file_name, stored, overwrite: false };
+                let copied = copy_entry_in(src_vfs, src, dest_vfs, dest, &file, target, config, &mut compat, &progress)?;
+                summary.renamed += 1;
+                summary.bytes += copied.bytes;
+                summary.bytes_cloned += copied.cloned();
+                reporter.report(&Event::Renamed { path: file.name.clone(), to: redundant_file_name.clone(), bytes: copied.bytes, method: Some(copied.method) });
                 log(LogType::Ok, &format!("Copied file: {} to destination", &file.name));
-            }
+                copy_attrs_in(src_vfs, &src_file, dest_vfs, &dest.join(stored), preserve, &mut attrs_warned);
+                log(LogType::Info, &format!("{} found in destination is with varied modified time than {} in source. Hence it is copied under the name {}", file.name, file.name, redundant_file_name));
+                copied_files_with_new_name.push(redundant_file_name);
+            },
+            Action::Copy => {
+                let target = Target { name: &file.name, stored, overwrite: false };
+                let copied = copy_entry_in(src_vfs, src, dest_vfs, dest, &file, target, config, &mut compat, &progress)?;
+                summary.copied += 1;
+                summary.bytes += copied.bytes;
+                summary.bytes_cloned += copied.cloned();
+                reporter.report(&Event::Copied { path: file.name.clone(), bytes: copied.bytes, method: Some(copied.method) });
+                log(LogType::Ok, &format!("Copied file: {} to destination", &file.name));
+                copy_attrs_in(src_vfs, &src_file, dest_vfs, &dest.join(stored), preserve, &mut attrs_warned);
+            },
         }
     }
 
-    if redundant_files.len() > 0 {
+    progress.finish();
+
+    if let Err(err) = compat.names.save_in(dest_vfs, dest) {
+        log(LogType::Err, &err);
+    }
+
+    if summary.bytes_cloned > 0 {
+        log(LogType::Info, &format!("{} of the {} bytes copied were cloned with reflinks", summary.bytes_cloned, summary.bytes));
+    }
+
+    if !redundant_files.is_empty() {
         log(LogType::Info, "Files that were present in both source and destination and hence were not copied:");
         for file in redundant_files {
-            println!("\t{}", file.name.yellow());
+            output::detail(&format!("\t{}", file.name.yellow()));
         }
     }
 
-    if copied_files_with_new_name.len() > 0 {
+    if !copied_files_with_new_name.is_empty() {
         log(LogType::Info, "Files that were present in both source and destination and hence were copied with new name:");
-        println!("{}", "  -- Please change the names of the below files ASAP -- ".on_red().bold());
+        output::detail(&"  -- Please change the names of the below files ASAP -- ".on_red().bold().to_string());
         for file in copied_files_with_new_name {
-            println!("\t{}", file.yellow());
+            output::detail(&format!("\t{}", file.yellow()));
         }
     }
 
-    Ok(())
+    Ok(summary)
 }
 
+// Where an entry of the plan is copied in the destination
+struct Target<'a> {
+    // The name of the copy: the name of the entry, or the new name of a conflicting file
+    name: &'a str,
+    // The path under which the copy is stored, which differs from `name` on FAT destinations
+    stored: &'a str,
+    // Whether the copy replaces an older copy
+    overwrite: bool,
+}
+
+// Copies the file `file` of `src` to `target` in `dest`, verifies the copy and gives it the modified time of the
+// source. The files too large for the destination are split into parts, and the files split in `src` are joined.
+#[allow(clippy::too_many_arguments)]
+fn copy_entry_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, file: &FileEntry, target: Target, config: &DirConfig, compat: &mut Compat, progress: &Progress) -> Result<Copied, String> {
+    let sources = compat.sources(src, file);
+    let part_size = compat.part_size(file);
+    let old_parts = compat.names.parts(target.name);
+    let dest_file = dest.join(target.stored);
+
+    let (copied, written) = if sources.len() == 1 && part_size.is_none() && old_parts.is_empty() {
+        let copied = if target.overwrite {
+            update_with_progress_in(src_vfs, &sources[0], dest_vfs, &dest_file, file, progress)?
+        } else {
+            copy_with_progress_in(src_vfs, &sources[0], dest_vfs, &dest_file, file, progress)?
+        };
+        (copied, vec![dest_file])
+    } else {
+        for part in &old_parts {
+            let _ = dest_vfs.remove(&dest.join(part));
+        }
+        if target.overwrite && old_parts.is_empty() {
+            let _ = dest_vfs.remove(&dest_file);
+        }
+        let bar = progress.file_bar(&file.name, file.length);
+        let result = fat::copy_parts_in(src_vfs, &sources, dest_vfs, &dest_file, part_size, |bytes| {
+            bar.inc(bytes);
+            progress.add_bytes(bytes);
+        });
+        bar.finish_and_clear();
+        let (bytes, written) = result.map_err(|err| copy_error(&format!("Error in copying file {}", file.name), &err))?;
+        log(LogType::Debug, &format!("Copied {} from {} into {} files: {} bytes", file.name, sources.len(), written.len(), bytes));
+        progress.file_done();
+        (Copied { bytes, method: CopyMethod::Userspace }, written)
+    };
+
+    for path in &written {
+        let _ = dest_vfs.set_times(path, file.modified());
+    }
+    checksum::verify_parts_in(src_vfs, &sources, dest_vfs, &written, config.verify, config.hash)?;
+    let stored: Vec<String> = written
+        .iter()
+        .filter_map(|path| path.strip_prefix(dest).ok())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    compat.names.record(target.name, &stored);
+    Ok(copied)
+}
+
+// The message of a failed copy, with a hint on its cause when there is one
+fn copy_error(msg: &str, err: &std::io::Error) -> String {
+    match fat::explain(err) {
+        Some(hint) => format!("{}: {} ({})", msg, err, hint),
+        None => format!("{}: {}", msg, err),
+    }
+}
+
+// Copies the extended attributes and ACLs selected by `preserve` from `src` to `dest`. Failures are only warnings, and
+// a filesystem without extended attributes is only reported once per backup. Returns whether the copy succeeded.
+fn copy_attrs_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, preserve: Preserve, warned: &mut bool) -> bool {
+    if !preserve.any() {
+        return true;
+    }
+    match xattrs::copy(src_vfs, src, dest_vfs, dest, preserve) {
+        Ok(()) => true,
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            if !*warned {
+                log(LogType::Info, &format!("Extended attributes and ACLs aren't supported where {} is stored, so they aren't copied", dest.to_string_lossy()));
+                *warned = true;
+            }
+            false
+        },
+        Err(err) => {
+            log(LogType::Info, &format!("Couldn't copy the attributes of {}: {}", src.to_string_lossy(), err));
+            false
+        },
+    }
+}
 
 /// Checks if the filename in src already exists in the dest directory
 /// 
@@ -220,14 +679,23 @@ pub fn backup(src: &PathBuf, dest: &PathBuf, dir :&str) -> Result<(), String> {
 /// * `dest_meta: Vec<FileEntry>` -> A `Vec<FileEntry>` that contains the metatdata of the dest directory
 /// 
 /// # Output: `i16`
-/// The index of the file in the destination metadata as a `i16` 
-pub fn check_with_filename(file: &String, dest_meta: &Vec<FileEntry>) -> i16 {
-    for (index, entry) in dest_meta.iter().enumerate() {
-        if entry.name == *file {
-            return index as i16;
-        }
-    }
-    -1
+/// The index of the file in the destination metadata as a `i16`, or -1 if it isn't there or its index doesn't fit
+#[deprecated(note = "use `find_by_name`, which returns an `Option<usize>` that doesn't overflow past 32767 entries")]
+#[allow(clippy::ptr_arg)]
+pub fn check_with_filename(file: &String, dest_meta: &[FileEntry]) -> i16 {
+    find_by_name(file, dest_meta).and_then(|index| i16::try_from(index).ok()).unwrap_or(-1)
+}
+
+/// Finds the entry named `file` in the metadata of a directory.
+/// 
+/// # Inputs
+/// * `file: &str` -> The file name
+/// * `dest_meta: &[FileEntry]` -> The metatdata of the dest directory
+/// 
+/// # Output: `Option<usize>`
+/// The index of the file in the destination metadata, if it is there
+pub fn find_by_name(file: &str, dest_meta: &[FileEntry]) -> Option<usize> {
+    dest_meta.iter().position(|entry| entry.name == file)
 }
 
 fn _rename_redundant_files_at_end(file: &str) -> String {
@@ -261,10 +729,10 @@ fn _rename_redundant_files_at_end(file: &str) -> String {
 pub fn rename_redundant_files(file: &str, dir: &str) -> String {
     if let Some(file_name) = PathBuf::from(file).file_name() {
         let old_file_name = String::from(file_name.to_str().unwrap_or("default"));
-        return format!("ebod-{}-{}", dir, old_file_name);
+        format!("ebod-{}-{}", dir, old_file_name)
     } else {
         log(LogType::Err, &format!("Couldn't resolve the file name of {}. Stored as ebod-src-default", file));
-        return String::from("ebod-src-default");
+        String::from("ebod-src-default")
     }
 }
 
@@ -280,7 +748,98 @@ pub fn copy_file(src: &PathBuf, dest: &PathBuf) -> Result<u64, String> {
     if let Ok(_success) = fs::copy(src, dest) {
         Ok(_success)
     } else {
-        Err(String::from(format!("Error in copying file {}", src.to_str().unwrap())))
+        Err(format!("Error in copying file {}", src.to_str().unwrap()))
+    }
+}
+
+/// Copies a file and reports the copied bytes to `progress`. Large files are copied in chunks, so that their own
+/// progress bar moves while they are being copied.
+/// 
+/// # Inputs
+/// * `src: &Path` -> `&Path` of the source file
+/// * `dest: &Path` -> `&Path` of the destination file
+/// * `file: &FileEntry` -> The metadata of the source file
+/// * `progress: &Progress` -> The progress display of the backup
+/// 
+/// # Output: `Result<Copied, String>`
+/// The number of bytes copied and the method used, or an error message.
+pub fn copy_with_progress(src: &Path, dest: &Path, file: &FileEntry, progress: &Progress) -> Result<Copied, String> {
+    copy_with_progress_in(&LocalFs, src, &LocalFs, dest, file, progress)
+}
+
+/// Same as `copy_with_progress`, with the file read from `src_vfs` and written to `dest_vfs`. When both sides are on
+/// the local filesystem, the file is copied with `reflink::copy`: with a reflink if the filesystem can, keeping the
+/// holes of sparse files, or with `copy_file_range`.
+pub fn copy_with_progress_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, file: &FileEntry, progress: &Progress) -> Result<Copied, String> {
+    log(LogType::Trace, &format!("Copying {} to {} ({} bytes)", src.to_string_lossy(), dest.to_string_lossy(), file.length));
+    let bar = progress.file_bar(&file.name, file.length);
+    let on_copied = |bytes| {
+        bar.inc(bytes);
+        progress.add_bytes(bytes);
+    };
+    let result = match src_vfs.local_path(src).zip(dest_vfs.local_path(dest)) {
+        Some((local_src, local_dest)) => reflink::copy(&local_src, &local_dest, file.allocated.is_some(), on_copied),
+        None => stream_copy_in(src_vfs, src, dest_vfs, dest, on_copied),
+    };
+    bar.finish_and_clear();
+    let copied = result.map_err(|err| copy_error(&format!("Error in copying file {}", src.to_string_lossy()), &err))?;
+    log(LogType::Debug, &format!("Copied {} ({}): {} of {} bytes", file.name, copied.method, copied.bytes, file.length));
+    // Like fs::copy, the permissions of the source are kept
+    if let Ok(stat) = src_vfs.stat(src) {
+        let _ = dest_vfs.set_mode(dest, stat.mode);
+    }
+    progress.file_done();
+    Ok(copied)
+}
+
+// Copies `src` into `dest` through the `Vfs` streams, for the backends that aren't local
+fn stream_copy_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, mut on_copied: impl FnMut(u64)) -> std::io::Result<Copied> {
+    let mut input = src_vfs.open(src)?;
+    let mut output = dest_vfs.create(dest)?;
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut bytes = 0;
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        output.write_all(&buffer[..read])?;
+        bytes += read as u64;
+        on_copied(read as u64);
+    }
+    output.flush()?;
+    Ok(Copied { bytes, method: CopyMethod::Userspace })
+}
+
+/// Same as `copy_with_progress_in`, for a file that overwrites an older copy in `dest`. Files of at least
+/// `delta::MIN_DELTA_SIZE` bytes are updated with a delta, so that only the blocks that changed are sent to `dest`.
+/// The file is copied in full if both sides are local, where a full copy is cheaper, or if the backend of `dest` can't
+/// copy ranges of the old copy. Sparse files are copied too, since a delta would write their holes.
+pub fn update_with_progress_in<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, file: &FileEntry, progress: &Progress) -> Result<Copied, String> {
+    let local = src_vfs.local_path(src).is_some() && dest_vfs.local_path(dest).is_some();
+    if local || file.length < delta::MIN_DELTA_SIZE || file.allocated.is_some() {
+        return copy_with_progress_in(src_vfs, src, dest_vfs, dest, file, progress);
+    }
+    let bar = progress.file_bar(&file.name, file.length);
+    let mut read = 0;
+    let result = delta::update_in(src_vfs, src, dest_vfs, dest, |bytes| {
+        read += bytes;
+        bar.inc(bytes);
+        progress.add_bytes(bytes);
+    });
+    bar.finish_and_clear();
+    match result {
+        Ok(stats) => {
+            log(LogType::Debug, &format!("Delta update of {}: {} bytes copied from the old copy, {} bytes sent", file.name, stats.matched, stats.literal));
+            if let Ok(stat) = src_vfs.stat(src) {
+                let _ = dest_vfs.set_mode(dest, stat.mode);
+            }
+            progress.file_done();
+            Ok(Copied { bytes: read, method: CopyMethod::Delta })
+        },
+        // Nothing was read or written yet
+        Err(err) if err.kind() == ErrorKind::Unsupported => copy_with_progress_in(src_vfs, src, dest_vfs, dest, file, progress),
+        Err(err) => Err(copy_error(&format!("Error in updating file {}", dest.to_string_lossy()), &err)),
     }
 }
 
@@ -303,39 +862,151 @@ pub fn read_metadata(path: &PathBuf) -> Result<Vec<FileEntry>, String> {
     }
 } 
 
+/// Reads the metadata stored in `<root>/.ebod/metadata.json` of `vfs`.
+/// 
+/// # Output: `Result<Vec<FileEntry>, String>`
+/// The stored metadata, or an error message if it can't be read or parsed.
+pub fn read_metadata_in<V: Vfs + ?Sized>(vfs: &V, root: &Path) -> Result<Vec<FileEntry>, String> {
+    let content = vfs.read(&root.join(".ebod/metadata.json")).map_err(|_| String::from("Error in reading from file"))?;
+    serde_json::from_slice(&content).map_err(|_| String::from("Error in parsing JSON data"))
+}
+
+/// Writes `data` into `<root>/.ebod/metadata.json` of `vfs`, creating the `.ebod` directory if needed.
+pub fn write_metadata_in<V: Vfs + ?Sized>(vfs: &V, root: &Path, data: &[FileEntry]) -> Result<(), String> {
+    let config_path = root.join(".ebod");
+    vfs.create_dir_all(&config_path).map_err(|_| format!("Couldn't create {}", config_path.to_string_lossy()))?;
+    let content = serde_json::to_string_pretty(data).map_err(|_| String::from("Error during serializing the metadata"))?;
+    let path = config_path.join("metadata.json");
+    vfs.write(&path, content.as_bytes()).map_err(|_| format!("Error in writing {}", path.to_string_lossy()))
+}
+
 /// A function to traverse the directories and files recursively and store their metadata.
 /// 
+/// Entries matched by a `.ebodignore` file or by the `ignore` setting of `.ebod/config.toml` are skipped. Ignored
+/// directories are not walked into at all.
+/// 
 /// # Input
-/// * `path: &PathBuf` -> `&PathBuf` of the directory whose metadata is required
-/// * `og_path: &PathBuf` -> Same `&PathBuf` as `path`. Used to prefix the directory name in each file in metadata
+/// * `path: &Path` -> `&Path` of the directory whose metadata is required
+/// * `og_path: &Path` -> Same `&Path` as `path`. Used to prefix the directory name in each file in metadata
 /// * `data: &mut Vec<FileEntry>` -> `Vec<FileEntry>` which is the buffer in which the data is recorded.
 /// * `include_hidden: bool` -> `bool` flag to represent the inclusion of hidden files
-pub fn recursive_listing(path: &PathBuf, og_path: &PathBuf, data: &mut Vec<FileEntry>, include_hidden: bool) {
-    if let Ok(read_dir) = fs::read_dir(&path) {
-        for entry in read_dir {
-            if let Ok(file) = entry {
-                if !include_hidden && file.file_name().into_string().unwrap_or("default".into()).starts_with(".") {
-                    continue;
-                }
-                if let Ok(meta) = fs::metadata(file.path()) {
-                    data.push(FileEntry {
-                        name: file.path().strip_prefix(og_path).unwrap_or(file.path().as_path()).to_str().unwrap_or("default").to_string(),
-                        modified_at: if let Ok(mod_time) = meta.modified() {
-                            mod_time.duration_since(UNIX_EPOCH).expect("Error with SystemTime").as_secs()
-                        } else {
-                            0
-                        },
-                        length: meta.len(),
-                        e_type: if meta.is_dir() {EntryType::Dir} else {EntryType::File},
-                        #[cfg(target_os = "linux")]
-                        inode: meta.ino(),
-                        #[cfg(target_os = "windows")]
-                        file_attr: meta.file_attributes()
-                    });
-                    if meta.is_dir() {
-                        recursive_listing(&file.path(), og_path, data, include_hidden);
-                    }
-                }
+pub fn recursive_listing(path: &Path, og_path: &Path, data: &mut Vec<FileEntry>, include_hidden: bool) {
+    recursive_listing_with_filters(path, og_path, data, include_hidden, &Filters::default());
+}
+
+/// Same as `recursive_listing`, but the entries left out by `filters` are not recorded. Directories left out by
+/// `--exclude` are not walked into.
+/// 
+/// # Input
+/// * `path: &Path` -> `&Path` of the directory whose metadata is required
+/// * `og_path: &Path` -> Same `&Path` as `path`. Used to prefix the directory name in each file in metadata
+/// * `data: &mut Vec<FileEntry>` -> `Vec<FileEntry>` which is the buffer in which the data is recorded.
+/// * `include_hidden: bool` -> `bool` flag to represent the inclusion of hidden files
+/// * `filters: &Filters` -> The filters given with `--include`, `--exclude`, `--min-size`, etc.
+pub fn recursive_listing_with_filters(path: &Path, og_path: &Path, data: &mut Vec<FileEntry>, include_hidden: bool, filters: &Filters) {
+    recursive_listing_in(&LocalFs, path, og_path, data, include_hidden, Preserve::default(), filters);
+}
+
+/// Same as `recursive_listing_with_filters`, with the directory read from `vfs`. The `.ebodignore` files and the
+/// `ignore` setting are read from `vfs` too. The entries that a backup stored under another name in a FAT or exFAT
+/// directory get back their original name. The hashes of the attributes selected by `preserve` are recorded.
+pub fn recursive_listing_in<V: Vfs + ?Sized>(vfs: &V, path: &Path, og_path: &Path, data: &mut Vec<FileEntry>, include_hidden: bool, preserve: Preserve, filters: &Filters) {
+    let mut rules = ignore_rules_in(vfs, path, og_path);
+    let mut listing = vec![];
+    listing_with_rules(vfs, path, og_path, &mut listing, include_hidden, preserve, filters, &mut rules);
+    data.extend(NameMap::load_in(vfs, og_path).restore(listing));
+}
+
+// Loads the `ignore` setting of `og_path` and the .ebodignore files from `og_path` down to the directory `path`
+pub(crate) fn ignore_rules_for(path: &Path, og_path: &Path) -> IgnoreRules {
+    ignore_rules_in(&LocalFs, path, og_path)
+}
+
+fn ignore_rules_in<V: Vfs + ?Sized>(vfs: &V, path: &Path, og_path: &Path) -> IgnoreRules {
+    let mut rules = IgnoreRules::new();
+    match DirConfig::load_in(vfs, og_path) {
+        Ok(config) if !config.ignore.is_empty() => rules.add_patterns(og_path, &config.ignore),
+        Ok(_) => {},
+        Err(err) => log(LogType::Err, &err),
+    }
+    let mut dir = og_path.to_path_buf();
+    rules.enter_in(vfs, &dir);
+    for component in path.strip_prefix(og_path).unwrap_or(Path::new("")).components() {
+        dir.push(component);
+        rules.enter_in(vfs, &dir);
+    }
+    rules
+}
+
+/// Builds the `FileEntry` of `path`, named relative to `og_path`.
+/// 
+/// # Input
+/// * `path: &Path` -> `&Path` of the file or directory
+/// * `og_path: &Path` -> `&Path` of the root directory
+/// * `meta: &fs::Metadata` -> The metadata of `path`
+/// * `preserve: Preserve` -> The attributes whose hashes are recorded
+pub fn file_entry(path: &Path, og_path: &Path, meta: &fs::Metadata, preserve: Preserve) -> FileEntry {
+    let mut entry = entry_from_stat(path, og_path, &Stat::from(meta));
+    set_attr_hashes(&mut entry, &LocalFs, path, preserve);
+    entry
+}
+
+// Records the hashes of the attributes of `path` selected by `preserve`. Reading the attributes costs a few system
+// calls per entry, so nothing is read when none are preserved
+fn set_attr_hashes<V: Vfs + ?Sized>(entry: &mut FileEntry, vfs: &V, path: &Path, preserve: Preserve) {
+    if !preserve.any() {
+        return;
+    }
+    let (xattrs, acls) = xattrs::hashes(vfs, path);
+    entry.xattrs = xattrs.filter(|_| preserve.xattrs);
+    entry.acls = acls.filter(|_| preserve.acls);
+}
+
+/// Same as `file_entry`, from the metadata returned by a `Vfs`. The hashes of the extended attributes are left empty.
+pub fn entry_from_stat(path: &Path, og_path: &Path, stat: &Stat) -> FileEntry {
+    let since_epoch = stat.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    FileEntry {
+        name: path.strip_prefix(og_path).unwrap_or(path).to_str().unwrap_or("default").to_string(),
+        modified_at: since_epoch.as_secs(),
+        modified_nanos: (since_epoch.subsec_nanos() > 0).then_some(since_epoch.subsec_nanos()),
+        length: stat.length,
+        allocated: (stat.kind == FileKind::File && stat.allocated < stat.length).then_some(stat.allocated),
+        e_type: if stat.is_dir() {EntryType::Dir} else {EntryType::File},
+        #[cfg(target_os = "linux")]
+        inode: stat.inode,
+        #[cfg(target_os = "windows")]
+        file_attr: stat.file_attr,
+        xattrs: None,
+        acls: None,
+        stored_as: None,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn listing_with_rules<V: Vfs + ?Sized>(vfs: &V, path: &Path, og_path: &Path, data: &mut Vec<FileEntry>, include_hidden: bool, preserve: Preserve, filters: &Filters, rules: &mut IgnoreRules) {
+    if let Ok(read_dir) = vfs.read_dir(path) {
+        for file in read_dir {
+            if !include_hidden && file.name.starts_with(".") {
+                continue;
+            }
+            // The identity file of a drive belongs to the drive, not to the backed up data
+            if path == og_path && file.name == drive::DRIVE_ID_FILE {
+                continue;
+            }
+            let file_path = path.join(&file.name);
+            if rules.is_ignored(&file_path, file.stat.is_dir()) {
+                continue;
+            }
+            let mut entry = entry_from_stat(&file_path, og_path, &file.stat);
+            set_attr_hashes(&mut entry, vfs, &file_path, preserve);
+            if filters.check(&entry).is_err() {
+                continue;
+            }
+            data.push(entry);
+            if file.stat.is_dir() {
+                rules.enter_in(vfs, &file_path);
+                listing_with_rules(vfs, &file_path, og_path, data, include_hidden, preserve, filters, rules);
+                rules.leave();
             }
         }
     }
@@ -350,25 +1021,60 @@ pub fn recursive_listing(path: &PathBuf, og_path: &PathBuf, data: &mut Vec<FileE
 /// * `src: &PathBuf` -> The `PathBuf` to the source directory
 /// * `dest: &PathBuf` -> The `PathBuf` to the destination directory
 /// * `include_hidden: bool` -> The boolean flag to represent inclusion of hidden files for synchronization process.
-pub fn sync_dirs(src: &PathBuf, dest: &PathBuf, include_hidden: bool) {
-    if let Ok(_suc) = backup(&src, &dest, "src") {
-        log(LogType::Ok, &format!("{} was backed up into {}", src.to_string_lossy(), dest.to_string_lossy()));
-        initialize_dir(&dest, include_hidden);
-        if let Ok(_success) = backup(&dest, &src, "dest") {
+#[deprecated(note = "use `TwoWaySync::new(src, dest).options(..).run(..)`, which returns a `BackupReport`")]
+pub fn sync_dirs(src: &Path, dest: &Path, include_hidden: bool) {
+    let config = DirConfig { include_hidden, ..DirConfig::default() };
+    let _ = sync_dirs_with_config(src, dest, &Filters::default(), &config);
+}
+
+/// Same as `sync_dirs`, but the entries left out by `filters` are not copied in either direction, and the settings in
+/// `config` are used for both directions.
+/// 
+/// # Input
+/// * `src: &Path` -> The `Path` to the source directory
+/// * `dest: &Path` -> The `Path` to the destination directory
+/// * `filters: &Filters` -> The filters given with `--include`, `--exclude`, `--min-size`, etc.
+/// * `config: &DirConfig` -> The settings merged from `.ebod/config.toml` and the command line
+/// 
+/// # Output: `Result<BackupSummary, String>`
+/// The combined counts of both directions, or an error message if either direction failed.
+pub fn sync_dirs_with_config(src: &Path, dest: &Path, filters: &Filters, config: &DirConfig) -> Result<BackupSummary, String> {
+    sync_with_reporter(src, dest, filters, config, true, &OutputReporter)
+}
+
+// Same as `sync_dirs_with_config`, with the events sent to `reporter`. The `ebod-src-` copies are only deleted from
+// `src` if `remove_copies` is set.
+pub(crate) fn sync_with_reporter(src: &Path, dest: &Path, filters: &Filters, config: &DirConfig, remove_copies: bool, reporter: &dyn Reporter) -> Result<BackupSummary, String> {
+    let include_hidden = config.include_hidden;
+    let mut summary = backup_with_reporter(src, dest, Side::Src.as_str(), filters, config, reporter).map_err(|err| {
+        let msg = format!("There was an error in backing up {} into {}: {}", src.to_string_lossy(), dest.to_string_lossy(), err);
+        log(LogType::Err, &msg);
+        msg
+    })?;
+    let mut result = Ok(());
+    log(LogType::Ok, &format!("{} was backed up into {}", src.to_string_lossy(), dest.to_string_lossy()));
+    initialize_dir_with_config(dest, &Filters::default(), config);
+    match backup_with_reporter(dest, src, Side::Dest.as_str(), filters, config, reporter) {
+        Ok(reverse) => {
+            summary.add(&reverse);
             log(LogType::Ok, &format!("{} was backed up into {}", dest.to_string_lossy(), src.to_string_lossy()));
-        } else {
-            log(LogType::Err, &format!("There was an error in backing up {} into {} dirctory", dest.to_string_lossy(), src.to_string_lossy()));
         }
+        Err(err) => {
+            let msg = format!("There was an error in backing up {} into {}: {}", dest.to_string_lossy(), src.to_string_lossy(), err);
+            log(LogType::Err, &msg);
+            result = Err(msg);
+        }
+    }
+    if remove_copies {
         let mut src_meta = vec![];
-        recursive_listing(&src, &src, &mut src_meta, include_hidden);
-        if let Ok(_suc) = delete_copies_in_dir(&src, &src_meta) {
+        recursive_listing(src, src, &mut src_meta, include_hidden);
+        if let Ok(_suc) = delete_copies_in_dir(src, &src_meta) {
             log(LogType::Ok, &format!("Duplicate files in {} have been successfully deleted.", src.to_string_lossy()));
         } else {
             log(LogType::Err, &format!("Error in deleting duplicate files in the {} directory.", src.to_string_lossy()));
         }
-    } else {
-        log(LogType::Err, &format!("There was an error in backing up {} into {} dirctory", src.to_string_lossy(), dest.to_string_lossy()));
     }
+    result.map(|_| summary)
 }
 
 /// The function deletes duplicates files in the directory passed as input. Useful to clean-up duplicate files after syncing two directories.
@@ -376,14 +1082,14 @@ pub fn sync_dirs(src: &PathBuf, dest: &PathBuf, include_hidden: bool) {
 /// # Input
 /// * `src: &PathBuf` -> The `PathBuf` to the directory in which the duplicates must be deleted.
 /// * `src_meta: &Vec<FileEntry>` -> The metadata that we get from calling the function `initialize_dir()`
-pub fn delete_copies_in_dir(src: &PathBuf, src_meta: &Vec<FileEntry>) -> Result<(), String> {
+pub fn delete_copies_in_dir(src: &Path, src_meta: &[FileEntry]) -> Result<(), String> {
     let mut error: bool = false;
     for file in src_meta {
         let file_pathbuf = PathBuf::from(&file.name);
         if let Some(file_name_os) = file_pathbuf.file_name() {
             if let Some(file_name) = file_name_os.to_str() {
                 if file_name.starts_with("ebod-src-") {
-                    match remove_file(&src.join(PathBuf::from(&file.name))) {
+                    match remove_file(src.join(PathBuf::from(&file.name))) {
                         Ok(()) => log(LogType::Ok, &format!("File removed successfully: {}", &file.name)),
                         Err(e) => {
                             match e.kind() {
@@ -410,9 +1116,65 @@ pub fn delete_copies_in_dir(src: &PathBuf, src_meta: &Vec<FileEntry>) -> Result<
     Ok(())
 }
 
-pub fn check_dir_existence(dir: &PathBuf) {
+pub fn check_dir_existence(dir: &Path) {
     if !dir.exists() {
         log(LogType::Err, &format!("The directory {} doesn't exist!!", dir.to_string_lossy()));
-        exit(0);
+        output::exit(0);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    fn entry(modified_at: u64, modified_nanos: Option<u32>) -> FileEntry {
+        FileEntry {
+            name: String::from("a.txt"),
+            modified_at,
+            modified_nanos,
+            length: 1,
+            allocated: None,
+            e_type: EntryType::File,
+            #[cfg(target_os = "linux")]
+            inode: 0,
+            #[cfg(target_os = "windows")]
+            file_attr: 0,
+            xattrs: None,
+            acls: None,
+            stored_as: None,
+        }
+    }
+
+    #[test]
+    fn mtimes_are_compared_to_the_nanosecond_when_both_sides_have_them() {
+        let precise = entry(100, Some(250_000_000));
+        assert_eq!(precise.mtime_diff(&entry(100, Some(750_000_000))), Duration::from_millis(500));
+        assert_eq!(entry(101, Some(100)).mtime_diff(&precise), Duration::new(0, 750_000_100));
+        assert!(precise.mtime_diff(&precise).is_zero());
+
+        // Whole seconds are compared when either side lacks the nanoseconds
+        assert!(precise.mtime_diff(&entry(100, None)).is_zero());
+        assert!(entry(100, None).mtime_diff(&precise).is_zero());
+        assert_eq!(entry(98, None).mtime_diff(&precise), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn old_metadata_without_nanoseconds_is_read() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("dir/.ebod")).unwrap();
+        #[cfg(target_os = "linux")]
+        let old = r#"[{"name": "a.txt", "modified_at": 100, "length": 1, "e_type": "File", "inode": 0}]"#;
+        #[cfg(target_os = "windows")]
+        let old = r#"[{"name": "a.txt", "modified_at": 100, "length": 1, "e_type": "File", "file_attr": 0}]"#;
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        let old = r#"[{"name": "a.txt", "modified_at": 100, "length": 1, "e_type": "File"}]"#;
+        fs.write(Path::new("dir/.ebod/metadata.json"), old.as_bytes()).unwrap();
+
+        let data = read_metadata_in(&fs, Path::new("dir")).unwrap();
+        assert_eq!(data, [entry(100, None)]);
+        assert_eq!(data[0].modified(), UNIX_EPOCH + Duration::from_secs(100));
+        // Written back, the entry still has no nanoseconds
+        write_metadata_in(&fs, Path::new("dir"), &data).unwrap();
+        assert!(!String::from_utf8(fs.read(Path::new("dir/.ebod/metadata.json")).unwrap()).unwrap().contains("modified_nanos"));
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,16 @@
-use std::path::PathBuf;
-use clap::{Parser, Subcommand};
+use std::{path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 
 // Importing from lib.rs
-use ebod::{LogType, backup, check_dir_existence, initialize_dir, log, sync_dirs};
+use ebod::{Backup, BackupOptions, LogType, archive, check_dir_existence, crypto, daemon, drive, schedule, initialize_dir_with_config, initialize_dir_with_filters, log, SyncOptions, TwoWaySync, watch, zip_archive};
+use ebod::checksum::{HashAlgorithm, VerifyMode};
+use ebod::config::{CONFIG_KEYS, ConflictPolicy, DirConfig, MtimeWindow};
+use ebod::fat::FsCompat;
+use ebod::filter::{Filters, parse_size};
+use ebod::plan::{plan_local, print_plan};
+use ebod::output::{self, OutputFormat, OutputReporter, Verbosity};
+use ebod::remote::{self, Remote};
+use ebod::profile::{ProfileState, Profiles, default_profiles_path, default_state_dir, print_summary_table, run_profile};
 
 
 #[derive(Parser, Debug)]
@@ -14,9 +22,146 @@ use ebod::{LogType, backup, check_dir_existence, initialize_dir, log, sync_dirs}
     help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
     author = "Sivaprakash P"
 )]
-struct CLI {
+struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    #[arg(short='q', long="quiet", global=true, conflicts_with="verbose", help="Only displays the errors")]
+    quiet: bool,
+    #[arg(short='v', long="verbose", global=true, action=ArgAction::Count, help="Displays more details. Can be given twice (-vv)")]
+    verbose: u8,
+    #[arg(long="log-file", global=true, value_name="FILE", help="Appends a complete timestamped log of the run to FILE")]
+    log_file: Option<PathBuf>,
+    #[arg(long="output", global=true, value_enum, default_value_t=OutputFormat::Text, help="Prints structured records to stdout (json, or ndjson for streaming). The log is then written to stderr")]
+    output: OutputFormat,
+}
+
+/// The filters shared by `init`, `backup`, `sync` and `diff`
+#[derive(Args, Debug)]
+struct FilterArgs {
+    #[arg(long="include", value_name="GLOB", help="Only includes the files matching the glob pattern. Can be given more than once")]
+    include: Vec<String>,
+    #[arg(long="exclude", value_name="GLOB", help="Excludes the files and directories matching the glob pattern. Can be given more than once")]
+    exclude: Vec<String>,
+    #[arg(long="min-size", value_name="SIZE", value_parser=parse_size, help="Excludes the files smaller than SIZE (e.g. 10K, 5M, 1G)")]
+    min_size: Option<u64>,
+    #[arg(long="max-size", value_name="SIZE", value_parser=parse_size, help="Excludes the files larger than SIZE (e.g. 10K, 5M, 1G)")]
+    max_size: Option<u64>,
+    #[arg(long="newer-than", value_name="AGE", help="Only includes the files modified within AGE (e.g. 12h, 7d, 2w)")]
+    newer_than: Option<String>,
+    #[arg(long="older-than", value_name="AGE", help="Only includes the files not modified within AGE (e.g. 12h, 7d, 2w)")]
+    older_than: Option<String>,
+}
+
+/// `-a` and `--no-include-hidden`, which override `include_hidden` of `.ebod/config.toml` when given
+#[derive(Args, Debug)]
+struct HiddenArgs {
+    #[arg(short='a', long="include-hidden", overrides_with="no_include_hidden", help="Includes the hidden files and directories")]
+    include_hidden: bool,
+    #[arg(long="no-include-hidden", help="Leaves out the hidden files and directories, even if include_hidden is set in .ebod/config.toml")]
+    no_include_hidden: bool,
+}
+
+impl HiddenArgs {
+    // The value given on the command line, if any
+    fn value(&self) -> Option<bool> {
+        flag(self.include_hidden, self.no_include_hidden)
+    }
+}
+
+// The value of a pair of flags `--x` and `--no-x`. The last one given wins, and `None` means that neither was given
+fn flag(yes: bool, no: bool) -> Option<bool> {
+    match (yes, no) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// The settings of `backup` and `sync` that override the ones in `.ebod/config.toml`
+#[derive(Args, Debug, Default)]
+struct SettingsArgs {
+    #[arg(long="conflict", value_enum, help="What is done with files that exist in the destination with a different modified time (Default = rename)")]
+    conflict: Option<ConflictPolicy>,
+    #[arg(long="hash", value_enum, help="The hash algorithm used to verify copies (Default = sha256)")]
+    hash: Option<HashAlgorithm>,
+    #[arg(long="verify", value_enum, help="How copied files are verified (Default = none)")]
+    verify: Option<VerifyMode>,
+    #[arg(long="xattrs", overrides_with="no_xattrs", help="Copies the extended attributes of the files (user.*, SELinux labels…)")]
+    xattrs: bool,
+    #[arg(long="no-xattrs", help="Doesn't copy the extended attributes, even if xattrs is set in .ebod/config.toml")]
+    no_xattrs: bool,
+    #[arg(long="acls", overrides_with="no_acls", help="Copies the POSIX ACLs of the files")]
+    acls: bool,
+    #[arg(long="no-acls", help="Doesn't copy the POSIX ACLs, even if acls is set in .ebod/config.toml")]
+    no_acls: bool,
+    #[arg(long="fs-compat", value_enum, help="The compatibility with a FAT32 or exFAT destination (Default = auto, detected from the mounts)")]
+    fs_compat: Option<FsCompat>,
+    #[arg(long="mtime-window", value_name="DURATION", help="Files whose modified times are at most DURATION apart (e.g. 2s, 500ms) are considered unchanged (Default = 0s)")]
+    mtime_window: Option<MtimeWindow>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    #[command(about = "Prints the value of a setting")]
+    Get {
+        key: String,
+        #[arg(short='d', long="dir", help="Relative path to the Directory (Default = '.')")]
+        dir: Option<PathBuf>,
+    },
+    #[command(about = "Changes the value of a setting")]
+    Set {
+        key: String,
+        value: String,
+        #[arg(short='d', long="dir", help="Relative path to the Directory (Default = '.')")]
+        dir: Option<PathBuf>,
+    },
+    #[command(about = "Prints all the settings")]
+    List {
+        #[arg(short='d', long="dir", help="Relative path to the Directory (Default = '.')")]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DriveAction {
+    #[command(about = "Registers the drive on which the path is stored, by writing an identity file at its root")]
+    Init {
+        #[arg(help="Relative path to a Directory on the drive (Default = '.')")]
+        path: Option<PathBuf>,
+        #[arg(short='l', long="label", help="A name for the drive, which can be used instead of its id in profiles")]
+        label: Option<String>,
+    },
+    #[command(about = "Prints the identity of the drive on which the path is stored")]
+    Show {
+        #[arg(help="Relative path to a Directory on the drive (Default = '.')")]
+        path: Option<PathBuf>,
+    },
+    #[command(about = "Lists the registered drives that are mounted")]
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum ScheduleAction {
+    #[command(about = "Runs the profile on a schedule, with a systemd timer or a crontab entry")]
+    Add {
+        #[arg(help="Name of the profile to schedule")]
+        profile: String,
+        #[arg(long="daily", value_name="HH:MM", value_parser=schedule::parse_time, group="when", help="Runs the profile every day at the given time")]
+        daily: Option<(u8, u8)>,
+        #[arg(long="weekly", value_name="DAY HH:MM", value_parser=schedule::parse_weekly, group="when", help="Runs the profile every week on the given day and time, like 'Sun 03:30'")]
+        weekly: Option<schedule::Schedule>,
+        #[arg(long="hourly", group="when", help="Runs the profile every hour")]
+        hourly: bool,
+        #[arg(long="profiles", help="Path to the profiles file (Default = ~/.config/ebod/profiles.toml)")]
+        profiles: Option<PathBuf>,
+    },
+    #[command(about = "Lists the scheduled profiles with the status of their last run")]
+    List,
+    #[command(about = "Removes the schedule of a profile")]
+    Remove {
+        #[arg(help="Name of the profile")]
+        profile: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -32,8 +177,10 @@ enum Commands {
     Init {
         #[arg(help="Relative path to the Directory to be initialized (Default = '.')")]
         path: Option<PathBuf>,
-        #[arg(short='a', long="include-hidden", help="Includes the hidden files and directories in the input directories")]
-        include_hidden: bool,
+        #[command(flatten)]
+        hidden: HiddenArgs,
+        #[command(flatten)]
+        filters: FilterArgs,
     },
     #[command(
         version,
@@ -46,8 +193,14 @@ enum Commands {
     Sync {
         src: PathBuf,
         dest: Option<PathBuf>,
-        #[arg(short='a', long="include-hidden", help="Includes the hidden files and directories in the input directories")]
-        include_hidden: bool
+        #[command(flatten)]
+        hidden: HiddenArgs,
+        #[arg(short='n', long="dry-run", help="Shows what would be copied in both directions without copying anything")]
+        dry_run: bool,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        settings: SettingsArgs,
     },
     #[command(
         version,
@@ -57,38 +210,516 @@ enum Commands {
         author = "Sivaprakash P"
     )]
     Backup {
+        #[arg(help="Relative path to Source Directory")]
+        src: PathBuf,
+        #[arg(help="Relative path to Destination Directory, sftp://[USER@]HOST[:PORT]/PATH, s3://BUCKET[/PREFIX] or dav[s]://[USER@]HOST[:PORT]/PATH")]
+        dest: Option<PathBuf>,
+        #[command(flatten)]
+        hidden: HiddenArgs,
+        #[arg(short='e', long="encrypt", help="Stores the backup in an encrypted repository in the Destination directory")]
+        encrypt: bool,
+        #[arg(short='k', long="key-file", help="File holding the passphrase of the encrypted repository (Default = $EBOD_PASSPHRASE)")]
+        key_file: Option<PathBuf>,
+        #[arg(long="to-archive", conflicts_with_all=["dest", "encrypt"], help="Writes the backup into a tar archive instead of a directory. Compressed with zstd if the name ends with .zst")]
+        to_archive: Option<PathBuf>,
+        #[arg(long="incremental-from", requires="to_archive", help="Previous archive. Only the changes since it are written into the new archive")]
+        incremental_from: Option<PathBuf>,
+        #[arg(long="to-zip", conflicts_with_all=["dest", "encrypt", "to_archive"], help="Writes the backup into a zip file. Only the new and modified files are written if it already exists")]
+        to_zip: Option<PathBuf>,
+        #[arg(short='n', long="dry-run", conflicts_with_all=["encrypt", "to_archive", "to_zip"], help="Shows what would be copied without copying anything")]
+        dry_run: bool,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to keep backing up the source directory as it changes",
+        long_about="Backs up the source into the destination, and then watches the source for changes. The changed files are backed up once no change has happened for the debounce delay. Runs until it is stopped with Ctrl+C.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Watch {
         #[arg(help="Relative path to Source Directory")]
         src: PathBuf,
         #[arg(help="Relative path to Destination Directory")]
         dest: Option<PathBuf>,
-        #[arg(short='a', long="include-hidden", help="Includes the hidden files and directories in the Source and Destination directory")]
-        include_hidden: bool,
+        #[command(flatten)]
+        hidden: HiddenArgs,
+        #[arg(long="debounce", value_name="MS", default_value_t=2000, help="Milliseconds without changes before the changes are backed up")]
+        debounce: u64,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to show the differences between the source and destination directories",
+        long_about="Compares the source with the destination and shows what a backup would do with every file, including why files were excluded by the filters. Nothing is written to either directory.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Diff {
+        #[arg(help="Relative path to Source Directory")]
+        src: PathBuf,
+        #[arg(help="Relative path to Destination Directory")]
+        dest: Option<PathBuf>,
+        #[command(flatten)]
+        hidden: HiddenArgs,
+        #[command(flatten)]
+        filters: FilterArgs,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to read and change the settings in .ebod/config.toml",
+        long_about="Reads and changes the settings stored in .ebod/config.toml of a directory. The settings are used by backup and sync, unless they are overridden on the command line.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to register and identify destination drives",
+        long_about="Writes an identity file at the root of a drive, so that profiles can target the drive wherever it is mounted. Drives are also identified by the UUID of their filesystem.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Drive {
+        #[command(subcommand)]
+        action: DriveAction,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to run the named backup profiles",
+        long_about="Runs the backup jobs defined in ~/.config/ebod/profiles.toml, and prints a summary table of their results.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Run {
+        #[arg(required_unless_present="all", help="Name of the profile to run")]
+        profile: Option<String>,
+        #[arg(long="all", conflicts_with="profile", help="Runs all the profiles")]
+        all: bool,
+        #[arg(long="profiles", help="Path to the profiles file (Default = ~/.config/ebod/profiles.toml)")]
+        profiles: Option<PathBuf>,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to run the profiles of a drive when it is plugged in",
+        long_about="Waits for registered drives to be mounted, and runs the profiles that target them. A desktop notification is sent when the profiles finish.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Daemon {
+        #[arg(long="profiles", help="Path to the profiles file (Default = ~/.config/ebod/profiles.toml)")]
+        profiles: Option<PathBuf>,
+        #[arg(long="mountinfo", default_value=drive::MOUNTINFO_PATH, help="The table of mounted filesystems that is watched")]
+        mountinfo: PathBuf,
+        #[arg(long="interval", value_name="SECS", default_value_t=2, help="Seconds between two reads of the mount table")]
+        interval: u64,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to run profiles on a schedule",
+        long_about="Installs user-level systemd timers that run profiles on a schedule. If systemd isn't available, crontab entries are written instead.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to restore an encrypted backup",
+        long_about="Decrypts all the files stored in the encrypted repository into the target directory.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Restore {
+        #[arg(help="Relative path to the encrypted repository")]
+        repo: PathBuf,
+        #[arg(help="Relative path to the Directory into which the files are restored (Default = '.')")]
+        target: Option<PathBuf>,
+        #[arg(short='k', long="key-file", help="File holding the passphrase of the encrypted repository (Default = $EBOD_PASSPHRASE)")]
+        key_file: Option<PathBuf>,
+    },
+    #[command(
+        version,
+        author,
+        about = "Used to verify an encrypted backup",
+        long_about="Decrypts and authenticates every file stored in the encrypted repository without writing them to disk.",
+        help_template = "{bin} {version}\nDeveloped By: {author}\n\n{about}\n\nUsage:\n\t{usage}\n\n{all-args}",
+        author = "Sivaprakash P"
+    )]
+    Verify {
+        #[arg(help="Relative path to the encrypted repository")]
+        repo: PathBuf,
+        #[arg(short='k', long="key-file", help="File holding the passphrase of the encrypted repository (Default = $EBOD_PASSPHRASE)")]
+        key_file: Option<PathBuf>,
     }
 }
 
 fn main() {
-    let cli = CLI::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    output::start(cli.output, matches.subcommand_name().unwrap_or_default());
+    output::set_verbosity(Verbosity::from_flags(cli.quiet, cli.verbose));
+    if let Some(log_file) = &cli.log_file
+        && let Err(err) = output::open_log_file(log_file)
+    {
+        log(LogType::Err, &err);
+        output::exit(1);
+    }
 
     if let Some(command) = cli.command {
         match command {
-            Commands::Init { path, include_hidden } => {
-                check_dir_existence(&path.clone().unwrap_or(PathBuf::from(".")));
-                initialize_dir(&path.unwrap_or(PathBuf::from(".")), include_hidden);
+            Commands::Init { path, hidden, filters } => {
+                let path = path.unwrap_or(PathBuf::from("."));
+                check_dir_existence(&path);
+                let config = build_config(&path, hidden.value(), &SettingsArgs::default());
+                initialize_dir_with_config(&path, &build_filters(&filters), &config);
             },
-            Commands::Sync { src, dest, include_hidden } => {
+            Commands::Sync { src, dest, hidden, dry_run, filters, settings } => {
                 check_dir_existence(&src);
                 let dest_path = dest.unwrap_or(PathBuf::from("."));
-                initialize_dir(&src, include_hidden);
-                initialize_dir(&dest_path, include_hidden);
-                sync_dirs(&src, &dest_path, include_hidden);
+                let filters = build_filters(&filters);
+                let config = build_config(&src, hidden.value(), &settings);
+                if dry_run {
+                    show_plan(&src, &dest_path, &filters, &config, "src");
+                    show_plan(&dest_path, &src, &filters, &config, "dest");
+                    log(LogType::Info, "Dry run: nothing was copied");
+                } else {
+                    let since = output::mark();
+                    let options = SyncOptions::from_config(&config).filters(filters);
+                    if let Err(err) = TwoWaySync::new(&src, &dest_path).options(options).run(&OutputReporter) {
+                        log(LogType::Err, &err);
+                    }
+                    save_history(&[&src, &dest_path], "sync", since);
+                }
             },
-            Commands::Backup { src, dest, include_hidden } => {
+            Commands::Backup { src, dest, hidden, encrypt, key_file, to_archive, incremental_from, to_zip, dry_run, filters, settings } => {
                 check_dir_existence(&src);
                 let dest = dest.unwrap_or(PathBuf::from("."));
-                copy_src_into_dest(src, dest, include_hidden);
+                let filters = build_filters(&filters);
+                let config = build_config(&src, hidden.value(), &settings);
+                let include_hidden = config.include_hidden;
+                if let Some(remote) = Remote::parse(&dest.to_string_lossy()) {
+                    if encrypt || to_archive.is_some() || to_zip.is_some() {
+                        log(LogType::Err, "--encrypt, --to-archive and --to-zip can't be used with a remote destination");
+                        output::exit(1);
+                    }
+                    if let Err(err) = remote.and_then(|remote| backup_to_remote(&src, &remote, &filters, &config, dry_run)) {
+                        log(LogType::Err, &err);
+                    }
+                } else if dry_run {
+                    show_plan(&src, &dest, &filters, &config, "src");
+                    log(LogType::Info, "Dry run: nothing was copied");
+                } else if let Some(zip_path) = to_zip {
+                    initialize_dir_with_filters(&src, include_hidden, &filters);
+                    if let Err(err) = zip_archive::backup_to_zip(&src, &zip_path) {
+                        log(LogType::Err, &err);
+                    }
+                } else if let Some(archive_path) = to_archive {
+                    initialize_dir_with_filters(&src, include_hidden, &filters);
+                    if let Err(err) = archive::backup_to_archive(&src, &archive_path, incremental_from.as_deref()) {
+                        log(LogType::Err, &err);
+                    }
+                } else if encrypt {
+                    encrypt_src_into_dest(src, dest, include_hidden, key_file, &filters);
+                } else {
+                    copy_src_into_dest(src, dest, &filters, &config);
+                }
+            },
+            Commands::Watch { src, dest, hidden, debounce, filters, settings } => {
+                output::stream_events();
+                check_dir_existence(&src);
+                let dest = dest.unwrap_or(PathBuf::from("."));
+                check_dir_existence(&dest);
+                let config = build_config(&src, hidden.value(), &settings);
+                if let Err(err) = watch::watch(&src, &dest, &build_filters(&filters), &config, Duration::from_millis(debounce)) {
+                    log(LogType::Err, &err);
+                    output::exit(1);
+                }
+            },
+            Commands::Diff { src, dest, hidden, filters, settings } => {
+                check_dir_existence(&src);
+                let dest = dest.unwrap_or(PathBuf::from("."));
+                show_plan(&src, &dest, &build_filters(&filters), &build_config(&src, hidden.value(), &settings), "src");
+            },
+            Commands::Config { action } => {
+                if let Err(err) = edit_config(action) {
+                    log(LogType::Err, &err);
+                }
+            },
+            Commands::Drive { action } => {
+                if let Err(err) = manage_drives(action) {
+                    log(LogType::Err, &err);
+                    output::exit(1);
+                }
+            },
+            Commands::Run { profile, all, profiles } => {
+                if let Err(err) = run_profiles(profile, all, profiles) {
+                    log(LogType::Err, &err);
+                    output::exit(1);
+                }
+            },
+            Commands::Daemon { profiles, mountinfo, interval } => {
+                output::stream_events();
+                match profiles.or_else(default_profiles_path) {
+                    Some(path) => daemon::run_daemon(&path, &mountinfo, Duration::from_secs(interval)),
+                    None => {
+                        log(LogType::Err, "Couldn't find the configuration directory");
+                        output::exit(1);
+                    },
+                }
+            },
+            Commands::Schedule { action } => {
+                if let Err(err) = manage_schedules(action) {
+                    log(LogType::Err, &err);
+                    output::exit(1);
+                }
+            },
+            Commands::Restore { repo, target, key_file } => {
+                check_dir_existence(&repo);
+                let target = target.unwrap_or(PathBuf::from("."));
+                match crypto::read_passphrase(key_file.as_deref()) {
+                    Ok(passphrase) => {
+                        if let Err(err) = crypto::restore(&repo, &target, &passphrase) {
+                            log(LogType::Err, &err);
+                        }
+                    },
+                    Err(err) => log(LogType::Err, &err),
+                }
             },
+            Commands::Verify { repo, key_file } => {
+                check_dir_existence(&repo);
+                match crypto::read_passphrase(key_file.as_deref()) {
+                    Ok(passphrase) => {
+                        if let Err(err) = crypto::verify(&repo, &passphrase) {
+                            log(LogType::Err, &err);
+                        }
+                    },
+                    Err(err) => log(LogType::Err, &err),
+                }
+            },
+        }
+    }
+    output::finish();
+}
+
+/// Builds the filters from the command line arguments. Exits if they are invalid.
+fn build_filters(args: &FilterArgs) -> Filters {
+    match Filters::new(&args.include, &args.exclude, args.min_size, args.max_size, args.newer_than.as_deref(), args.older_than.as_deref()) {
+        Ok(filters) => filters,
+        Err(err) => {
+            log(LogType::Err, &err);
+            output::exit(1);
+        }
+    }
+}
+
+/// Merges the settings in `.ebod/config.toml` of `src` with the command line. The command line takes precedence.
+/// Exits if the configuration file is invalid.
+fn build_config(src: &Path, include_hidden: Option<bool>, args: &SettingsArgs) -> DirConfig {
+    let mut config = match DirConfig::load(src) {
+        Ok(config) => config,
+        Err(err) => {
+            log(LogType::Err, &err);
+            output::exit(1);
+        }
+    };
+    if let Some(include_hidden) = include_hidden {
+        config.include_hidden = include_hidden;
+    }
+    if let Some(conflict) = args.conflict {
+        config.conflict = conflict;
+    }
+    if let Some(hash) = args.hash {
+        config.hash = hash;
+    }
+    if let Some(verify) = args.verify {
+        config.verify = verify;
+    }
+    if let Some(xattrs) = flag(args.xattrs, args.no_xattrs) {
+        config.xattrs = xattrs;
+    }
+    if let Some(acls) = flag(args.acls, args.no_acls) {
+        config.acls = acls;
+    }
+    if let Some(fs_compat) = args.fs_compat {
+        config.fs_compat = fs_compat;
+    }
+    if let Some(mtime_window) = args.mtime_window {
+        config.mtime_window = mtime_window;
+    }
+    config
+}
+
+/// Runs the `ebod config` subcommands on `.ebod/config.toml` of the directory.
+fn edit_config(action: ConfigAction) -> Result<(), String> {
+    match action {
+        ConfigAction::Get { key, dir } => {
+            let config = DirConfig::load(&dir.unwrap_or(PathBuf::from(".")))?;
+            output::print(&config.get(&key)?);
+        },
+        ConfigAction::Set { key, value, dir } => {
+            let dir = dir.unwrap_or(PathBuf::from("."));
+            check_dir_existence(&dir);
+            let mut config = DirConfig::load(&dir)?;
+            config.set(&key, &value)?;
+            config.save(&dir)?;
+            log(LogType::Ok, &format!("{} was set to {}", key, config.get(&key)?));
+        },
+        ConfigAction::List { dir } => {
+            let config = DirConfig::load(&dir.unwrap_or(PathBuf::from(".")))?;
+            for key in CONFIG_KEYS {
+                output::print(&format!("{} = {}", key, config.get(key)?));
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Runs the `ebod drive` subcommands.
+fn manage_drives(action: DriveAction) -> Result<(), String> {
+    let mountinfo = Path::new(drive::MOUNTINFO_PATH);
+    match action {
+        DriveAction::Init { path, label } => {
+            let path = path.unwrap_or(PathBuf::from("."));
+            check_dir_existence(&path);
+            let (root, identity) = drive::register_drive(&path, label.as_deref(), mountinfo)?;
+            log(LogType::Ok, &format!("The drive at {} was registered with the id {}", root.to_string_lossy(), identity.id));
+        },
+        DriveAction::Show { path } => {
+            let path = path.unwrap_or(PathBuf::from("."));
+            let mounts = drive::read_mounts(mountinfo);
+            let mount = drive::mount_for(&path, &mounts).ok_or(format!("Couldn't find the filesystem of {}", path.to_string_lossy()))?;
+            output::print(&format!("Mount point: {}", mount.mount_point.to_string_lossy()));
+            output::print(&format!("Device: {} ({})", mount.source, mount.fs_type));
+            output::print(&format!("Filesystem UUID: {}", drive::fs_uuid(mount).unwrap_or(String::from("-"))));
+            match drive::DriveIdentity::read(&mount.mount_point) {
+                Some(identity) => {
+                    output::print(&format!("Drive id: {}", identity.id));
+                    output::print(&format!("Label: {}", identity.label.unwrap_or(String::from("-"))));
+                },
+                None => output::print("The drive is not registered. Run `ebod drive init` to register it"),
+            }
+        },
+        DriveAction::List => {
+            let drives = drive::registered_drives(mountinfo);
+            if drives.is_empty() {
+                log(LogType::Info, "No registered drives are mounted");
+            }
+            for (mount, identity) in drives {
+                output::print(&format!("{}  {:<12}  {}", identity.id, identity.label.unwrap_or(String::from("-")), mount.mount_point.to_string_lossy()));
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Runs the `ebod schedule` subcommands.
+fn manage_schedules(action: ScheduleAction) -> Result<(), String> {
+    match action {
+        ScheduleAction::Add { profile, daily, weekly, hourly, profiles } => {
+            let when = match (daily, weekly) {
+                (Some((hour, minute)), _) => schedule::Schedule::Daily(hour, minute),
+                (_, Some(weekly)) => weekly,
+                _ if hourly => schedule::Schedule::Hourly,
+                _ => return Err(String::from("Give one of --daily, --weekly or --hourly")),
+            };
+            let path = profiles.clone().or_else(default_profiles_path).ok_or("Couldn't find the configuration directory")?;
+            if !Profiles::load(&path)?.profiles.contains_key(&profile) {
+                return Err(format!("No profile named {} in {}", profile, path.to_string_lossy()));
+            }
+            let backend = schedule::add_schedule(&profile, &when, profiles.as_deref())?;
+            log(LogType::Ok, &format!("The profile {} is run {} ({})", profile, when, backend));
+        },
+        ScheduleAction::List => {
+            let scheduled = schedule::list_schedules();
+            if scheduled.is_empty() {
+                log(LogType::Info, "No profiles are scheduled");
+                return Ok(());
+            }
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            for entry in scheduled {
+                let last_run = match ProfileState::load(&entry.profile) {
+                    Some(state) if state.ok => format!("ok, {} ago", format_age(now.saturating_sub(state.last_run))),
+                    Some(state) => format!("failed, {} ago: {}", format_age(now.saturating_sub(state.last_run)), state.error.unwrap_or_default()),
+                    None => String::from("never run"),
+                };
+                output::print(&format!("{:<16}  {:<8}  {:<24}  {}", entry.profile, entry.backend, entry.when, last_run));
+            }
+        },
+        ScheduleAction::Remove { profile } => {
+            schedule::remove_schedule(&profile)?;
+            log(LogType::Ok, &format!("The schedule of {} was removed", profile));
+        },
+    }
+    Ok(())
+}
+
+/// Formats a number of seconds in the largest fitting unit, like `3h` or `2d`.
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Runs the profile `profile`, or all the profiles with `--all`, and prints the summary table.
+/// Returns an error if a profile failed.
+fn run_profiles(profile: Option<String>, all: bool, profiles_path: Option<PathBuf>) -> Result<(), String> {
+    let path = profiles_path.or_else(default_profiles_path).ok_or("Couldn't find the configuration directory")?;
+    let profiles = Profiles::load(&path)?;
+    let names: Vec<String> = if all {
+        profiles.profiles.keys().cloned().collect()
+    } else {
+        let name = profile.unwrap_or_default();
+        if !profiles.profiles.contains_key(&name) {
+            return Err(format!("No profile named {} in {}", name, path.to_string_lossy()));
         }
+        vec![name]
+    };
+    if names.is_empty() {
+        return Err(format!("No profiles are defined in {}", path.to_string_lossy()));
+    }
+    let state_dir = default_state_dir();
+    let results: Vec<_> =
+        names.iter().map(|name| run_profile(name, &profiles.profiles[name], Path::new(drive::MOUNTINFO_PATH), state_dir.as_deref())).collect();
+    print_summary_table(&results);
+    let failed = results.iter().filter(|r| r.result.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} profiles failed", failed, results.len()));
     }
+    Ok(())
+}
+
+/// Prints what a backup of `src` into `dest` would do, without writing to either directory.
+/// 
+/// The directories are indexed like the backup indexes them, so that the plan can show why each excluded entry was
+/// left out and which attributes would be updated.
+fn show_plan(src: &Path, dest: &Path, filters: &Filters, config: &DirConfig, dir: &str) {
+    log(LogType::Info, &format!("Backing up {} into {}", src.to_string_lossy(), dest.to_string_lossy()));
+    print_plan(&plan_local(src, dest, dir, filters, config));
 }
 
 /// Abstracted function to copy `src` into `dest`
@@ -96,13 +727,122 @@ fn main() {
 /// # Input
 /// * `src: &PathBuf` -> The `PathBuf` to the source directory
 /// * `dest: &PathBuf` -> The `PathBuf` to the destination directory
+/// * `filters: &Filters` -> The filters given on the command line
+/// * `config: &DirConfig` -> The settings merged from `.ebod/config.toml` and the command line
+fn copy_src_into_dest(src: PathBuf, dest: PathBuf, filters: &Filters, config: &DirConfig) {
+    let since = output::mark();
+    let options = BackupOptions::from_config(config).filters(filters.clone());
+    if let Err(err) = Backup::new(&src, &dest).options(options).run(&OutputReporter) {
+        log(LogType::Err, &err);
+    }
+    save_history(&[&dest], "backup", since);
+}
+
+/// Abstracted function to back up `src` into a remote destination, or to show the plan with `dry_run`
+/// 
+/// # Input
+/// * `src: &Path` -> The `Path` to the source directory
+/// * `remote: &Remote` -> The remote destination
+/// * `filters: &Filters` -> The filters given on the command line
+/// * `config: &DirConfig` -> The settings merged from `.ebod/config.toml` and the command line
+/// * `dry_run: bool` -> Only shows what would be uploaded
+fn backup_to_remote(src: &Path, remote: &Remote, filters: &Filters, config: &DirConfig, dry_run: bool) -> Result<(), String> {
+    initialize_dir_with_filters(src, config.include_hidden, filters);
+    let vfs = remote.connect()?;
+    log(LogType::Info, &format!("Backing up {} into {} on the remote side", src.to_string_lossy(), remote.root().to_string_lossy()));
+    if dry_run {
+        print_plan(&remote::plan_to(src, vfs.as_ref(), remote.root(), filters, config, remote.relists()));
+        log(LogType::Info, "Dry run: nothing was copied");
+        return Ok(());
+    }
+    let summary = remote::backup_to(src, vfs.as_ref(), remote.root(), filters, config, remote.relists(), &OutputReporter)?;
+    log(LogType::Ok, &format!("{} files uploaded ({} bytes)", summary.copied + summary.renamed + summary.overwritten, summary.bytes));
+    Ok(())
+}
+
+/// Saves the log of the run since `since` into the run history (`.ebod/logs/`) of every directory in `dirs`, and ends
+/// the history.
+fn save_history(dirs: &[&Path], name: &str, since: usize) {
+    for dir in dirs {
+        if let Err(err) = output::save_history(dir, name, since) {
+            log(LogType::Err, &err);
+        }
+    }
+    output::end_history();
+}
+
+/// Abstracted function to back up `src` into an encrypted repository at `dest`
+/// 
+/// # Input
+/// * `src: PathBuf` -> The `PathBuf` to the source directory
+/// * `dest: PathBuf` -> The `PathBuf` to the root of the encrypted repository
 /// * `include_hidden: bool` -> The boolean flag to represent inclusion of hidden files for backup process.
-fn copy_src_into_dest(src: PathBuf, dest: PathBuf, include_hidden: bool) {
-    // Initializing the .ebod directories in both the folders
-    initialize_dir(&src, include_hidden);
-    initialize_dir(&dest, include_hidden);
-    
-    if let Err(err) = backup(&src, &dest, "src") {
-        log(LogType::Err, &format!("{}", err));
-    }
-}
\ No newline at end of file
+/// * `key_file: Option<PathBuf>` -> The file holding the passphrase. `$EBOD_PASSPHRASE` is used if it is `None`.
+/// * `filters: &Filters` -> The filters given on the command line
+fn encrypt_src_into_dest(src: PathBuf, dest: PathBuf, include_hidden: bool, key_file: Option<PathBuf>, filters: &Filters) {
+    let passphrase = match crypto::read_passphrase(key_file.as_deref()) {
+        Ok(passphrase) => passphrase,
+        Err(err) => {
+            log(LogType::Err, &err);
+            return;
+        }
+    };
+    // Only the source is initialized, the destination keeps its metadata in the encrypted index
+    initialize_dir_with_filters(&src, include_hidden, filters);
+
+    if let Err(err) = crypto::encrypted_backup(&src, &dest, &passphrase) {
+        log(LogType::Err, &err);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The settings given to `ebod backup` with `args`
+    fn backup_settings(args: &[&str]) -> (HiddenArgs, SettingsArgs) {
+        let cli = Cli::try_parse_from(["ebod", "backup", "src", "dest"].iter().chain(args)).unwrap();
+        match cli.command {
+            Some(Commands::Backup { hidden, settings, .. }) => (hidden, settings),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn flags_only_override_the_config_when_given() {
+        let (hidden, settings) = backup_settings(&[]);
+        assert_eq!(hidden.value(), None);
+        assert_eq!(flag(settings.xattrs, settings.no_xattrs), None);
+
+        let (hidden, settings) = backup_settings(&["--no-include-hidden", "--no-xattrs", "--acls"]);
+        assert_eq!(hidden.value(), Some(false));
+        assert_eq!(flag(settings.xattrs, settings.no_xattrs), Some(false));
+        assert_eq!(flag(settings.acls, settings.no_acls), Some(true));
+
+        // The last flag of a pair wins
+        let (hidden, settings) = backup_settings(&["--no-include-hidden", "-a", "--acls", "--no-acls"]);
+        assert_eq!(hidden.value(), Some(true));
+        assert_eq!(flag(settings.acls, settings.no_acls), Some(false));
+    }
+
+    #[test]
+    fn the_command_line_takes_precedence_over_the_config_file() {
+        let src = std::env::temp_dir().join(format!("ebod-precedence-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&src);
+        let mut file = DirConfig::default();
+        for (key, value) in [("include_hidden", "true"), ("xattrs", "true"), ("conflict", "skip"), ("hash", "blake3")] {
+            file.set(key, value).unwrap();
+        }
+        file.save(&src).unwrap();
+
+        // The values of the file are used when no flag is given
+        let (hidden, settings) = backup_settings(&[]);
+        assert_eq!(build_config(&src, hidden.value(), &settings), file);
+
+        let (hidden, settings) = backup_settings(&["--no-include-hidden", "--no-xattrs", "--acls", "--conflict", "overwrite"]);
+        let config = build_config(&src, hidden.value(), &settings);
+        assert!(!config.include_hidden && !config.xattrs && config.acls);
+        assert_eq!(config.conflict, ConflictPolicy::Overwrite);
+        assert_eq!(config.hash, HashAlgorithm::Blake3);
+        std::fs::remove_dir_all(&src).unwrap();
+    }
+}
@@ -0,0 +1,450 @@
+//! Machine-readable output.
+//!
+//! With `--output json` or `--output ndjson`, the human-readable log is written to stderr, and stdout only gets
+//! structured records:
+//! * `ndjson` -> One JSON object per event as soon as it happens, and the summary object as the last line
+//! * `json` -> A single JSON object `{"events": [...], "summary": {...}}` when the command finishes
+//!
+//! Every record has an `event` field naming its kind. The summary is computed from the events, so it is the same for
+//! every command: files copied, bytes, skipped, conflicts, errors and duration.
+//!
+//! The module also keeps the plain-text log of the run. Every line is timestamped, whatever the verbosity, and written
+//! to `--log-file` as it happens. The lines are only kept in memory between `mark` and `end_history`, to be saved in the
+//! run history (`.ebod/logs/`), so that `watch` and `daemon` don't keep the log of every run.
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{CopyMethod, progress, utc_datetime};
+
+/// How much is displayed: `-q` only displays errors, `-v` adds the debug messages and `-vv` the trace messages.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum Verbosity {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+    Trace,
+}
+
+impl Verbosity {
+    /// The verbosity given by `-q` and the number of `-v`.
+    pub fn from_flags(quiet: bool, verbose: u8) -> Verbosity {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            _ => Verbosity::Trace,
+        }
+    }
+}
+
+/// The format of the output of ebod.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Ndjson,
+}
+
+/// A structured record of something ebod did.
+///
+/// # Values in Enum
+/// * `Copied` -> A file was copied under the same name. `method` tells how, for the files copied by a backup
+/// * `Renamed` -> A conflicting file was copied under the name `to`
+/// * `Overwritten` -> A conflicting file overwrote the file in the destination
+/// * `Kept` -> A conflicting file wasn't copied and the destination was kept
+/// * `DirCreated` -> A directory was created in the destination
+/// * `AttrsUpdated` -> The extended attributes or ACLs of an entry were updated in the destination
+/// * `Skipped` -> An entry wasn't copied, because it is identical or was excluded (`reason`)
+/// * `Planned` -> What a backup would do with an entry (`ebod diff` and `--dry-run`)
+/// * `Error` -> An error message
+/// * `Summary` -> The counts of the whole command
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Copied {
+        path: String,
+        bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        method: Option<CopyMethod>,
+    },
+    Renamed {
+        path: String,
+        to: String,
+        bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        method: Option<CopyMethod>,
+    },
+    Overwritten {
+        path: String,
+        bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        method: Option<CopyMethod>,
+    },
+    Kept { path: String },
+    DirCreated { path: String },
+    AttrsUpdated { path: String },
+    Skipped { path: String, reason: String },
+    Planned { path: String, action: String, detail: Option<String> },
+    Error { message: String },
+    Summary(Summary),
+}
+
+/// Receives the events of a backup. The command line sends them to the `--output` records with `OutputReporter`.
+/// Library users can pass their own reporter, or a closure taking `&Event`, to `Backup::run` and `TwoWaySync::run`.
+pub trait Reporter {
+    fn report(&self, event: &Event);
+}
+
+/// The reporter of the command line. The events are recorded with `emit`.
+pub struct OutputReporter;
+
+/// A reporter that ignores the events.
+pub struct NoReporter;
+
+impl Reporter for OutputReporter {
+    fn report(&self, event: &Event) {
+        emit(event.clone());
+    }
+}
+
+impl Reporter for NoReporter {
+    fn report(&self, _event: &Event) {}
+}
+
+impl<F: Fn(&Event)> Reporter for F {
+    fn report(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// The final record of a command.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct Summary {
+    pub command: String,
+    pub ok: bool,
+    pub copied: u64,
+    pub renamed: u64,
+    pub overwritten: u64,
+    pub conflicts: u64,
+    pub dirs_created: u64,
+    pub attrs_updated: u64,
+    pub skipped: u64,
+    pub bytes: u64,
+    pub bytes_cloned: u64,
+    pub errors: u64,
+    pub duration_secs: f64,
+}
+
+impl Summary {
+    fn add_bytes(&mut self, bytes: u64, method: Option<CopyMethod>) {
+        self.bytes += bytes;
+        if method == Some(CopyMethod::Reflink) {
+            self.bytes_cloned += bytes;
+        }
+    }
+}
+
+// The single object printed with `json`
+#[derive(Serialize)]
+struct Report<'a> {
+    events: &'a [Event],
+    summary: &'a Summary,
+}
+
+struct State {
+    format: OutputFormat,
+    verbosity: Verbosity,
+    started: Option<Instant>,
+    events: Vec<Event>,
+    summary: Summary,
+    // The lines logged since the oldest pending `mark`
+    lines: Vec<String>,
+    // The number of lines logged before `lines[0]`
+    first_line: usize,
+    // The number of `mark` calls without their `end_history`
+    pending: usize,
+    log_file: Option<File>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    format: OutputFormat::Text,
+    verbosity: Verbosity::Normal,
+    started: None,
+    events: vec![],
+    summary: summary_default(),
+    lines: vec![],
+    first_line: 0,
+    pending: 0,
+    log_file: None,
+});
+
+const fn summary_default() -> Summary {
+    Summary {
+        command: String::new(),
+        ok: true,
+        copied: 0,
+        renamed: 0,
+        overwritten: 0,
+        conflicts: 0,
+        dirs_created: 0,
+        attrs_updated: 0,
+        skipped: 0,
+        bytes: 0,
+        bytes_cloned: 0,
+        errors: 0,
+        duration_secs: 0.0,
+    }
+}
+
+/// Sets the output format, and starts measuring the duration of `command`.
+pub fn start(format: OutputFormat, command: &str) {
+    if let Ok(mut state) = STATE.lock() {
+        state.format = format;
+        state.started = Some(Instant::now());
+        state.summary.command = command.to_string();
+    }
+}
+
+/// Prints the events of `json` as they happen, like `ndjson`. Used by `watch` and `daemon`, which run until they are
+/// stopped: they would never print the final object, and would keep every event until then.
+pub fn stream_events() {
+    if let Ok(mut state) = STATE.lock()
+        && state.format == OutputFormat::Json
+    {
+        state.format = OutputFormat::Ndjson;
+    }
+}
+
+/// Sets how much is displayed.
+pub fn set_verbosity(verbosity: Verbosity) {
+    if let Ok(mut state) = STATE.lock() {
+        state.verbosity = verbosity;
+    }
+}
+
+/// The verbosity set with `set_verbosity`.
+pub fn verbosity() -> Verbosity {
+    STATE.lock().map(|state| state.verbosity).unwrap_or_default()
+}
+
+/// Opens the `--log-file`. The log of the run is appended to it as it happens.
+pub fn open_log_file(path: &Path) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|_| format!("Couldn't open the log file {}", path.to_string_lossy()))?;
+    if let Ok(mut state) = STATE.lock() {
+        state.log_file = Some(file);
+    }
+    Ok(())
+}
+
+// The current time as `2026-01-31 23:59:59Z`
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day, hour, minute, second) = utc_datetime(secs);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+// Removes the color codes written by `colored`
+fn strip_ansi(line: &str) -> String {
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// Adds a timestamped line to the plain-text log of the run, and to the `--log-file`.
+pub fn record(line: &str) {
+    let line = format!("{} {}", timestamp(), strip_ansi(line));
+    if let Ok(mut state) = STATE.lock() {
+        if let Some(file) = state.log_file.as_mut() {
+            let _ = writeln!(file, "{}", line);
+        }
+        if state.pending > 0 {
+            state.lines.push(line);
+        } else {
+            state.first_line += 1;
+        }
+    }
+}
+
+/// Starts keeping the lines logged from now on, for `save_history`. Returns the number of lines logged so far, which
+/// is passed to `save_history`. Every `mark` is followed by an `end_history`.
+pub fn mark() -> usize {
+    let Ok(mut state) = STATE.lock() else {
+        return 0;
+    };
+    state.pending += 1;
+    state.first_line + state.lines.len()
+}
+
+/// Ends the history started by `mark`. When no other history is pending, the lines kept are dropped.
+pub fn end_history() {
+    if let Ok(mut state) = STATE.lock() {
+        state.pending = state.pending.saturating_sub(1);
+        if state.pending == 0 {
+            state.first_line += state.lines.len();
+            state.lines.clear();
+        }
+    }
+}
+
+/// Saves the lines logged since `since` into the run history of `dir`: `.ebod/logs/<time>-<name>.log`.
+pub fn save_history(dir: &Path, name: &str, since: usize) -> Result<(), String> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day, hour, minute, second) = utc_datetime(secs);
+    let logs = dir.join(".ebod").join("logs");
+    fs::create_dir_all(&logs).map_err(|_| format!("Couldn't create {}", logs.to_string_lossy()))?;
+    let stem = format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z-{}", year, month, day, hour, minute, second, name);
+    let mut path = logs.join(format!("{}.log", stem));
+    // Runs started within the same second get a number
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = logs.join(format!("{}-{}.log", stem, count));
+    }
+    let content = STATE
+        .lock()
+        .map(|state| state.lines.get(since.saturating_sub(state.first_line)..).unwrap_or_default().iter().map(|line| format!("{}\n", line)).collect::<String>())
+        .unwrap_or_default();
+    fs::write(&path, content).map_err(|_| format!("Couldn't write {}", path.to_string_lossy()))
+}
+
+/// The output format set with `start`.
+pub fn format() -> OutputFormat {
+    STATE.lock().map(|state| state.format).unwrap_or_default()
+}
+
+/// Whether stdout is reserved for structured records.
+pub fn is_structured() -> bool {
+    format() != OutputFormat::Text
+}
+
+/// Records an event. It is printed right away with `ndjson`, and kept for the final object with `json`.
+pub fn emit(event: Event) {
+    let Ok(mut state) = STATE.lock() else {
+        return;
+    };
+    let summary = &mut state.summary;
+    match &event {
+        Event::Copied { bytes, method, .. } => {
+            summary.copied += 1;
+            summary.add_bytes(*bytes, *method);
+        }
+        Event::Renamed { bytes, method, .. } => {
+            summary.renamed += 1;
+            summary.conflicts += 1;
+            summary.add_bytes(*bytes, *method);
+        }
+        Event::Overwritten { bytes, method, .. } => {
+            summary.overwritten += 1;
+            summary.conflicts += 1;
+            summary.add_bytes(*bytes, *method);
+        }
+        Event::Kept { .. } => summary.conflicts += 1,
+        Event::DirCreated { .. } => summary.dirs_created += 1,
+        Event::AttrsUpdated { .. } => summary.attrs_updated += 1,
+        Event::Skipped { .. } => summary.skipped += 1,
+        Event::Error { .. } => summary.errors += 1,
+        Event::Planned { .. } | Event::Summary(_) => {}
+    }
+    match state.format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => state.events.push(event),
+        OutputFormat::Ndjson => println!("{}", serde_json::to_string(&event).unwrap_or_default()),
+    }
+}
+
+/// Prints a line of human-readable output. It goes to stdout, unless stdout is reserved for structured records. The
+/// line is printed above the progress bars, if they are shown. It is also added to the log of the run.
+pub fn print(line: &str) {
+    record(line);
+    print_unrecorded(line);
+}
+
+/// Same as `print`, but only if the verbosity isn't `Quiet`.
+pub fn detail(line: &str) {
+    record(line);
+    if verbosity() > Verbosity::Quiet {
+        print_unrecorded(line);
+    }
+}
+
+/// Same as `print`, without adding the line to the log of the run. Used by `log`, which records its own line.
+pub fn print_unrecorded(line: &str) {
+    let structured = is_structured();
+    progress::suspend(|| {
+        if structured {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    });
+}
+
+/// Prints the summary of the command (with `json`, together with all the events). Nothing is printed with `text`.
+pub fn finish() {
+    let Ok(state) = STATE.lock() else {
+        return;
+    };
+    let mut summary = state.summary.clone();
+    summary.ok = summary.errors == 0;
+    summary.duration_secs = state.started.map(|started| started.elapsed().as_secs_f64()).unwrap_or(0.0);
+    match state.format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => {
+            let report = Report { events: &state.events, summary: &summary };
+            println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+        }
+        OutputFormat::Ndjson => println!("{}", serde_json::to_string(&Event::Summary(summary)).unwrap_or_default()),
+    }
+}
+
+/// Prints the summary and exits with `code`.
+pub fn exit(code: i32) -> ! {
+    finish();
+    std::process::exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_history_holds_the_lines_since_its_mark() {
+        let dir = std::env::temp_dir().join(format!("ebod-output-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        record("before the mark");
+        let since = mark();
+        record("\u{1b}[32mafter the mark\u{1b}[0m");
+        save_history(&dir, "test", since).unwrap();
+        end_history();
+
+        let logs: Vec<_> = fs::read_dir(dir.join(".ebod/logs")).unwrap().flatten().collect();
+        assert_eq!(logs.len(), 1);
+        let content = fs::read_to_string(logs[0].path()).unwrap();
+        assert!(content.contains("Z after the mark\n"));
+        assert!(!content.contains("before the mark"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+//! Planning of a backup.
+//!
+//! The plan decides what happens to every entry of the source directory before anything is copied. It is executed by
+//! `backup`, and printed by `ebod diff` and `--dry-run`.
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use colored::Colorize;
+
+use crate::config::{ConflictPolicy, DirConfig};
+use crate::fat::{DestFs, Namer};
+use crate::filter::{Exclusion, Filters};
+use crate::output::{self, Event};
+use crate::vfs::{LocalFs, Vfs};
+use crate::xattrs::Preserve;
+use crate::{EntryType, FileEntry, recursive_listing_in, rename_redundant_files};
+
+/// The action that the backup takes for an entry of the source directory.
+///
+/// # Values in Enum
+/// * `CreateDir` -> The directory is created in the destination
+/// * `Copy` -> The file is copied to the destination under the same name
+/// * `CopyAs(String)` -> A file with the same name but a different modified time exists in the destination. So the file is copied under the given name
+/// * `Overwrite` -> The conflicting file in the destination is overwritten (`--conflict overwrite|newer`)
+/// * `KeepDest` -> The conflicting file in the destination is kept and the file is not copied (`--conflict skip|newer`)
+/// * `UpdateAttrs` -> The file exists in the destination, but its extended attributes or ACLs changed (`--xattrs`, `--acls`)
+/// * `Identical` -> The entry already exists in the destination and is not copied
+/// * `Excluded(Exclusion)` -> The entry was left out by a filter
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Action {
+    CreateDir,
+    Copy,
+    CopyAs(String),
+    Overwrite,
+    KeepDest,
+    UpdateAttrs,
+    Identical,
+    Excluded(Exclusion),
+}
+
+/// An entry of the source directory together with the action planned for it.
+///
+/// # Members
+/// * `entry: FileEntry` -> The entry of the source directory
+/// * `action: Action` -> What the backup does with it
+/// * `stored_as: Option<String>` -> The path of the entry in the destination, if it isn't its name (or the name given
+///   by `CopyAs`). Set for the names that a FAT or exFAT destination doesn't allow
+#[derive(Debug, Clone)]
+pub struct PlannedEntry {
+    pub entry: FileEntry,
+    pub action: Action,
+    pub stored_as: Option<String>,
+}
+
+/// Plans the backup of a source into the directory `dest` of `dest_vfs` with the settings of `config`. The backup and
+/// its preview (`ebod diff`, `--dry-run`) both plan with it, so that the preview shows what the backup does.
+///
+/// `src_meta` and `dest_meta` list both sides without the filters. The filters only leave entries of the source out: a
+/// destination file that they would leave out must still be seen, so that the source file of the same name goes
+/// through the conflict policy instead of overwriting it.
+pub fn plan_dirs<D: Vfs + ?Sized>(src_meta: &[FileEntry], dest_meta: &[FileEntry], dest_vfs: &D, dest: &Path, dir: &str, filters: &Filters, config: &DirConfig) -> Vec<PlannedEntry> {
+    let dest_fs = dest_vfs.local_path(dest).map(|path| config.dest_fs(&path)).unwrap_or_default();
+    plan_backup(src_meta, dest_meta, dir, filters, config.conflict, config.preserve(), config.mtime_window(dest_fs), dest_fs)
+}
+
+/// Plans the backup of the local directory `src` into `dest` without writing to either, for `ebod diff` and
+/// `--dry-run`. Both sides are listed like `Backup::run` indexes them: without the filters, and with the hashes of the
+/// attributes that `config` preserves, so that the preview shows the same actions as the backup.
+pub fn plan_local(src: &Path, dest: &Path, dir: &str, filters: &Filters, config: &DirConfig) -> Vec<PlannedEntry> {
+    let index = |path: &Path| {
+        let mut data = vec![];
+        recursive_listing_in(&LocalFs, path, path, &mut data, config.include_hidden, config.preserve(), &Filters::default());
+        data
+    };
+    plan_dirs(&index(src), &index(dest), &LocalFs, dest, dir, filters, config)
+}
+
+/// Plans the backup of the `src_meta` entries into a destination described by `dest_meta`. It follows the rules
+/// documented on `backup`.
+///
+/// # Input
+/// * `src_meta: &[FileEntry]` -> The metadata of the source directory
+/// * `dest_meta: &[FileEntry]` -> The metadata of the destination directory
+/// * `dir: &str` -> The name (src/dest) used to rename conflicting files
+/// * `filters: &Filters` -> The filters that leave entries out of the backup
+/// * `conflict: ConflictPolicy` -> What is done with files that have a different `modified_at` in the destination
+/// * `preserve: Preserve` -> The attributes whose changes are planned as `UpdateAttrs`
+/// * `mtime_window: Duration` -> The tolerance of the comparison of modified times
+/// * `dest_fs: DestFs` -> The filesystem of the destination, which sets the names allowed
+///
+/// # Output: `Vec<PlannedEntry>`
+/// One planned entry for every entry of `src_meta`, in the same order.
+#[allow(clippy::too_many_arguments)]
+pub fn plan_backup(src_meta: &[FileEntry], dest_meta: &[FileEntry], dir: &str, filters: &Filters, conflict: ConflictPolicy, preserve: Preserve, mtime_window: Duration, dest_fs: DestFs) -> Vec<PlannedEntry> {
+    let mut namer = Namer::new(dest_fs, dest_meta);
+    // The entries of the destination by name. The first one wins if a name is listed twice
+    let mut by_name: HashMap<&str, &FileEntry> = HashMap::with_capacity(dest_meta.len());
+    for entry in dest_meta {
+        by_name.entry(entry.name.as_str()).or_insert(entry);
+    }
+    src_meta
+        .iter()
+        .map(|file| {
+            let existing = by_name.get(file.name.as_str()).copied();
+            let action = if let Err(reason) = filters.check(file) {
+                Action::Excluded(reason)
+            } else if existing == Some(file) {
+                Action::Identical
+            } else if file.e_type == EntryType::Dir {
+                Action::CreateDir
+            } else {
+                match existing {
+                    None => Action::Copy,
+                    Some(dest_file) if dest_file.mtime_diff(file) > mtime_window => match conflict {
+                        ConflictPolicy::Rename => Action::CopyAs(rename_redundant_files(&file.name, dir)),
+                        ConflictPolicy::Skip => Action::KeepDest,
+                        ConflictPolicy::Overwrite => Action::Overwrite,
+                        ConflictPolicy::Newer if file.modified() > dest_file.modified() => Action::Overwrite,
+                        ConflictPolicy::Newer => Action::KeepDest,
+                    },
+                    Some(dest_file) if preserve.differs(file, dest_file) => Action::UpdateAttrs,
+                    Some(_) => Action::Identical,
+                }
+            };
+            // The entries that already exist keep their path, the new ones get a path allowed by the destination
+            let stored_as = match (&action, existing) {
+                (Action::CopyAs(new_name), _) => namer.store(new_name, file),
+                (Action::Copy, _) | (Action::CreateDir, None) => namer.store(&file.name, file),
+                (Action::Excluded(_), _) | (_, None) => None,
+                (_, Some(dest_file)) => dest_file.stored_as.clone(),
+            };
+            PlannedEntry { entry: file.clone(), action, stored_as }
+        })
+        .collect()
+}
+
+/// Prints the plan, one line per entry, with the reason for every excluded entry. Every entry is also recorded as a
+/// `planned` event.
+pub fn print_plan(plan: &[PlannedEntry]) {
+    for planned in plan {
+        let name = &planned.entry.name;
+        let (action, detail) = match &planned.action {
+            Action::CreateDir => {
+                output::print(&format!("{} {}", " mkdir  ".on_green().bold(), name));
+                ("create_dir", None)
+            }
+            Action::Copy => match &planned.stored_as {
+                Some(stored) => {
+                    output::print(&format!("{} {} (stored as {})", "  copy  ".on_green().bold(), name, stored.yellow()));
+                    ("copy", Some(stored.clone()))
+                }
+                None => {
+                    output::print(&format!("{} {}", "  copy  ".on_green().bold(), name));
+                    ("copy", None)
+                }
+            },
+            Action::CopyAs(new_name) => {
+                output::print(&format!("{} {} -> {}", " rename ".on_yellow().bold(), name, new_name.yellow()));
+                ("rename", Some(new_name.clone()))
+            }
+            Action::Overwrite => {
+                output::print(&format!("{} {}", " update ".on_yellow().bold(), name));
+                ("overwrite", None)
+            }
+            Action::KeepDest => {
+                output::print(&format!("{} {} (conflict, the destination is kept)", "  keep  ".bold(), name));
+                ("keep", None)
+            }
+            Action::UpdateAttrs => {
+                output::print(&format!("{} {}", " attrs  ".on_yellow().bold(), name));
+                ("update_attrs", None)
+            }
+            Action::Identical => {
+                output::print(&format!("{} {}", "  same  ".bold(), name));
+                ("identical", None)
+            }
+            Action::Excluded(reason) => {
+                output::print(&format!("{} {} ({})", " exclude".on_red().bold(), name, reason.to_string().red()));
+                ("exclude", Some(reason.to_string()))
+            }
+        };
+        output::emit(Event::Planned { path: name.clone(), action: action.to_string(), detail });
+    }
+    let count = |f: fn(&Action) -> bool| plan.iter().filter(|p| f(&p.action)).count();
+    output::print(&format!(
+        "{} to copy, {} to rename, {} to overwrite, {} conflicts kept, {} directories to create, {} attributes to update, {} identical, {} excluded",
+        count(|a| *a == Action::Copy),
+        count(|a| matches!(a, Action::CopyAs(_))),
+        count(|a| *a == Action::Overwrite),
+        count(|a| *a == Action::KeepDest),
+        count(|a| *a == Action::CreateDir),
+        count(|a| *a == Action::UpdateAttrs),
+        count(|a| *a == Action::Identical),
+        count(|a| matches!(a, Action::Excluded(_))),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Backup, BackupOptions};
+    use std::fs;
+    use std::sync::Mutex;
+
+    // The name of the event that the backup sends for every action
+    fn event_of(action: &Action) -> &'static str {
+        match action {
+            Action::CreateDir => "dir_created",
+            Action::Copy => "copied",
+            Action::CopyAs(_) => "renamed",
+            Action::Overwrite => "overwritten",
+            Action::KeepDest => "kept",
+            Action::UpdateAttrs => "attrs_updated",
+            Action::Identical | Action::Excluded(_) => "skipped",
+        }
+    }
+
+    #[test]
+    fn the_preview_plans_what_the_backup_does() {
+        let root = std::env::temp_dir().join(format!("ebod-preview-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (src, dest) = (root.join("src"), root.join("dest"));
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(src.join("docs/new.txt"), "new").unwrap();
+        fs::write(src.join("skip.log"), "log").unwrap();
+        fs::write(src.join("tagged.txt"), "same").unwrap();
+        fs::write(dest.join("tagged.txt"), "same").unwrap();
+        let modified = fs::metadata(src.join("tagged.txt")).unwrap().modified().unwrap();
+        fs::File::options().write(true).open(dest.join("tagged.txt")).unwrap().set_modified(modified).unwrap();
+        // Without extended attributes, the file is identical on both sides instead of having its attributes updated
+        let tagged = LocalFs.set_xattr(&src.join("tagged.txt"), "user.ebod.test", b"value").is_ok();
+
+        let filters = Filters::new(&[], &[String::from("*.log")], None, None, None, None).unwrap();
+        let options = BackupOptions::new().xattrs(true).filters(filters.clone());
+        let plan = plan_local(&src, &dest, "src", &filters, &options.config());
+        assert_eq!(plan.iter().any(|p| p.action == Action::UpdateAttrs), tagged);
+
+        let events = Mutex::new(vec![]);
+        let reporter = |event: &Event| {
+            let record = serde_json::to_value(event).unwrap();
+            events.lock().unwrap().push((record["path"].as_str().unwrap().to_string(), record["event"].as_str().unwrap().to_string()));
+        };
+        Backup::new(&src, &dest).options(options).run(&reporter).unwrap();
+        let mut planned: Vec<(String, String)> = plan.iter().map(|p| (p.entry.name.clone(), event_of(&p.action).to_string())).collect();
+        let mut done = events.into_inner().unwrap();
+        planned.sort();
+        done.sort();
+        assert_eq!(planned, done);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+//! Named backup profiles stored in the user-level configuration `~/.config/ebod/profiles.toml`.
+//!
+//! ```toml
+//! [profiles.photos]
+//! src = "~/Pictures"
+//! dest = "/media/me/RED/photos"
+//! mode = "backup"
+//! exclude = ["*.tmp"]
+//! verify = "size"
+//! ```
+//!
+//! A profile can target a registered drive with `drive = "<id>"`, where the id is the one written by `ebod drive init`,
+//! the filesystem UUID or the label of the drive. `dest` is then relative to wherever the drive is mounted, and an
+//! absolute `dest` must be on that drive.
+//!
+//! A profile holds everything that would be given on the command line of `backup` or `sync`. The options that are
+//! not set in the profile fall back to `.ebod/config.toml` of the source directory and then to the defaults.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Backup, BackupOptions, SyncOptions, TwoWaySync};
+use crate::checksum::{HashAlgorithm, VerifyMode};
+use crate::config::{ConflictPolicy, DirConfig, MtimeWindow};
+use crate::drive::{check_drive, find_drive};
+use crate::fat::FsCompat;
+use crate::filter::{Filters, parse_size};
+use crate::output::{self, OutputReporter};
+use crate::schedule;
+use crate::{BackupSummary, LogType, log};
+
+/// Whether a profile backs up the source into the destination or syncs them.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileMode {
+    #[default]
+    Backup,
+    Sync,
+}
+
+/// A named backup job.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    pub drive: Option<String>,
+    pub mode: ProfileMode,
+    pub include_hidden: Option<bool>,
+    pub conflict: Option<ConflictPolicy>,
+    pub hash: Option<HashAlgorithm>,
+    pub verify: Option<VerifyMode>,
+    pub xattrs: Option<bool>,
+    pub acls: Option<bool>,
+    pub fs_compat: Option<FsCompat>,
+    pub mtime_window: Option<MtimeWindow>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub min_size: Option<String>,
+    pub max_size: Option<String>,
+    pub newer_than: Option<String>,
+    pub older_than: Option<String>,
+}
+
+/// The contents of `profiles.toml`.
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Profiles {
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// The outcome of running a profile, used for the summary table of `ebod run`.
+pub struct ProfileResult {
+    pub name: String,
+    pub mode: ProfileMode,
+    pub result: Result<BackupSummary, String>,
+    pub duration: Duration,
+}
+
+/// The state of a profile that is kept between runs, in `~/.local/state/ebod/profiles/<name>.json` (or the platform's
+/// equivalent). It is written after every run, so that scheduled runs can be checked with `ebod schedule list`.
+///
+/// # Members
+/// * `last_run: u64` -> The timestamp in seconds when the last run finished
+/// * `ok: bool` -> Whether the last run succeeded
+/// * `error: Option<String>` -> The error of the last run, if it failed
+/// * `summary: Option<BackupSummary>` -> The counts of the last run, if it succeeded
+/// * `duration_secs: f64` -> How long the last run took
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ProfileState {
+    pub last_run: u64,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub summary: Option<BackupSummary>,
+    pub duration_secs: f64,
+}
+
+impl ProfileState {
+    /// The location of the state of the profile `name` in the state directory `dir`. An error if `name` isn't a plain
+    /// name, which could point outside of `dir`.
+    pub fn path_in(dir: &Path, name: &str) -> Result<PathBuf, String> {
+        schedule::check_name(name)?;
+        Ok(dir.join(format!("{}.json", name)))
+    }
+
+    /// Same as `path_in`, in the default state directory.
+    pub fn path(name: &str) -> Result<PathBuf, String> {
+        ProfileState::path_in(&default_state_dir().ok_or(String::from("Couldn't find the state directory"))?, name)
+    }
+
+    /// Loads the state of the profile `name` from the state directory `dir`. `None` if the profile never ran.
+    pub fn load_in(dir: &Path, name: &str) -> Option<ProfileState> {
+        let content = fs::read_to_string(ProfileState::path_in(dir, name).ok()?).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Same as `load_in`, from the default state directory.
+    pub fn load(name: &str) -> Option<ProfileState> {
+        ProfileState::load_in(&default_state_dir()?, name)
+    }
+
+    /// Saves the state of the profile `name` into the state directory `dir`.
+    pub fn save_in(&self, dir: &Path, name: &str) -> Result<(), String> {
+        let path = ProfileState::path_in(dir, name)?;
+        fs::create_dir_all(dir).map_err(|_| format!("Couldn't create {}", dir.to_string_lossy()))?;
+        let data = serde_json::to_string_pretty(self).map_err(|_| String::from("Error during serializing the profile state"))?;
+        fs::write(&path, data).map_err(|_| format!("Couldn't write {}", path.to_string_lossy()))
+    }
+}
+
+/// The default directory of the states of the profiles: `~/.local/state/ebod/profiles` (or the platform's
+/// equivalent).
+pub fn default_state_dir() -> Option<PathBuf> {
+    dirs::state_dir().or_else(dirs::data_local_dir).map(|dir| dir.join("ebod").join("profiles"))
+}
+
+/// The default location of the profiles: `~/.config/ebod/profiles.toml` (or the platform's equivalent).
+pub fn default_profiles_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("ebod").join("profiles.toml"))
+}
+
+/// Expands a leading `~` into the home directory of the user.
+pub fn expand_home(path: &Path) -> PathBuf {
+    if let Ok(rest) = path.strip_prefix("~")
+        && let Some(home) = dirs::home_dir()
+    {
+        return home.join(rest);
+    }
+    path.to_path_buf()
+}
+
+impl Profiles {
+    /// Loads the profiles from `path`.
+    pub fn load(path: &Path) -> Result<Profiles, String> {
+        let content = fs::read_to_string(path).map_err(|_| format!("Couldn't read the profiles from {}", path.to_string_lossy()))?;
+        toml::from_str(&content).map_err(|e| format!("Error in parsing {}: {}", path.to_string_lossy(), e.message()))
+    }
+}
+
+impl Profile {
+    /// Builds the filters of the profile.
+    pub fn filters(&self) -> Result<Filters, String> {
+        let min_size = self.min_size.as_deref().map(parse_size).transpose()?;
+        let max_size = self.max_size.as_deref().map(parse_size).transpose()?;
+        Filters::new(&self.include, &self.exclude, min_size, max_size, self.newer_than.as_deref(), self.older_than.as_deref())
+    }
+
+    /// Merges the options of the profile with `.ebod/config.toml` of the source directory. The profile takes precedence.
+    pub fn config(&self, src: &Path) -> Result<DirConfig, String> {
+        let mut config = DirConfig::load(src)?;
+        if let Some(include_hidden) = self.include_hidden {
+            config.include_hidden = include_hidden;
+        }
+        if let Some(conflict) = self.conflict {
+            config.conflict = conflict;
+        }
+        if let Some(hash) = self.hash {
+            config.hash = hash;
+        }
+        if let Some(verify) = self.verify {
+            config.verify = verify;
+        }
+        if let Some(xattrs) = self.xattrs {
+            config.xattrs = xattrs;
+        }
+        if let Some(acls) = self.acls {
+            config.acls = acls;
+        }
+        if let Some(fs_compat) = self.fs_compat {
+            config.fs_compat = fs_compat;
+        }
+        if let Some(mtime_window) = self.mtime_window {
+            config.mtime_window = mtime_window;
+        }
+        Ok(config)
+    }
+
+    /// Resolves the destination of the profile. With `drive`, a relative `dest` is joined to the mount point of the
+    /// drive, and an absolute `dest` is checked to be on the drive. The drives are looked up in `mountinfo`.
+    pub fn destination(&self, mountinfo: &Path) -> Result<PathBuf, String> {
+        let dest = expand_home(&self.dest);
+        let Some(drive) = &self.drive else {
+            return Ok(dest);
+        };
+        if dest.is_relative() {
+            let root = find_drive(drive, mountinfo).ok_or(format!("The drive {} is not mounted", drive))?;
+            return Ok(root.join(dest));
+        }
+        check_drive(&dest, drive, mountinfo)?;
+        Ok(dest)
+    }
+
+    /// Runs the profile. Unlike `ebod backup`, the destination must already exist, so that a backup never ends up in
+    /// the mount point of a drive that isn't plugged in.
+    ///
+    /// # Input
+    /// * `mountinfo: &Path` -> The `mountinfo` file in which the drive of the profile is looked up
+    ///
+    /// # Output: `Result<BackupSummary, String>`
+    pub fn run(&self, mountinfo: &Path) -> Result<BackupSummary, String> {
+        let src = expand_home(&self.src);
+        let dest = self.destination(mountinfo)?;
+        if !src.is_dir() {
+            return Err(format!("The source directory {} doesn't exist", src.to_string_lossy()));
+        }
+        if !dest.is_dir() {
+            return Err(format!("The destination directory {} doesn't exist", dest.to_string_lossy()));
+        }
+        let filters = self.filters()?;
+        let config = self.config(&src)?;
+        let report = match self.mode {
+            ProfileMode::Backup => {
+                Backup::new(&src, &dest).options(BackupOptions::from_config(&config).filters(filters)).run(&OutputReporter)?
+            }
+            ProfileMode::Sync => TwoWaySync::new(&src, &dest).options(SyncOptions::from_config(&config).filters(filters)).run(&OutputReporter)?,
+        };
+        Ok(report.summary)
+    }
+}
+
+/// Runs the profile `name` and measures how long it took. The drive of the profile is looked up in `mountinfo`, and
+/// the state of the run is saved into `state_dir`.
+pub fn run_profile(name: &str, profile: &Profile, mountinfo: &Path, state_dir: Option<&Path>) -> ProfileResult {
+    let since = output::mark();
+    log(LogType::Info, &format!("Running profile {}", name));
+    let start = Instant::now();
+    let result = profile.run(mountinfo);
+    if let Err(err) = &result {
+        log(LogType::Err, &format!("Profile {} failed: {}", name, err));
+    }
+    let duration = start.elapsed();
+    let state = ProfileState {
+        last_run: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        ok: result.is_ok(),
+        error: result.as_ref().err().cloned(),
+        summary: result.as_ref().ok().cloned(),
+        duration_secs: duration.as_secs_f64(),
+    };
+    let saved = state_dir.ok_or(String::from("Couldn't find the state directory")).and_then(|dir| state.save_in(dir, name));
+    if let Err(err) = saved {
+        log(LogType::Err, &format!("Couldn't record the state of profile {}: {}", name, err));
+    }
+    if let Ok(dest) = profile.destination(mountinfo)
+        && dest.is_dir()
+        && let Err(err) = output::save_history(&dest, &format!("profile-{}", name), since)
+    {
+        log(LogType::Err, &err);
+    }
+    output::end_history();
+    ProfileResult { name: name.to_string(), mode: profile.mode, result, duration }
+}
+
+/// Prints a table with one row per profile that was run.
+pub fn print_summary_table(results: &[ProfileResult]) {
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0).max("Profile".len());
+    output::print("");
+    output::print(
+        &format!("{:<width$}  {:<6}  {:<6}  {:>7}  {:>7}  {:>9}  {:>9}  {:>12}  {:>8}", "Profile", "Mode", "Status", "Copied", "Renamed", "Conflicts", "Unchanged", "Bytes", "Time")
+            .bold()
+            .to_string(),
+    );
+    for r in results {
+        let mode = if r.mode == ProfileMode::Sync { "sync" } else { "backup" };
+        let time = format!("{:.1}s", r.duration.as_secs_f64());
+        match &r.result {
+            Ok(s) => output::print(&format!(
+                "{:<width$}  {:<6}  {}  {:>7}  {:>7}  {:>9}  {:>9}  {:>12}  {:>8}",
+                r.name,
+                mode,
+                format!("{:<6}", "ok").green(),
+                s.copied + s.overwritten,
+                s.renamed,
+                s.kept,
+                s.identical,
+                s.bytes,
+                time
+            )),
+            Err(_) => output::print(&format!(
+                "{:<width$}  {:<6}  {}  {:>7}  {:>7}  {:>9}  {:>9}  {:>12}  {:>8}",
+                r.name,
+                mode,
+                format!("{:<6}", "failed").red(),
+                "-",
+                "-",
+                "-",
+                "-",
+                "-",
+                time
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_files_only_take_plain_names() {
+        for name in ["../escape", "a/b", "", "/etc/passwd"] {
+            assert!(ProfileState::path(name).is_err(), "{}", name);
+            assert!(ProfileState::default().save_in(&std::env::temp_dir(), name).is_err());
+        }
+        if let Ok(path) = ProfileState::path("photos-daily") {
+            assert!(path.ends_with("ebod/profiles/photos-daily.json"));
+        }
+    }
+}
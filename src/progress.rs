@@ -0,0 +1,101 @@
+//! Progress display of a backup.
+//!
+//! The totals come from the plan: the number of files to copy and the sum of their sizes. The overall bar shows the
+//! files done, the bytes done, the throughput and the ETA. Files of at least `LARGE_FILE` bytes get a bar of their
+//! own while they are being copied.
+//!
+//! The bars are drawn on stderr, and only when it is a terminal and the output isn't `--output json|ndjson`.
+use std::io::IsTerminal;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::output;
+
+/// Files of at least this size get their own progress bar.
+pub const LARGE_FILE: u64 = 64 * 1024 * 1024;
+
+// The bars being drawn, so that log lines can be printed above them
+static ACTIVE: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// The progress of the files copied by a backup.
+pub struct Progress {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    files_total: u64,
+    files_done: AtomicU64,
+}
+
+// Whether the bars can be drawn
+fn enabled() -> bool {
+    std::io::stderr().is_terminal() && !output::is_structured()
+}
+
+impl Progress {
+    /// Starts the display for `files` files with a total of `bytes` bytes.
+    pub fn new(files: u64, bytes: u64) -> Progress {
+        let target = if enabled() { ProgressDrawTarget::stderr() } else { ProgressDrawTarget::hidden() };
+        let multi = MultiProgress::with_draw_target(target);
+        let overall = multi.add(ProgressBar::new(bytes));
+        overall.set_style(
+            ProgressStyle::with_template("{msg} [{bar:30}] {binary_bytes}/{binary_total_bytes} {binary_bytes_per_sec} ETA {eta}")
+                .unwrap_or_else(|_| ProgressStyle::default_bar())
+                .progress_chars("=> "),
+        );
+        overall.set_message(format!("0/{} files", files));
+        if let Ok(mut active) = ACTIVE.lock() {
+            *active = Some(multi.clone());
+        }
+        Progress { multi, overall, files_total: files, files_done: AtomicU64::new(0) }
+    }
+
+    /// Adds a bar for a large file. The bar is hidden for files smaller than `LARGE_FILE`.
+    pub fn file_bar(&self, name: &str, length: u64) -> ProgressBar {
+        if length < LARGE_FILE {
+            return ProgressBar::hidden();
+        }
+        let bar = self.multi.add(ProgressBar::new(length));
+        bar.set_style(
+            ProgressStyle::with_template("  {wide_msg} [{bar:30}] {binary_bytes}/{binary_total_bytes}")
+                .unwrap_or_else(|_| ProgressStyle::default_bar())
+                .progress_chars("=> "),
+        );
+        bar.set_message(name.to_string());
+        bar
+    }
+
+    /// Counts `bytes` more bytes as copied.
+    pub fn add_bytes(&self, bytes: u64) {
+        self.overall.inc(bytes);
+    }
+
+    /// Counts one more file as done.
+    pub fn file_done(&self) {
+        let done = self.files_done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.overall.set_message(format!("{}/{} files", done, self.files_total));
+    }
+
+    /// Removes the bars from the terminal.
+    pub fn finish(&self) {
+        self.overall.finish_and_clear();
+        if let Ok(mut active) = ACTIVE.lock() {
+            *active = None;
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Runs `f`, which prints something, with the bars hidden, so that the printed lines stay above the bars.
+pub fn suspend<F: FnOnce()>(f: F) {
+    let active = ACTIVE.lock().ok().and_then(|active| active.clone());
+    match active {
+        Some(multi) => multi.suspend(f),
+        None => f(),
+    }
+}
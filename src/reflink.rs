@@ -0,0 +1,150 @@
+//! Fast copies between local directories.
+//!
+//! When the source and the destination are both on the local filesystem, a file is copied with the fastest method
+//! that works:
+//! 1. A reflink (`FICLONE`): on copy-on-write filesystems like Btrfs and XFS, the copy shares the blocks of the source
+//!    when both are on the same filesystem, so nothing is copied and no space is used until either of them changes
+//! 2. For a sparse file, a copy of its data ranges that keeps its holes (`sparse::copy`)
+//! 3. `copy_file_range`, which copies in the kernel without going through ebod
+//! 4. A copy read and written by ebod
+//!
+//! Reflinks and `copy_file_range` are only tried on Linux. The method used for every file is in the events of the
+//! backup, and the bytes that were cloned instead of copied are counted in the summary.
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+
+use crate::{CopyMethod, Copied, sparse};
+
+/// Copies the local file `src` into `dest` with the fastest method that works. `sparse` tells whether `src` has holes
+/// to keep. `on_copied` is called with the number of bytes of `src` done as they are done.
+///
+/// # Output: `io::Result<Copied>`
+/// The number of bytes written and the method used, or the error that stopped the copy.
+pub fn copy(src: &Path, dest: &Path, sparse: bool, mut on_copied: impl FnMut(u64)) -> io::Result<Copied> {
+    let mut input = File::open(src)?;
+    let length = input.metadata()?.len();
+    let mut output = File::create(dest)?;
+
+    if clone(&input, &output)? {
+        on_copied(length);
+        return Ok(Copied { bytes: length, method: CopyMethod::Reflink });
+    }
+    if sparse && let Some(bytes) = sparse::copy(src, dest, &mut on_copied)? {
+        return Ok(Copied { bytes, method: CopyMethod::Sparse });
+    }
+    if let Some(bytes) = copy_range(&input, &output, &mut on_copied)? {
+        return Ok(Copied { bytes, method: CopyMethod::CopyFileRange });
+    }
+
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut bytes = 0;
+    loop {
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        output.write_all(&buffer[..read])?;
+        bytes += read as u64;
+        on_copied(read as u64);
+    }
+    output.flush()?;
+    Ok(Copied { bytes, method: CopyMethod::Userspace })
+}
+
+// The errors of `FICLONE` and `copy_file_range` that mean that they can't be used between these two files, so that
+// the next method is tried
+#[cfg(target_os = "linux")]
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EXDEV | libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::ENOSYS | libc::EPERM | libc::EBADF)
+    )
+}
+
+// Makes `output` a reflink of `input`. `Ok(false)` if the filesystem can't.
+#[cfg(target_os = "linux")]
+fn clone(input: &File, output: &File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: both descriptors are owned by open files, and FICLONE takes the source descriptor as its argument
+    if unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if is_unsupported(&err) { Ok(false) } else { Err(err) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone(_input: &File, _output: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+// Copies `input` into `output` with `copy_file_range`. `Ok(None)` if nothing could be copied with it.
+#[cfg(target_os = "linux")]
+fn copy_range(input: &File, output: &File, on_copied: &mut impl FnMut(u64)) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    const CHUNK: usize = 64 * 1024 * 1024;
+    let mut bytes = 0;
+    loop {
+        // SAFETY: both descriptors are owned by open files, and the null offsets make the call use and move the
+        // offsets of the descriptors
+        let copied = unsafe { libc::copy_file_range(input.as_raw_fd(), std::ptr::null_mut(), output.as_raw_fd(), std::ptr::null_mut(), CHUNK, 0) };
+        match copied {
+            0 => return Ok(Some(bytes)),
+            copied if copied > 0 => {
+                bytes += copied as u64;
+                on_copied(copied as u64);
+            },
+            _ => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    ErrorKind::Interrupted => {},
+                    // Nothing was written yet, so the copy can be done by ebod
+                    _ if bytes == 0 && is_unsupported(&err) => return Ok(None),
+                    _ => return Err(err),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_range(_input: &File, _output: &File, _on_copied: &mut impl FnMut(u64)) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn copies_are_identical_whatever_the_method() {
+        let root = std::env::temp_dir().join(format!("ebod-reflink-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let data: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+        fs::write(root.join("src"), &data).unwrap();
+
+        for (name, sparse) in [("plain", false), ("sparse", true)] {
+            let mut reported = 0;
+            let copied = copy(&root.join("src"), &root.join(name), sparse, |done| reported += done).unwrap();
+            assert_eq!(fs::read(root.join(name)).unwrap(), data);
+            assert_eq!(reported, data.len() as u64);
+            assert_eq!(copied.bytes, data.len() as u64, "{}", copied.method);
+            assert!(matches!(copied.method, CopyMethod::Reflink | CopyMethod::Sparse | CopyMethod::CopyFileRange | CopyMethod::Userspace));
+            assert_eq!(copied.cloned(), if copied.method == CopyMethod::Reflink { copied.bytes } else { 0 });
+            if !sparse {
+                assert_ne!(copied.method, CopyMethod::Sparse);
+            }
+        }
+
+        let missing = copy(&root.join("missing"), &root.join("copy"), false, |_| {});
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::NotFound);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
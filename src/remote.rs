@@ -0,0 +1,244 @@
+//! Remote destinations.
+//!
+//! A destination given as a URL is reached through a `Vfs` backend instead of the local filesystem:
+//! * `sftp://[USER@]HOST[:PORT]/PATH` -> `SftpFs`. `sftp://HOST/~/PATH` is relative to the home directory
+//! * `s3://BUCKET[/PREFIX]` -> `S3Fs`, configured with the `AWS_*` and `EBOD_S3_ENDPOINT` environment variables
+//! * `dav://[USER@]HOST[:PORT]/PATH` or `davs://…` (HTTPS) -> `WebDavFs`. `webdav://` and `webdavs://` are accepted too
+//!
+//! The source stays local. The destination is indexed through the backend, only the planned files are uploaded, and
+//! the `.ebod/metadata.json` of the destination is kept up to date on the remote side after every backup. Uploaded
+//! files get the modified time of their source, so that they are seen as identical by the next backup. Backends that
+//! can't set modified times, like S3, record the time of the source in the index instead, and the index is trusted
+//! rather than listing the destination again.
+use std::path::{Path, PathBuf};
+
+use crate::config::DirConfig;
+use crate::filter::Filters;
+use crate::output::Reporter;
+use crate::plan::{Action, PlannedEntry, plan_dirs};
+use crate::s3::{S3Fs, S3Target};
+use crate::sftp::{SftpFs, SftpTarget};
+use crate::vfs::{LocalFs, Vfs};
+use crate::webdav::{DavTarget, WebDavFs};
+use crate::xattrs::Preserve;
+use crate::{BackupSummary, EntryType, FileEntry, LogType, entry_from_stat, execute_plan_in, log, read_metadata_in, recursive_listing_in, write_metadata_in};
+
+/// A remote destination parsed from a URL.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Remote {
+    Sftp { target: SftpTarget, path: PathBuf },
+    S3 { bucket: String, path: PathBuf },
+    WebDav { target: DavTarget, path: PathBuf },
+}
+
+// Splits `[USER@]HOST[:PORT]`. IPv6 addresses are written in brackets: `[::1]:2222`.
+fn parse_authority(authority: &str, url: &str) -> Result<(Option<String>, String, Option<u16>), String> {
+    let (user, host_port) = match authority.rsplit_once('@') {
+        Some((user, host_port)) => (Some(user.to_string()), host_port),
+        None => (None, authority),
+    };
+    let (host, port) = if let Some(rest) = host_port.strip_prefix('[') {
+        let (host, after) = rest.split_once(']').ok_or_else(|| format!("Invalid host in {}", url))?;
+        (host, after.strip_prefix(':'))
+    } else {
+        match host_port.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(format!("No host in {}", url));
+    }
+    let port = match port {
+        Some(port) => Some(port.parse().map_err(|_| format!("Invalid port {} in {}", port, url))?),
+        None => None,
+    };
+    Ok((user, host.to_string(), port))
+}
+
+impl Remote {
+    /// Parses a destination given on the command line.
+    ///
+    /// # Output: `Option<Result<Remote, String>>`
+    /// `None` if `dest` is a local path, or the remote destination, or an error if the URL is invalid.
+    pub fn parse(dest: &str) -> Option<Result<Remote, String>> {
+        if let Some(rest) = dest.strip_prefix("s3://") {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            if bucket.is_empty() {
+                return Some(Err(format!("No bucket in {}", dest)));
+            }
+            let prefix = prefix.trim_matches('/');
+            let path = if prefix.is_empty() { PathBuf::from(".") } else { PathBuf::from(prefix) };
+            return Some(Ok(Remote::S3 { bucket: bucket.to_string(), path }));
+        }
+        for (scheme, secure) in [("dav://", false), ("davs://", true), ("webdav://", false), ("webdavs://", true)] {
+            if let Some(rest) = dest.strip_prefix(scheme) {
+                let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+                let path = PathBuf::from(format!("/{}", path.trim_end_matches('/')));
+                return Some(parse_authority(authority, dest).map(|(user, host, port)| {
+                    let host = if host.contains(':') { format!("[{}]", host) } else { host };
+                    let host = match port {
+                        Some(port) => format!("{}:{}", host, port),
+                        None => host,
+                    };
+                    Remote::WebDav { target: DavTarget { secure, host, user }, path }
+                }));
+            }
+        }
+        let rest = dest.strip_prefix("sftp://")?;
+        let (authority, path) = match rest.split_once('/') {
+            Some((authority, path)) => (authority, format!("/{}", path)),
+            None => (rest, String::from("/~")),
+        };
+        // SFTP servers resolve relative paths from the home directory
+        let path = match path.strip_prefix("/~") {
+            Some("") => PathBuf::from("."),
+            Some(home) if home.starts_with('/') => PathBuf::from(&home[1..]),
+            _ => PathBuf::from(&path),
+        };
+        Some(parse_authority(authority, dest).map(|(user, host, port)| Remote::Sftp { target: SftpTarget { user, host, port }, path }))
+    }
+
+    /// The directory of the destination on the remote side.
+    pub fn root(&self) -> &Path {
+        match self {
+            Remote::Sftp { path, .. } | Remote::S3 { path, .. } | Remote::WebDav { path, .. } => path,
+        }
+    }
+
+    /// Opens the backend of the destination.
+    pub fn connect(&self) -> Result<Box<dyn Vfs>, String> {
+        match self {
+            Remote::Sftp { target, .. } => Ok(Box::new(SftpFs::connect(target)?)),
+            Remote::S3 { bucket, .. } => Ok(Box::new(S3Fs::connect(S3Target::from_env(bucket)?)?)),
+            Remote::WebDav { target, path } => Ok(Box::new(WebDavFs::connect(target, path)?)),
+        }
+    }
+
+    /// Whether the destination is listed before every backup. Otherwise its stored index is trusted when it exists.
+    pub fn relists(&self) -> bool {
+        match self {
+            Remote::Sftp { .. } => true,
+            Remote::S3 { .. } | Remote::WebDav { .. } => false,
+        }
+    }
+}
+
+/// The metadata of the destination `dest` of `vfs`: listed through the backend if `relist` is set or if there is no
+/// stored index, and read from its `.ebod/metadata.json` otherwise. The filters aren't applied, since the listing is
+/// stored back as the index of the destination.
+pub fn dest_index<V: Vfs + ?Sized>(vfs: &V, dest: &Path, include_hidden: bool, relist: bool) -> Vec<FileEntry> {
+    if !relist && let Ok(index) = read_metadata_in(vfs, dest) {
+        return index;
+    }
+    let mut data = vec![];
+    recursive_listing_in(vfs, dest, dest, &mut data, include_hidden, Preserve::default(), &Filters::default());
+    data
+}
+
+// The files that the plan uploads although the destination already holds their contents under the same name become
+// `Identical`. Returns the names of these files.
+fn skip_same_contents<V: Vfs + ?Sized>(plan: &mut [PlannedEntry], src: &Path, dest_vfs: &V, dest: &Path) -> Vec<String> {
+    let mut skipped = vec![];
+    for planned in plan.iter_mut() {
+        let upload = matches!(planned.action, Action::Copy | Action::Overwrite | Action::CopyAs(_));
+        let name = &planned.entry.name;
+        if upload && planned.entry.e_type == EntryType::File && dest_vfs.same_contents(&dest.join(name), &src.join(name)) {
+            skipped.push(name.clone());
+            planned.action = Action::Identical;
+        }
+    }
+    skipped
+}
+
+// The settings of a backup to a remote destination, which has no extended attributes
+fn remote_config(config: &DirConfig) -> DirConfig {
+    DirConfig { xattrs: false, acls: false, ..config.clone() }
+}
+
+/// Plans the backup of the local `src` into the destination `dest` of `dest_vfs`. `src` must have been initialized.
+pub fn plan_to<V: Vfs + ?Sized>(src: &Path, dest_vfs: &V, dest: &Path, filters: &Filters, config: &DirConfig, relist: bool) -> Vec<PlannedEntry> {
+    let config = &remote_config(config);
+    let src_meta = read_metadata_in(&LocalFs, src).unwrap_or(vec![]);
+    let dest_meta = dest_index(dest_vfs, dest, config.include_hidden, relist);
+    let mut plan = plan_dirs(&src_meta, &dest_meta, dest_vfs, dest, "src", filters, config);
+    skip_same_contents(&mut plan, src, dest_vfs, dest);
+    plan
+}
+
+/// Backs up the local `src` into the destination `dest` of `dest_vfs`, and stores the updated index of the
+/// destination in its `.ebod/metadata.json`. `src` must have been initialized.
+///
+/// # Input
+/// * `src: &Path` -> The local source directory
+/// * `dest_vfs: &V`, `dest: &Path` -> The backend and the directory of the destination. It is created if needed
+/// * `filters: &Filters` -> The filters that leave entries out of the backup
+/// * `config: &DirConfig` -> The settings of the backup
+/// * `relist: bool` -> Whether the destination is listed instead of trusting its stored index
+/// * `reporter: &dyn Reporter` -> Receives an event for every entry of the source
+///
+/// # Output: `Result<BackupSummary, String>`
+/// The counts of what was done, or the error that stopped the backup.
+///
+/// # Rules followed:
+/// 1. The index is updated with the entries that were uploaded, even if the backup stopped on an error.
+/// 2. The uploaded files get the modified time of their source, in the destination if the backend supports it and
+///    in the index in any case.
+/// 3. The files whose contents the backend already holds under the same name aren't uploaded again.
+pub fn backup_to<V: Vfs + ?Sized>(
+    src: &Path,
+    dest_vfs: &V,
+    dest: &Path,
+    filters: &Filters,
+    config: &DirConfig,
+    relist: bool,
+    reporter: &dyn Reporter,
+) -> Result<BackupSummary, String> {
+    dest_vfs.create_dir_all(dest).map_err(|err| format!("Couldn't create {}: {}", dest.to_string_lossy(), err))?;
+    // The remote backends have no extended attributes, and the index of the destination records none
+    if config.preserve().any() {
+        log(LogType::Info, "Extended attributes and ACLs aren't supported by remote destinations, so they aren't copied");
+    }
+    let config = &remote_config(config);
+    let src_meta = read_metadata_in(&LocalFs, src).unwrap_or(vec![]);
+    let mut dest_meta = dest_index(dest_vfs, dest, config.include_hidden, relist);
+    let mut plan = plan_dirs(&src_meta, &dest_meta, dest_vfs, dest, "src", filters, config);
+    let skipped = skip_same_contents(&mut plan, src, dest_vfs, dest);
+
+    // The names the planned entries get in the destination
+    let changed: Vec<(String, FileEntry)> = plan
+        .iter()
+        .filter_map(|planned| match &planned.action {
+            Action::Copy | Action::Overwrite | Action::CreateDir => Some((planned.entry.name.clone(), planned.entry.clone())),
+            Action::CopyAs(name) => Some((name.clone(), planned.entry.clone())),
+            Action::Identical if skipped.contains(&planned.entry.name) => Some((planned.entry.name.clone(), planned.entry.clone())),
+            Action::KeepDest | Action::UpdateAttrs | Action::Identical | Action::Excluded(_) => None,
+        })
+        .collect();
+    let result = execute_plan_in(&LocalFs, src, dest_vfs, dest, plan, config, reporter);
+
+    for (name, entry) in changed {
+        let path = dest.join(&name);
+        if entry.e_type == EntryType::File {
+            let _ = dest_vfs.set_times(&path, entry.modified());
+        }
+        let Ok(stat) = dest_vfs.stat(&path) else {
+            continue;
+        };
+        let mut updated = entry_from_stat(&path, dest, &stat);
+        if entry.e_type == EntryType::File {
+            updated.modified_at = entry.modified_at;
+            updated.modified_nanos = entry.modified_nanos;
+        }
+        match dest_meta.iter_mut().find(|e| e.name == updated.name) {
+            Some(existing) => *existing = updated,
+            None => dest_meta.push(updated),
+        }
+    }
+    if let Err(err) = write_metadata_in(dest_vfs, dest, &dest_meta) {
+        log(LogType::Err, &err);
+    } else {
+        log(LogType::Ok, &format!("Index of the destination updated at {}", dest.join(".ebod/metadata.json").to_string_lossy()));
+    }
+    result
+}
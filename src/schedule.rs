@@ -0,0 +1,349 @@
+//! Scheduled runs of profiles.
+//!
+//! `ebod schedule add` installs a user-level systemd timer (`~/.config/systemd/user/ebod-<profile>.timer` and its
+//! `.service`) that runs `ebod run <profile>`. When systemd isn't available, a crontab entry is written instead. The
+//! crontab entries of ebod are recognised by their trailing `# ebod:<profile>` comment.
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::{LogType, log};
+
+const UNIT_PREFIX: &str = "ebod-";
+const CRON_MARKER: &str = "# ebod:";
+
+/// When a profile is run.
+///
+/// # Values in Enum
+/// * `Hourly` -> At the start of every hour
+/// * `Daily(u8, u8)` -> Every day at the hour and minute
+/// * `Weekly(String, u8, u8)` -> Every week on the day (`Mon` to `Sun`) at the hour and minute
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Schedule {
+    Hourly,
+    Daily(u8, u8),
+    Weekly(String, u8, u8),
+}
+
+/// Where a schedule is installed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Backend {
+    Systemd,
+    Cron,
+}
+
+/// A schedule found by `list_schedules`.
+#[derive(Debug, Clone)]
+pub struct ScheduledProfile {
+    pub profile: String,
+    pub backend: Backend,
+    pub when: String,
+}
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Parses a time of the day given as `HH:MM`.
+pub fn parse_time(time: &str) -> Result<(u8, u8), String> {
+    let err = || format!("Invalid time {}. Expected HH:MM, like 02:00", time);
+    let (hour, minute) = time.split_once(':').ok_or_else(err)?;
+    let hour: u8 = hour.parse().map_err(|_| err())?;
+    let minute: u8 = minute.parse().map_err(|_| err())?;
+    if hour > 23 || minute > 59 {
+        return Err(err());
+    }
+    Ok((hour, minute))
+}
+
+/// Parses a day of the week and a time given as `DAY HH:MM`, like `Sun 03:30`.
+pub fn parse_weekly(spec: &str) -> Result<Schedule, String> {
+    let (day, time) = spec.trim().split_once(' ').ok_or(format!("Invalid schedule {}. Expected DAY HH:MM, like Sun 03:30", spec))?;
+    let day = DAYS
+        .iter()
+        .find(|d| day.to_lowercase().starts_with(&d.to_lowercase()))
+        .ok_or(format!("Invalid day {}. Expected one of {}", day, DAYS.join(", ")))?;
+    let (hour, minute) = parse_time(time.trim())?;
+    Ok(Schedule::Weekly(day.to_string(), hour, minute))
+}
+
+impl Schedule {
+    /// The `OnCalendar=` expression of the systemd timer.
+    pub fn on_calendar(&self) -> String {
+        match self {
+            Schedule::Hourly => String::from("hourly"),
+            Schedule::Daily(hour, minute) => format!("*-*-* {:02}:{:02}:00", hour, minute),
+            Schedule::Weekly(day, hour, minute) => format!("{} *-*-* {:02}:{:02}:00", day, hour, minute),
+        }
+    }
+
+    /// The time fields of the crontab entry.
+    pub fn cron(&self) -> String {
+        match self {
+            Schedule::Hourly => String::from("0 * * * *"),
+            Schedule::Daily(hour, minute) => format!("{} {} * * *", minute, hour),
+            Schedule::Weekly(day, hour, minute) => {
+                format!("{} {} * * {}", minute, hour, DAYS.iter().position(|d| d == day).unwrap_or(0))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Hourly => write!(f, "hourly"),
+            Schedule::Daily(hour, minute) => write!(f, "daily at {:02}:{:02}", hour, minute),
+            Schedule::Weekly(day, hour, minute) => write!(f, "every {} at {:02}:{:02}", day, hour, minute),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Systemd => f.pad("systemd"),
+            Backend::Cron => f.pad("cron"),
+        }
+    }
+}
+
+/// Checks whether the user instance of systemd can be reached.
+pub fn systemd_available() -> bool {
+    Command::new("systemctl")
+        .args(["--user", "show-environment"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+// Profile names end up in unit names, in shell commands and in the names of the state files, so only plain names are
+// accepted
+pub(crate) fn check_name(profile: &str) -> Result<(), String> {
+    if profile.is_empty() || !profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return Err(format!("The profile name {} can only have letters, digits, '-', '_' and '.'", profile));
+    }
+    Ok(())
+}
+
+fn unit_dir() -> Result<PathBuf, String> {
+    dirs::config_dir().map(|dir| dir.join("systemd").join("user")).ok_or(String::from("Couldn't find the configuration directory"))
+}
+
+// The command line that runs the profile, with the path of the running ebod binary
+fn run_command(profile: &str, profiles_path: Option<&Path>) -> Result<Vec<String>, String> {
+    let exe = std::env::current_exe().map_err(|_| String::from("Couldn't find the path of the ebod binary"))?;
+    let mut command = vec![exe.to_string_lossy().to_string(), String::from("run"), profile.to_string()];
+    if let Some(path) = profiles_path {
+        let path = fs::canonicalize(path).map_err(|_| format!("Couldn't find {}", path.to_string_lossy()))?;
+        command.push(String::from("--profiles"));
+        command.push(path.to_string_lossy().to_string());
+    }
+    Ok(command)
+}
+
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// The `ExecStart=` value of the service that runs `command`. systemd expands `%` specifiers and `$` variables even in
+// quoted arguments, so they are doubled
+fn exec_start(command: &[String]) -> String {
+    let exec: Vec<String> = command
+        .iter()
+        .map(|arg| format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%").replace('$', "$$")))
+        .collect();
+    exec.join(" ")
+}
+
+// The command of the crontab entry that runs `command`. cron turns the `%` that aren't escaped into newlines, even in
+// quoted arguments
+fn cron_command(command: &[String]) -> String {
+    let quoted: Vec<String> = command.iter().map(|arg| quote(arg).replace('%', "\\%")).collect();
+    quoted.join(" ")
+}
+
+fn systemctl(args: &[&str]) -> Result<(), String> {
+    let status = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .status()
+        .map_err(|_| String::from("Couldn't run systemctl"))?;
+    if status.success() { Ok(()) } else { Err(format!("systemctl --user {} failed", args.join(" "))) }
+}
+
+fn read_crontab() -> Result<String, String> {
+    let output = Command::new("crontab").arg("-l").output().map_err(|_| String::from("Neither systemd nor crontab is available"))?;
+    // `crontab -l` fails when the user has no crontab yet
+    Ok(if output.status.success() { String::from_utf8_lossy(&output.stdout).to_string() } else { String::new() })
+}
+
+fn write_crontab(content: &str) -> Result<(), String> {
+    let mut child = Command::new("crontab")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|_| String::from("Couldn't run crontab"))?;
+    child
+        .stdin
+        .take()
+        .ok_or("Couldn't write to crontab")?
+        .write_all(content.as_bytes())
+        .map_err(|_| String::from("Couldn't write to crontab"))?;
+    let status = child.wait().map_err(|_| String::from("Couldn't run crontab"))?;
+    if status.success() { Ok(()) } else { Err(String::from("crontab refused the new entries")) }
+}
+
+// The crontab without the entry of `profile`
+fn without_entry(crontab: &str, profile: &str) -> String {
+    let marker = format!("{}{}", CRON_MARKER, profile);
+    crontab.lines().filter(|line| !line.trim_end().ends_with(&marker)).map(|line| format!("{}\n", line)).collect()
+}
+
+/// Schedules the profile. An existing schedule of the profile is replaced.
+///
+/// # Input
+/// * `profile: &str` -> The name of the profile
+/// * `schedule: &Schedule` -> When the profile is run
+/// * `profiles_path: Option<&Path>` -> The profiles file, if it isn't the default one
+///
+/// # Output: `Result<Backend, String>`
+/// Whether the schedule was installed as a systemd timer or as a crontab entry.
+pub fn add_schedule(profile: &str, schedule: &Schedule, profiles_path: Option<&Path>) -> Result<Backend, String> {
+    check_name(profile)?;
+    let command = run_command(profile, profiles_path)?;
+    if systemd_available() {
+        let dir = unit_dir()?;
+        fs::create_dir_all(&dir).map_err(|_| format!("Couldn't create {}", dir.to_string_lossy()))?;
+        let unit = format!("{}{}", UNIT_PREFIX, profile);
+        let service = format!(
+            "[Unit]\nDescription=ebod backup profile {}\n\n[Service]\nType=oneshot\nExecStart={}\n",
+            profile,
+            exec_start(&command)
+        );
+        let timer = format!(
+            "[Unit]\nDescription=Run the ebod backup profile {} {}\n\n[Timer]\nOnCalendar={}\nPersistent=true\n\n[Install]\nWantedBy=timers.target\n",
+            profile,
+            schedule,
+            schedule.on_calendar()
+        );
+        fs::write(dir.join(format!("{}.service", unit)), service).map_err(|_| format!("Couldn't write the service of {}", unit))?;
+        fs::write(dir.join(format!("{}.timer", unit)), timer).map_err(|_| format!("Couldn't write the timer of {}", unit))?;
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", &format!("{}.timer", unit)])?;
+        Ok(Backend::Systemd)
+    } else {
+        log(LogType::Info, "systemd isn't available. The schedule is written into the crontab");
+        let mut crontab = without_entry(&read_crontab()?, profile);
+        crontab.push_str(&format!("{} {} {}{}\n", schedule.cron(), cron_command(&command), CRON_MARKER, profile));
+        write_crontab(&crontab)?;
+        Ok(Backend::Cron)
+    }
+}
+
+/// Lists the schedules installed by ebod, both the systemd timers and the crontab entries.
+pub fn list_schedules() -> Vec<ScheduledProfile> {
+    let mut scheduled = vec![];
+    if let Ok(dir) = unit_dir()
+        && let Ok(entries) = fs::read_dir(dir)
+    {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(profile) = name.strip_prefix(UNIT_PREFIX).and_then(|rest| rest.strip_suffix(".timer")) {
+                let content = fs::read_to_string(entry.path()).unwrap_or_default();
+                let when = content.lines().find_map(|line| line.strip_prefix("OnCalendar=")).unwrap_or("-").to_string();
+                scheduled.push(ScheduledProfile { profile: profile.to_string(), backend: Backend::Systemd, when });
+            }
+        }
+    }
+    for line in read_crontab().unwrap_or_default().lines() {
+        if let Some((entry, profile)) = line.rsplit_once(CRON_MARKER) {
+            let when = entry.split_whitespace().take(5).collect::<Vec<_>>().join(" ");
+            scheduled.push(ScheduledProfile { profile: profile.trim().to_string(), backend: Backend::Cron, when });
+        }
+    }
+    scheduled.sort_by(|a, b| a.profile.cmp(&b.profile));
+    scheduled
+}
+
+/// Removes the schedule of the profile, from systemd and from the crontab.
+///
+/// # Output: `Result<(), String>`
+/// An error if the profile wasn't scheduled.
+pub fn remove_schedule(profile: &str) -> Result<(), String> {
+    check_name(profile)?;
+    let mut removed = false;
+    let unit = format!("{}{}", UNIT_PREFIX, profile);
+    let dir = unit_dir()?;
+    let timer = dir.join(format!("{}.timer", unit));
+    if timer.exists() {
+        if systemd_available() {
+            systemctl(&["disable", "--now", &format!("{}.timer", unit)])?;
+        }
+        fs::remove_file(&timer).map_err(|_| format!("Couldn't remove {}", timer.to_string_lossy()))?;
+        let _ = fs::remove_file(dir.join(format!("{}.service", unit)));
+        if systemd_available() {
+            systemctl(&["daemon-reload"])?;
+        }
+        removed = true;
+    }
+    if let Ok(crontab) = read_crontab() {
+        let kept = without_entry(&crontab, profile);
+        if kept.lines().count() != crontab.lines().count() {
+            write_crontab(&kept)?;
+            removed = true;
+        }
+    }
+    if removed { Ok(()) } else { Err(format!("The profile {} isn't scheduled", profile)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_and_days_are_parsed() {
+        assert_eq!(parse_time("02:00"), Ok((2, 0)));
+        assert_eq!(parse_time("23:59"), Ok((23, 59)));
+        for time in ["24:00", "12:60", "12", "ab:cd", ""] {
+            assert!(parse_time(time).is_err(), "{}", time);
+        }
+        assert_eq!(parse_weekly("Sun 03:30"), Ok(Schedule::Weekly(String::from("Sun"), 3, 30)));
+        assert_eq!(parse_weekly(" monday 7:05 "), Ok(Schedule::Weekly(String::from("Mon"), 7, 5)));
+        assert!(parse_weekly("Someday 03:30").is_err());
+        assert!(parse_weekly("Sun").is_err());
+        assert!(parse_weekly("Sun 25:00").is_err());
+    }
+
+    #[test]
+    fn schedules_are_written_for_systemd_and_cron() {
+        let weekly = Schedule::Weekly(String::from("Wed"), 3, 5);
+        assert_eq!(Schedule::Hourly.on_calendar(), "hourly");
+        assert_eq!(Schedule::Daily(2, 0).on_calendar(), "*-*-* 02:00:00");
+        assert_eq!(weekly.on_calendar(), "Wed *-*-* 03:05:00");
+        assert_eq!(Schedule::Hourly.cron(), "0 * * * *");
+        assert_eq!(Schedule::Daily(2, 0).cron(), "0 2 * * *");
+        assert_eq!(weekly.cron(), "5 3 * * 3");
+        assert_eq!(weekly.to_string(), "every Wed at 03:05");
+    }
+
+    #[test]
+    fn commands_are_escaped() {
+        let command = [String::from("/opt/ebod $HOME/50%"), String::from("run"), String::from("it's")];
+        assert_eq!(exec_start(&command), "\"/opt/ebod $$HOME/50%%\" \"run\" \"it's\"");
+        assert_eq!(cron_command(&command), "'/opt/ebod $HOME/50\\%' 'run' 'it'\\''s'");
+        assert_eq!(exec_start(&[String::from("a \"b\" \\c")]), "\"a \\\"b\\\" \\\\c\"");
+    }
+
+    #[test]
+    fn only_the_entry_of_the_profile_is_removed() {
+        let crontab = "MAILTO=me\n0 2 * * * '/bin/ebod' 'run' 'photos' # ebod:photos\n0 3 * * * '/bin/ebod' 'run' 'photos-old' # ebod:photos-old\n0 4 * * * backup.sh\n";
+        assert_eq!(
+            without_entry(crontab, "photos"),
+            "MAILTO=me\n0 3 * * * '/bin/ebod' 'run' 'photos-old' # ebod:photos-old\n0 4 * * * backup.sh\n"
+        );
+        assert_eq!(without_entry(crontab, "docs"), crontab);
+        assert_eq!(without_entry("", "photos"), "");
+    }
+}
@@ -0,0 +1,785 @@
+//! SFTP backend.
+//!
+//! `SftpFs` speaks version 3 of the SFTP protocol (the one of OpenSSH) over the standard input and output of a
+//! subprocess. By default the subprocess is `ssh -s [-p PORT] [USER@]HOST sftp`, so the keys, agents and
+//! `~/.ssh/config` of the user are used as they are. When `$EBOD_SFTP_COMMAND` is set, it is run with `sh -c` instead,
+//! which lets an `sftp-server` binary be used directly, without an SSH server:
+//!
+//! ```text
+//! EBOD_SFTP_COMMAND=/usr/lib/openssh/sftp-server ebod backup photos sftp://localhost/tmp/photos
+//! ```
+//!
+//! Several requests are kept in flight while a file is read or written, so that the latency of the connection is paid
+//! once per window instead of once per chunk. When the server supports the `copy-data` extension of OpenSSH, modified
+//! files are updated with a delta (see `delta`): the unchanged blocks are copied on the server, and only the changed
+//! ones are uploaded.
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::vfs::{FileKind, FilePatch, Stat, Vfs, VfsEntry};
+
+/// The environment variable holding a command to run instead of `ssh`.
+pub const SFTP_COMMAND_VAR: &str = "EBOD_SFTP_COMMAND";
+
+// The size of the data of a READ or WRITE request. OpenSSH accepts up to 255 KiB, but 32 KiB is the size every
+// server has to accept.
+const CHUNK: usize = 32 * 1024;
+// The number of READ or WRITE requests kept in flight
+const WINDOW: usize = 16;
+
+// Packet types
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+const SSH_FXP_EXTENDED: u8 = 200;
+
+// Status codes
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+
+// Flags of OPEN
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+
+// Flags of the attributes
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+// The file type bits of the permissions
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+// The rename of OpenSSH that replaces the target, like rename(2)
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+// The server side copy of a range of a file into another file, of OpenSSH 9.0 and later
+const COPY_DATA: &str = "copy-data";
+
+/// Where an SFTP session is opened: `sftp://[USER@]HOST[:PORT]/PATH`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SftpTarget {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl SftpTarget {
+    /// The command that runs the SFTP subsystem of the target.
+    pub fn command(&self) -> Command {
+        if let Ok(command) = std::env::var(SFTP_COMMAND_VAR) {
+            let mut sh = Command::new("sh");
+            sh.arg("-c").arg(command);
+            return sh;
+        }
+        let mut ssh = Command::new("ssh");
+        if let Some(port) = self.port {
+            ssh.arg("-p").arg(port.to_string());
+        }
+        let host = match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        };
+        ssh.arg("-s").arg(host).arg("sftp");
+        ssh
+    }
+}
+
+// A packet received from the server: its type and its payload after the request id
+struct Packet {
+    kind: u8,
+    data: Vec<u8>,
+}
+
+// Parses the fields of a packet
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("Invalid SFTP packet: {}", msg))
+}
+
+impl Fields<'_> {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.data.len() < n {
+            return Err(invalid("truncated"));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(((self.u32()? as u64) << 32) | self.u32()? as u64)
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes()?).to_string())
+    }
+
+    fn attrs(&mut self) -> io::Result<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            self.u32()?;
+            self.u32()?;
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            attrs.atime = Some(self.u32()?);
+            attrs.mtime = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+// Builds the payload of a request
+#[derive(Default)]
+struct Request {
+    data: Vec<u8>,
+}
+
+impl Request {
+    fn u32(mut self, value: u32) -> Request {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Request {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Request {
+        self = self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    fn path(self, path: &Path) -> Request {
+        self.bytes(path.to_string_lossy().as_bytes())
+    }
+
+    fn attrs(self, attrs: &Attrs) -> Request {
+        let mut flags = 0;
+        if attrs.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        if attrs.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        if attrs.mtime.is_some() {
+            flags |= SSH_FILEXFER_ATTR_ACMODTIME;
+        }
+        let mut request = self.u32(flags);
+        if let Some(size) = attrs.size {
+            request = request.u64(size);
+        }
+        if let Some(permissions) = attrs.permissions {
+            request = request.u32(permissions);
+        }
+        if let Some(mtime) = attrs.mtime {
+            request = request.u32(attrs.atime.unwrap_or(mtime)).u32(mtime);
+        }
+        request
+    }
+}
+
+// The attributes of a file, as sent by the server
+#[derive(Debug, Default, Clone)]
+struct Attrs {
+    size: Option<u64>,
+    permissions: Option<u32>,
+    atime: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl Attrs {
+    fn stat(&self) -> Stat {
+        let permissions = self.permissions.unwrap_or(0);
+        Stat {
+            kind: match permissions & S_IFMT {
+                S_IFDIR => FileKind::Dir,
+                S_IFLNK => FileKind::Symlink,
+                _ => FileKind::File,
+            },
+            length: self.size.unwrap_or(0),
+            allocated: self.size.unwrap_or(0),
+            modified: UNIX_EPOCH + Duration::from_secs(self.mtime.unwrap_or(0) as u64),
+            inode: 0,
+            mode: permissions & 0o7777,
+            #[cfg(target_os = "windows")]
+            file_attr: 0,
+        }
+    }
+}
+
+// The error of a STATUS packet
+fn status_error(code: u32, msg: &str, path: &str) -> io::Error {
+    let kind = match code {
+        SSH_FX_NO_SUCH_FILE => ErrorKind::NotFound,
+        SSH_FX_PERMISSION_DENIED => ErrorKind::PermissionDenied,
+        SSH_FX_EOF => ErrorKind::UnexpectedEof,
+        _ => ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{}: {}", path, msg))
+}
+
+// An SFTP session over the pipes of the subprocess
+struct Session {
+    child: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    next_id: u32,
+    // Responses that arrived while another one was awaited
+    unclaimed: HashMap<u32, Packet>,
+}
+
+impl Session {
+    fn send(&mut self, kind: u8, request: Request) -> io::Result<u32> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+        let len = (request.data.len() + 5) as u32;
+        let mut packet = Vec::with_capacity(len as usize + 4);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.push(kind);
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&request.data);
+        self.input.write_all(&packet)?;
+        self.input.flush()?;
+        Ok(id)
+    }
+
+    fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut len = [0u8; 4];
+        self.output.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            return Err(invalid("empty packet"));
+        }
+        let mut packet = vec![0u8; len];
+        self.output.read_exact(&mut packet)?;
+        let kind = packet[0];
+        packet.remove(0);
+        Ok((kind, packet))
+    }
+
+    fn wait(&mut self, id: u32) -> io::Result<Packet> {
+        if let Some(packet) = self.unclaimed.remove(&id) {
+            return Ok(packet);
+        }
+        loop {
+            let (kind, data) = self.read_packet()?;
+            let mut fields = Fields { data: &data };
+            let received = fields.u32()?;
+            let packet = Packet { kind, data: fields.data.to_vec() };
+            if received == id {
+                return Ok(packet);
+            }
+            self.unclaimed.insert(received, packet);
+        }
+    }
+
+    fn call(&mut self, kind: u8, request: Request) -> io::Result<Packet> {
+        let id = self.send(kind, request)?;
+        self.wait(id)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Closing the input ends the session on the server side
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Checks that a response is a STATUS with the code OK
+fn expect_ok(packet: Packet, path: &Path) -> io::Result<()> {
+    if packet.kind != SSH_FXP_STATUS {
+        return Err(invalid("expected a status"));
+    }
+    let mut fields = Fields { data: &packet.data };
+    let code = fields.u32()?;
+    if code == SSH_FX_OK {
+        return Ok(());
+    }
+    Err(status_error(code, &fields.string().unwrap_or_default(), &path.to_string_lossy()))
+}
+
+// Turns a STATUS response that was not expected into an error
+fn unexpected(packet: Packet, path: &Path) -> io::Error {
+    if packet.kind == SSH_FXP_STATUS {
+        let mut fields = Fields { data: &packet.data };
+        let code = fields.u32().unwrap_or(u32::MAX);
+        return status_error(code, &fields.string().unwrap_or_default(), &path.to_string_lossy());
+    }
+    invalid(&format!("unexpected packet type {}", packet.kind))
+}
+
+fn expect_handle(packet: Packet, path: &Path) -> io::Result<Vec<u8>> {
+    if packet.kind != SSH_FXP_HANDLE {
+        return Err(unexpected(packet, path));
+    }
+    Fields { data: &packet.data }.bytes()
+}
+
+/// A filesystem reached over SFTP.
+pub struct SftpFs {
+    session: Mutex<Session>,
+    posix_rename: bool,
+    copy_data: bool,
+}
+
+impl SftpFs {
+    /// Starts the SFTP subsystem of `target` and negotiates the version 3 of the protocol.
+    pub fn connect(target: &SftpTarget) -> Result<SftpFs, String> {
+        let mut child = target
+            .command()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|err| format!("Couldn't start the SFTP session with {}: {}", target.host, err))?;
+        let (Some(input), Some(output)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(format!("Couldn't start the SFTP session with {}", target.host));
+        };
+        let mut session = Session { child, input, output: BufReader::new(output), next_id: 0, unclaimed: HashMap::new() };
+        let error = |err: io::Error| format!("The SFTP session with {} failed: {}", target.host, err);
+
+        // INIT has no request id: the version takes its place
+        let init = [0, 0, 0, 5, SSH_FXP_INIT, 0, 0, 0, 3];
+        session.input.write_all(&init).and_then(|_| session.input.flush()).map_err(error)?;
+        let (kind, data) = session.read_packet().map_err(error)?;
+        if kind != SSH_FXP_VERSION {
+            return Err(format!("{} didn't answer with an SFTP version", target.host));
+        }
+        let mut fields = Fields { data: &data };
+        let version = fields.u32().map_err(error)?;
+        if version < 3 {
+            return Err(format!("{} only speaks the SFTP version {}", target.host, version));
+        }
+        let (mut posix_rename, mut copy_data) = (false, false);
+        while !fields.data.is_empty() {
+            let name = fields.string().map_err(error)?;
+            fields.bytes().map_err(error)?;
+            posix_rename |= name == POSIX_RENAME;
+            copy_data |= name == COPY_DATA;
+        }
+        Ok(SftpFs { session: Mutex::new(session), posix_rename, copy_data })
+    }
+
+    fn session(&self) -> io::Result<MutexGuard<'_, Session>> {
+        self.session.lock().map_err(|_| io::Error::other("The SFTP session is poisoned"))
+    }
+
+    fn call(&self, kind: u8, request: Request) -> io::Result<Packet> {
+        self.session()?.call(kind, request)
+    }
+
+    fn open_handle(&self, path: &Path, flags: u32) -> io::Result<Vec<u8>> {
+        let request = Request::default().path(path).u32(flags).attrs(&Attrs::default());
+        expect_handle(self.call(SSH_FXP_OPEN, request)?, path)
+    }
+
+    fn close_handle(&self, handle: &[u8], path: &Path) -> io::Result<()> {
+        expect_ok(self.call(SSH_FXP_CLOSE, Request::default().bytes(handle))?, path)
+    }
+
+    fn attrs(&self, path: &Path) -> io::Result<Attrs> {
+        let packet = self.call(SSH_FXP_STAT, Request::default().path(path))?;
+        if packet.kind != SSH_FXP_ATTRS {
+            return Err(unexpected(packet, path));
+        }
+        Fields { data: &packet.data }.attrs()
+    }
+
+    fn setstat(&self, path: &Path, attrs: &Attrs) -> io::Result<()> {
+        expect_ok(self.call(SSH_FXP_SETSTAT, Request::default().path(path).attrs(attrs))?, path)
+    }
+}
+
+// Reads a file with READ requests kept in flight
+struct SftpReader<'a> {
+    fs: &'a SftpFs,
+    path: PathBuf,
+    handle: Vec<u8>,
+    // The offset of the next request to send, and the requests in flight with their offset and length
+    next_offset: u64,
+    pending: VecDeque<(u32, u64)>,
+    buffer: Vec<u8>,
+    position: usize,
+    eof: bool,
+}
+
+impl SftpReader<'_> {
+    fn fill(&mut self) -> io::Result<()> {
+        let mut session = self.fs.session()?;
+        while self.pending.len() < WINDOW {
+            let request = Request::default().bytes(&self.handle).u64(self.next_offset).u32(CHUNK as u32);
+            let id = session.send(SSH_FXP_READ, request)?;
+            self.pending.push_back((id, self.next_offset));
+            self.next_offset += CHUNK as u64;
+        }
+        let Some((id, offset)) = self.pending.pop_front() else {
+            return Ok(());
+        };
+        let packet = session.wait(id)?;
+        match packet.kind {
+            SSH_FXP_DATA => {
+                self.buffer = Fields { data: &packet.data }.bytes()?;
+                self.position = 0;
+                if self.buffer.len() < CHUNK {
+                    // A short read: the requests in flight were sent for the wrong offsets, so they are dropped and
+                    // sent again after this chunk
+                    for (id, _) in self.pending.drain(..) {
+                        let _ = session.wait(id);
+                    }
+                    self.next_offset = offset + self.buffer.len() as u64;
+                    self.eof = self.buffer.is_empty();
+                }
+                Ok(())
+            }
+            SSH_FXP_STATUS => {
+                let mut fields = Fields { data: &packet.data };
+                let code = fields.u32()?;
+                for (id, _) in self.pending.drain(..) {
+                    let _ = session.wait(id);
+                }
+                if code == SSH_FX_EOF {
+                    self.eof = true;
+                    self.buffer.clear();
+                    self.position = 0;
+                    return Ok(());
+                }
+                Err(status_error(code, &fields.string().unwrap_or_default(), &self.path.to_string_lossy()))
+            }
+            _ => Err(invalid("expected data")),
+        }
+    }
+}
+
+impl Read for SftpReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Drop for SftpReader<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut session) = self.fs.session() {
+            for (id, _) in self.pending.drain(..) {
+                let _ = session.wait(id);
+            }
+        }
+        let _ = self.fs.close_handle(&self.handle, &self.path);
+    }
+}
+
+// Writes a file with WRITE requests kept in flight. The errors are reported by `flush`.
+struct SftpWriter<'a> {
+    fs: &'a SftpFs,
+    path: PathBuf,
+    handle: Vec<u8>,
+    offset: u64,
+    buffer: Vec<u8>,
+    pending: VecDeque<u32>,
+}
+
+impl SftpWriter<'_> {
+    // Sends a request answered with a status, keeping at most `WINDOW` of them in flight
+    fn send_request(&mut self, kind: u8, request: Request) -> io::Result<()> {
+        let mut session = self.fs.session()?;
+        if self.pending.len() >= WINDOW
+            && let Some(id) = self.pending.pop_front()
+        {
+            expect_ok(session.wait(id)?, &self.path)?;
+        }
+        let id = session.send(kind, request)?;
+        self.pending.push_back(id);
+        Ok(())
+    }
+
+    fn send_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let request = Request::default().bytes(&self.handle).u64(self.offset).bytes(chunk);
+        self.send_request(SSH_FXP_WRITE, request)?;
+        self.offset += chunk.len() as u64;
+        Ok(())
+    }
+
+    // Sends the buffered data, which belongs at the current offset
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let chunk = std::mem::take(&mut self.buffer);
+            self.send_chunk(&chunk)?;
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        let mut session = self.fs.session()?;
+        let mut result = Ok(());
+        for id in self.pending.drain(..) {
+            let status = session.wait(id).and_then(|packet| expect_ok(packet, &self.path));
+            if result.is_ok() {
+                result = status;
+            }
+        }
+        result
+    }
+}
+
+impl Write for SftpWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= CHUNK {
+            let chunk: Vec<u8> = self.buffer.drain(..CHUNK).collect();
+            self.send_chunk(&chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()?;
+        self.drain()
+    }
+}
+
+impl Drop for SftpWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        let _ = self.fs.close_handle(&self.handle, &self.path);
+    }
+}
+
+// The file opened by `patch`: a writer of the new file, and the basis, whose ranges are copied with `copy-data`
+struct SftpPatch<'a> {
+    writer: SftpWriter<'a>,
+    basis: PathBuf,
+    basis_handle: Vec<u8>,
+}
+
+impl Write for SftpPatch<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl FilePatch for SftpPatch<'_> {
+    fn copy_from_basis(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        // The server handles the requests in order, so the copy lands after the data written before it
+        self.writer.send_buffer()?;
+        let request = Request::default()
+            .bytes(COPY_DATA.as_bytes())
+            .bytes(&self.basis_handle)
+            .u64(offset)
+            .u64(length)
+            .bytes(&self.writer.handle)
+            .u64(self.writer.offset);
+        self.writer.send_request(SSH_FXP_EXTENDED, request)?;
+        self.writer.offset += length;
+        Ok(())
+    }
+}
+
+impl Drop for SftpPatch<'_> {
+    fn drop(&mut self) {
+        let _ = self.writer.fs.close_handle(&self.basis_handle, &self.basis);
+    }
+}
+
+impl Vfs for SftpFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsEntry>> {
+        let handle = expect_handle(self.call(SSH_FXP_OPENDIR, Request::default().path(path))?, path)?;
+        let mut entries = vec![];
+        let mut links = vec![];
+        let result = loop {
+            let packet = match self.call(SSH_FXP_READDIR, Request::default().bytes(&handle)) {
+                Ok(packet) => packet,
+                Err(err) => break Err(err),
+            };
+            if packet.kind != SSH_FXP_NAME {
+                let mut fields = Fields { data: &packet.data };
+                if packet.kind == SSH_FXP_STATUS && fields.u32()? == SSH_FX_EOF {
+                    break Ok(());
+                }
+                break Err(unexpected(packet, path));
+            }
+            let mut fields = Fields { data: &packet.data };
+            for _ in 0..fields.u32()? {
+                let name = fields.string()?;
+                fields.string()?;
+                let attrs = fields.attrs()?;
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let stat = attrs.stat();
+                if stat.kind == FileKind::Symlink {
+                    links.push(name);
+                } else {
+                    entries.push(VfsEntry { name, stat });
+                }
+            }
+        };
+        let _ = self.close_handle(&handle, path);
+        result?;
+        // READDIR doesn't follow the links. Like the local filesystem, they are followed, and broken links are left out
+        for name in links {
+            if let Ok(attrs) = self.attrs(&path.join(&name)) {
+                entries.push(VfsEntry { name, stat: attrs.stat() });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Stat> {
+        self.attrs(path).map(|attrs| attrs.stat())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let handle = self.open_handle(path, SSH_FXF_READ)?;
+        Ok(Box::new(SftpReader {
+            fs: self,
+            path: path.to_path_buf(),
+            handle,
+            next_offset: 0,
+            pending: VecDeque::new(),
+            buffer: vec![],
+            position: 0,
+            eof: false,
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + '_>> {
+        let handle = self.open_handle(path, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC)?;
+        Ok(Box::new(SftpWriter { fs: self, path: path.to_path_buf(), handle, offset: 0, buffer: vec![], pending: VecDeque::new() }))
+    }
+
+    fn patch(&self, basis: &Path, path: &Path) -> io::Result<Box<dyn FilePatch + '_>> {
+        if !self.copy_data {
+            return Err(io::Error::new(ErrorKind::Unsupported, format!("The server of {} can't copy data", basis.to_string_lossy())));
+        }
+        let basis_handle = self.open_handle(basis, SSH_FXF_READ)?;
+        let writer = match self.open_handle(path, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC) {
+            Ok(handle) => SftpWriter { fs: self, path: path.to_path_buf(), handle, offset: 0, buffer: vec![], pending: VecDeque::new() },
+            Err(err) => {
+                let _ = self.close_handle(&basis_handle, basis);
+                return Err(err);
+            }
+        };
+        Ok(Box::new(SftpPatch { writer, basis: basis.to_path_buf(), basis_handle }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut dir = PathBuf::new();
+        for component in path.components() {
+            dir.push(component);
+            match self.attrs(&dir) {
+                Ok(attrs) if attrs.stat().is_dir() => continue,
+                Ok(_) => return Err(io::Error::other(format!("{} isn't a directory", dir.to_string_lossy()))),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+            expect_ok(self.call(SSH_FXP_MKDIR, Request::default().path(&dir).attrs(&Attrs::default()))?, &dir)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.posix_rename {
+            let request = Request::default().bytes(POSIX_RENAME.as_bytes()).path(from).path(to);
+            return expect_ok(self.call(SSH_FXP_EXTENDED, request)?, from);
+        }
+        // The plain RENAME of the version 3 fails if the target exists
+        match self.remove(to) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        expect_ok(self.call(SSH_FXP_RENAME, Request::default().path(from).path(to))?, from)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        if self.attrs(path)?.stat().is_dir() {
+            expect_ok(self.call(SSH_FXP_RMDIR, Request::default().path(path))?, path)
+        } else {
+            expect_ok(self.call(SSH_FXP_REMOVE, Request::default().path(path))?, path)
+        }
+    }
+
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        // The version 3 of the protocol only has 32 bit times in seconds
+        let secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0).min(u32::MAX as u64) as u32;
+        self.setstat(path, &Attrs { mtime: Some(secs), atime: Some(secs), ..Attrs::default() })
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        // OpenSSH takes the arguments in the reverse order of the specification, and every server follows it
+        expect_ok(self.call(SSH_FXP_SYMLINK, Request::default().path(target).path(link))?, link)
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        self.setstat(path, &Attrs { permissions: Some(mode & 0o7777), ..Attrs::default() })
+    }
+}
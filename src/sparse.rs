@@ -0,0 +1,139 @@
+//! Sparse files.
+//!
+//! A sparse file, like a VM disk image, has holes: ranges that read as zeros but aren't allocated on disk. `fs::copy`
+//! writes those zeros, so the copy of a 100 GB image takes 100 GB whatever its allocated size. The listing records the
+//! allocated size of a sparse file in `FileEntry::allocated`, and the copier copies it with `copy`: the data ranges of
+//! the source are found with `SEEK_DATA` and `SEEK_HOLE`, only they are written, and the rest of the destination is
+//! left as holes.
+//!
+//! Holes are only recreated when both sides are on the local filesystem. The remote backends get the full data.
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// The ranges of `file` that hold data, in order. The rest of the file is holes.
+///
+/// # Output: `io::Result<Option<Vec<Range<u64>>>>`
+/// The data ranges, or `None` if the system or the filesystem can't find the holes of a file.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn data_ranges(file: &File) -> io::Result<Option<Vec<Range<u64>>>> {
+    use std::os::fd::AsRawFd;
+
+    let length = file.metadata()?.len();
+    let fd = file.as_raw_fd();
+    let mut ranges = vec![];
+    let mut offset = 0;
+    while offset < length {
+        // SAFETY: lseek only moves the offset of the descriptor, which stays owned by `file`
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No data after `offset`: the file ends with a hole
+                Some(libc::ENXIO) => Ok(Some(ranges)),
+                Some(libc::EINVAL) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        // SAFETY: same as above
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ranges.push(start as u64..end as u64);
+        offset = end as u64;
+    }
+    Ok(Some(ranges))
+}
+
+/// The ranges of `file` that hold data, in order. The rest of the file is holes.
+///
+/// # Output: `io::Result<Option<Vec<Range<u64>>>>`
+/// Always `None`: this system can't find the holes of a file.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn data_ranges(_file: &File) -> io::Result<Option<Vec<Range<u64>>>> {
+    Ok(None)
+}
+
+/// Copies the local file `src` into `dest`, writing only the data ranges of `src` so that `dest` gets the same holes.
+/// `on_copied` is called with the number of bytes of `src` done as they are done, holes included.
+///
+/// # Output: `io::Result<Option<u64>>`
+/// The number of bytes of data written, or `None` if the holes of `src` can't be found. Nothing is written then.
+pub fn copy(src: &Path, dest: &Path, mut on_copied: impl FnMut(u64)) -> io::Result<Option<u64>> {
+    let mut input = File::open(src)?;
+    let length = input.metadata()?.len();
+    let Some(ranges) = data_ranges(&input)? else {
+        return Ok(None);
+    };
+    let mut output = File::create(dest)?;
+    const BUFFER: usize = 1024 * 1024;
+    let mut buffer = vec![0u8; BUFFER];
+    let mut done = 0;
+    let mut written = 0;
+    for range in ranges {
+        on_copied(range.start.saturating_sub(done));
+        input.seek(SeekFrom::Start(range.start))?;
+        output.seek(SeekFrom::Start(range.start))?;
+        let mut remaining = range.end - range.start;
+        while remaining > 0 {
+            let read = match input.read(&mut buffer[..remaining.min(BUFFER as u64) as usize]) {
+                // The file was truncated while it was copied
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            output.write_all(&buffer[..read])?;
+            remaining -= read as u64;
+            written += read as u64;
+            on_copied(read as u64);
+        }
+        done = range.end;
+    }
+    // A hole at the end of the file isn't written by anything
+    output.set_len(length.max(done))?;
+    on_copied(length.saturating_sub(done));
+    output.flush()?;
+    Ok(Some(written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[cfg(unix)]
+    #[test]
+    fn holes_are_kept_by_the_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = std::env::temp_dir().join(format!("ebod-sparse-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let (src, dest) = (root.join("disk.img"), root.join("copy.img"));
+        let length = 8 * 1024 * 1024;
+        let mut file = File::create(&src).unwrap();
+        file.set_len(length).unwrap();
+        file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        file.write_all(&[7; 4096]).unwrap();
+        drop(file);
+
+        // The filesystem of the temporary directory may not find holes, or may not make any
+        let ranges = data_ranges(&File::open(&src).unwrap()).unwrap();
+        if ranges.is_none_or(|ranges| ranges.iter().map(|range| range.end - range.start).sum::<u64>() == length) {
+            fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+        let mut reported = 0;
+        let written = copy(&src, &dest, |done| reported += done).unwrap();
+        assert_eq!(written, Some(4096));
+        assert_eq!(reported, length);
+        assert_eq!(fs::read(&dest).unwrap(), fs::read(&src).unwrap());
+        let meta = fs::metadata(&dest).unwrap();
+        assert_eq!(meta.len(), length);
+        assert!(meta.blocks() * 512 < length, "{} blocks", meta.blocks());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
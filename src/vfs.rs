@@ -0,0 +1,784 @@
+//! Filesystem abstraction.
+//!
+//! The indexer (`recursive_listing_in`), the planner (`plan_backup`, which only works on `FileEntry` lists) and the
+//! copier (`execute_plan_in`) go through the `Vfs` trait instead of calling `std::fs`. Two implementations are provided:
+//! * `LocalFs` -> The local filesystem, used by the command line
+//! * `MemoryFs` -> A filesystem kept in memory, for fast and deterministic tests of the backup logic
+//!
+//! Other backends only need to implement the trait to be usable as a source or a destination.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(target_os = "windows")]
+use std::os::windows::fs::MetadataExt as WindowsMetadataExt;
+
+/// The kind of an entry of a `Vfs`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// The metadata of an entry of a `Vfs`.
+///
+/// # Members
+/// * `kind: FileKind` -> The kind of the entry. Symbolic links are followed by `stat` and `read_dir`
+/// * `length: u64` -> The size of the entry in bytes
+/// * `allocated: u64` -> The bytes allocated to the entry on disk, less than `length` for a sparse file. Backends that
+///   don't know it report `length`
+/// * `modified: SystemTime` -> The time the entry was last modified
+/// * `inode: u64` -> The inode number of the entry, or 0 if the backend has none
+/// * `mode: u32` -> The UNIX permission bits of the entry
+/// * `file_attr: u32` **[WINDOWS ONLY]** -> The file attributes of the entry
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Stat {
+    pub kind: FileKind,
+    pub length: u64,
+    pub allocated: u64,
+    pub modified: SystemTime,
+    pub inode: u64,
+    pub mode: u32,
+    #[cfg(target_os = "windows")]
+    pub file_attr: u32,
+}
+
+impl Stat {
+    /// Whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+}
+
+impl From<&fs::Metadata> for Stat {
+    fn from(meta: &fs::Metadata) -> Stat {
+        Stat {
+            kind: if meta.is_dir() {
+                FileKind::Dir
+            } else if meta.is_symlink() {
+                FileKind::Symlink
+            } else {
+                FileKind::File
+            },
+            length: meta.len(),
+            #[cfg(unix)]
+            allocated: meta.blocks() * 512,
+            #[cfg(not(unix))]
+            allocated: meta.len(),
+            modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            #[cfg(target_os = "linux")]
+            inode: meta.ino(),
+            #[cfg(not(target_os = "linux"))]
+            inode: 0,
+            #[cfg(unix)]
+            mode: meta.permissions().mode(),
+            #[cfg(not(unix))]
+            mode: if meta.permissions().readonly() { 0o444 } else { 0o644 },
+            #[cfg(target_os = "windows")]
+            file_attr: meta.file_attributes(),
+        }
+    }
+}
+
+/// An entry returned by `Vfs::read_dir`: its file name and its metadata.
+#[derive(Debug, Clone)]
+pub struct VfsEntry {
+    pub name: String,
+    pub stat: Stat,
+}
+
+/// A file opened by `Vfs::patch`: written in order, with data or with ranges of its basis.
+pub trait FilePatch: Write {
+    /// Appends the `length` bytes of the basis at `offset` to the file.
+    fn copy_from_basis(&mut self, offset: u64, length: u64) -> io::Result<()>;
+}
+
+/// The operations that ebod needs from a filesystem.
+///
+/// Paths are given as they are passed to the backup, so a backend decides how they map onto its storage. Errors are
+/// `io::Error`s, so that callers can tell a missing entry (`ErrorKind::NotFound`) from other failures.
+pub trait Vfs {
+    /// The entries of the directory `path`, in no particular order. Entries whose metadata can't be read are left out.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsEntry>>;
+
+    /// The metadata of `path`. Symbolic links are followed.
+    fn stat(&self, path: &Path) -> io::Result<Stat>;
+
+    /// Opens the file `path` for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Creates the file `path`, or truncates it if it exists, and opens it for writing. The parent must exist.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + '_>>;
+
+    /// Creates the directory `path` and its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Renames `from` into `to`, replacing `to` if it is a file.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes the file, the symbolic link or the empty directory `path`.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Sets the modified time of `path`.
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()>;
+
+    /// Creates a symbolic link at `link` that points to `target`.
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+
+    /// Creates the file `path`, like `create`, and opens it along with the existing file `basis`, whose ranges can be
+    /// copied into it without going through ebod. Lets the copier send only the changed blocks of a file. Backends that
+    /// can't copy ranges of a file return `ErrorKind::Unsupported`.
+    fn patch(&self, basis: &Path, _path: &Path) -> io::Result<Box<dyn FilePatch + '_>> {
+        Err(io::Error::new(ErrorKind::Unsupported, format!("{} can't be patched", basis.to_string_lossy())))
+    }
+
+    /// The extended attributes of `path`, with their values. Symbolic links are followed. Backends without extended
+    /// attributes return `ErrorKind::Unsupported`.
+    fn xattrs(&self, path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+        Err(io::Error::new(ErrorKind::Unsupported, format!("{} has no extended attributes", path.to_string_lossy())))
+    }
+
+    /// Sets the extended attribute `name` of `path`.
+    fn set_xattr(&self, path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(ErrorKind::Unsupported, format!("{} has no extended attributes", path.to_string_lossy())))
+    }
+
+    /// Removes the extended attribute `name` of `path`.
+    fn remove_xattr(&self, path: &Path, _name: &str) -> io::Result<()> {
+        Err(io::Error::new(ErrorKind::Unsupported, format!("{} has no extended attributes", path.to_string_lossy())))
+    }
+
+    /// Sets the UNIX permission bits of `path`. Backends without permissions ignore it.
+    fn set_mode(&self, _path: &Path, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The path of `path` on the local filesystem, if the backend is the local filesystem. Lets the copier use the
+    /// system calls of the local filesystem when both sides are local.
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// Whether the file `path` is known to hold the contents of the local file `local`, without reading `path`.
+    /// Lets backends that keep digests of their files, like the ETags of S3, skip the upload of unchanged files.
+    fn same_contents(&self, _path: &Path, _local: &Path) -> bool {
+        false
+    }
+
+    /// Whether `path` exists.
+    fn exists(&self, path: &Path) -> bool {
+        self.stat(path).is_ok()
+    }
+
+    /// Reads the whole file `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Writes `data` into the file `path`, replacing its contents.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = self.create(path)?;
+        file.write_all(data)?;
+        file.flush()
+    }
+}
+
+/// The local filesystem.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFs;
+
+impl Vfs for LocalFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsEntry>> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(path)?.flatten() {
+            if let Ok(meta) = fs::metadata(entry.path()) {
+                entries.push(VfsEntry { name: entry.file_name().to_string_lossy().to_string(), stat: Stat::from(&meta) });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Stat> {
+        fs::metadata(path).map(|meta| Stat::from(&meta))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + '_>> {
+        Ok(Box::new(fs::File::create(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        fs::File::options().write(true).open(path)?.set_modified(modified)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, link);
+        #[cfg(windows)]
+        return if target.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        };
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        #[cfg(unix)]
+        let permissions = fs::Permissions::from_mode(mode);
+        #[cfg(not(unix))]
+        let permissions = {
+            let mut permissions = fs::metadata(path)?.permissions();
+            permissions.set_readonly(mode & 0o200 == 0);
+            permissions
+        };
+        fs::set_permissions(path, permissions)
+    }
+
+    #[cfg(unix)]
+    fn xattrs(&self, path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let mut attrs = vec![];
+        for name in xattr::list_deref(path).map_err(xattr_error)? {
+            // An attribute can disappear between the listing and the read
+            if let Some(value) = xattr::get_deref(path, &name).map_err(xattr_error)? {
+                attrs.push((name.to_string_lossy().to_string(), value));
+            }
+        }
+        Ok(attrs)
+    }
+
+    #[cfg(unix)]
+    fn set_xattr(&self, path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        xattr::set_deref(path, name, value).map_err(xattr_error)
+    }
+
+    #[cfg(unix)]
+    fn remove_xattr(&self, path: &Path, name: &str) -> io::Result<()> {
+        xattr::remove_deref(path, name).map_err(xattr_error)
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        fs::write(path, data)
+    }
+}
+
+// Filesystems without extended attributes (FAT, exFAT…) fail with `EOPNOTSUPP`, which `io::Error` doesn't map to
+// `ErrorKind::Unsupported`
+#[cfg(unix)]
+fn xattr_error(err: io::Error) -> io::Error {
+    #[cfg(target_os = "linux")]
+    const EOPNOTSUPP: i32 = 95;
+    #[cfg(not(target_os = "linux"))]
+    const EOPNOTSUPP: i32 = 45;
+    match err.raw_os_error() {
+        Some(EOPNOTSUPP) => io::Error::new(ErrorKind::Unsupported, err),
+        _ => err,
+    }
+}
+
+// An entry of a `MemoryFs`
+#[derive(Debug, Clone)]
+enum Content {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    content: Content,
+    modified: SystemTime,
+    inode: u64,
+    mode: u32,
+}
+
+#[derive(Debug, Default)]
+struct Tree {
+    nodes: BTreeMap<PathBuf, Node>,
+    next_inode: u64,
+}
+
+// Links are followed at most this many times, like the `ELOOP` limit of Linux
+const MAX_LINKS: usize = 40;
+
+/// A filesystem kept in memory. Every path is a directory below an implicit root, so `/a/b` and `a/b` are the same
+/// entry. New entries are modified at `SystemTime::now()`, and get increasing inode numbers.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    tree: Mutex<Tree>,
+}
+
+// Removes the root, `.` and `..` from `path`, so that every path of an entry has a single key
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normal.push(name),
+            Component::ParentDir => {
+                normal.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normal
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("{} doesn't exist", path.to_string_lossy()))
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("The in-memory filesystem is poisoned")
+}
+
+impl Tree {
+    // The key of the entry `path` points to, after following the symbolic links
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut key = normalize(path);
+        for _ in 0..MAX_LINKS {
+            match self.nodes.get(&key) {
+                Some(Node { content: Content::Symlink(target), .. }) => {
+                    key = normalize(&key.parent().unwrap_or(Path::new("")).join(target));
+                }
+                Some(_) => return Ok(key),
+                None if key.as_os_str().is_empty() => return Ok(key),
+                None => return Err(not_found(path)),
+            }
+        }
+        Err(io::Error::other(format!("Too many levels of symbolic links in {}", path.to_string_lossy())))
+    }
+
+    fn stat(&self, key: &Path) -> Stat {
+        let Some(node) = self.nodes.get(key) else {
+            // The implicit root
+            return Stat {
+                kind: FileKind::Dir,
+                length: 0,
+                allocated: 0,
+                modified: SystemTime::UNIX_EPOCH,
+                inode: 0,
+                mode: 0o755,
+                #[cfg(target_os = "windows")]
+                file_attr: 0,
+            };
+        };
+        let (kind, length) = match &node.content {
+            Content::File(data) => (FileKind::File, data.len() as u64),
+            Content::Dir => (FileKind::Dir, 0),
+            Content::Symlink(target) => (FileKind::Symlink, target.as_os_str().len() as u64),
+        };
+        Stat {
+            kind,
+            length,
+            allocated: length,
+            modified: node.modified,
+            inode: node.inode,
+            mode: node.mode,
+            #[cfg(target_os = "windows")]
+            file_attr: 0,
+        }
+    }
+
+    // Checks that the parent of `key` is a directory
+    fn check_parent(&self, key: &Path, path: &Path) -> io::Result<()> {
+        let parent = key.parent().unwrap_or(Path::new(""));
+        match self.nodes.get(parent) {
+            Some(Node { content: Content::Dir, .. }) => Ok(()),
+            None if parent.as_os_str().is_empty() => Ok(()),
+            Some(_) => Err(io::Error::other(format!("The parent of {} isn't a directory", path.to_string_lossy()))),
+            None => Err(not_found(parent)),
+        }
+    }
+
+    fn insert(&mut self, key: PathBuf, content: Content, mode: u32) {
+        self.next_inode += 1;
+        let node = Node { content, modified: SystemTime::now(), inode: self.next_inode, mode };
+        self.nodes.insert(key, node);
+    }
+}
+
+impl MemoryFs {
+    /// An empty filesystem.
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    fn tree(&self) -> io::Result<std::sync::MutexGuard<'_, Tree>> {
+        self.tree.lock().map_err(|_| poisoned())
+    }
+}
+
+// The writer returned by `MemoryFs::create`. Every write is appended to the file in the tree.
+struct MemoryWriter<'a> {
+    fs: &'a MemoryFs,
+    key: PathBuf,
+}
+
+impl Write for MemoryWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tree = self.fs.tree()?;
+        match tree.nodes.get_mut(&self.key) {
+            Some(Node { content: Content::File(data), modified, .. }) => {
+                data.extend_from_slice(buf);
+                *modified = SystemTime::now();
+                Ok(buf.len())
+            }
+            _ => Err(not_found(&self.key)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The file returned by `MemoryFs::patch`, with a copy of its basis
+struct MemoryPatch<'a> {
+    file: Box<dyn Write + 'a>,
+    basis: Vec<u8>,
+}
+
+impl Write for MemoryPatch<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl FilePatch for MemoryPatch<'_> {
+    fn copy_from_basis(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let range = usize::try_from(offset).ok().zip(usize::try_from(offset + length).ok());
+        let data = range.and_then(|(start, end)| self.basis.get(start..end));
+        let data = data.ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "The range is past the end of the basis"))?;
+        self.file.write_all(data)
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsEntry>> {
+        let tree = self.tree()?;
+        let dir = tree.resolve(path)?;
+        if !tree.stat(&dir).is_dir() {
+            return Err(io::Error::other(format!("{} isn't a directory", path.to_string_lossy())));
+        }
+        let mut entries = vec![];
+        for key in tree.nodes.keys().filter(|key| key.parent() == Some(dir.as_path())) {
+            // Like the local filesystem, the links are followed, and broken links are left out
+            if let Ok(target) = tree.resolve(key) {
+                let name = key.file_name().unwrap_or_default().to_string_lossy().to_string();
+                entries.push(VfsEntry { name, stat: tree.stat(&target) });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Stat> {
+        let tree = self.tree()?;
+        let key = tree.resolve(path)?;
+        Ok(tree.stat(&key))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let tree = self.tree()?;
+        let key = tree.resolve(path)?;
+        match tree.nodes.get(&key) {
+            Some(Node { content: Content::File(data), .. }) => Ok(Box::new(Cursor::new(data.clone()))),
+            _ => Err(io::Error::other(format!("{} isn't a file", path.to_string_lossy()))),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + '_>> {
+        let mut tree = self.tree()?;
+        let key = match tree.resolve(path) {
+            Ok(key) => key,
+            Err(err) if err.kind() == ErrorKind::NotFound => normalize(path),
+            Err(err) => return Err(err),
+        };
+        match tree.nodes.get_mut(&key) {
+            Some(Node { content: Content::File(data), modified, .. }) => {
+                data.clear();
+                *modified = SystemTime::now();
+            }
+            Some(_) => return Err(io::Error::other(format!("{} isn't a file", path.to_string_lossy()))),
+            None => {
+                tree.check_parent(&key, path)?;
+                tree.insert(key.clone(), Content::File(vec![]), 0o644);
+            }
+        }
+        Ok(Box::new(MemoryWriter { fs: self, key }))
+    }
+
+    fn patch(&self, basis: &Path, path: &Path) -> io::Result<Box<dyn FilePatch + '_>> {
+        let basis = self.read(basis)?;
+        Ok(Box::new(MemoryPatch { file: self.create(path)?, basis }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut tree = self.tree()?;
+        let mut key = PathBuf::new();
+        for component in normalize(path).components() {
+            key.push(component);
+            match tree.nodes.get(&key) {
+                Some(Node { content: Content::Dir, .. }) => {}
+                Some(_) => return Err(io::Error::other(format!("{} isn't a directory", key.to_string_lossy()))),
+                None => tree.insert(key.clone(), Content::Dir, 0o755),
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut tree = self.tree()?;
+        let (from, to) = (normalize(from), normalize(to));
+        if !tree.nodes.contains_key(&from) {
+            return Err(not_found(&from));
+        }
+        tree.check_parent(&to, &to)?;
+        if let Some(Node { content: Content::Dir, .. }) = tree.nodes.get(&to) {
+            return Err(io::Error::other(format!("{} is a directory", to.to_string_lossy())));
+        }
+        // The entry and everything below it are moved
+        let moved: Vec<PathBuf> = tree.nodes.keys().filter(|key| key.starts_with(&from)).cloned().collect();
+        for key in moved {
+            if let Some(node) = tree.nodes.remove(&key) {
+                let new_key = to.join(key.strip_prefix(&from).unwrap_or(Path::new("")));
+                tree.nodes.insert(normalize(&new_key), node);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut tree = self.tree()?;
+        let key = normalize(path);
+        if !tree.nodes.contains_key(&key) {
+            return Err(not_found(path));
+        }
+        if tree.nodes.keys().any(|other| other.parent() == Some(key.as_path())) {
+            return Err(io::Error::other(format!("The directory {} isn't empty", path.to_string_lossy())));
+        }
+        tree.nodes.remove(&key);
+        Ok(())
+    }
+
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let mut tree = self.tree()?;
+        let key = tree.resolve(path)?;
+        let node = tree.nodes.get_mut(&key).ok_or_else(|| not_found(path))?;
+        node.modified = modified;
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let mut tree = self.tree()?;
+        let key = normalize(link);
+        if tree.nodes.contains_key(&key) {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} already exists", link.to_string_lossy())));
+        }
+        tree.check_parent(&key, link)?;
+        tree.insert(key, Content::Symlink(target.to_path_buf()), 0o777);
+        Ok(())
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut tree = self.tree()?;
+        let key = tree.resolve(path)?;
+        let node = tree.nodes.get_mut(&key).ok_or_else(|| not_found(path))?;
+        node.mode = mode;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConflictPolicy, DirConfig};
+    use crate::fat::DestFs;
+    use crate::filter::Filters;
+    use crate::output::{Event, NoReporter, Reporter};
+    use crate::plan::{Action, plan_backup};
+    use crate::xattrs::Preserve;
+    use crate::{CopyMethod, EntryType, FileEntry, execute_plan_in, recursive_listing_in};
+    use std::time::Duration;
+
+    // Keeps the events of a backup
+    #[derive(Default)]
+    struct Events(Mutex<Vec<Event>>);
+
+    impl Reporter for Events {
+        fn report(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    fn listing(fs: &MemoryFs, root: &str) -> Vec<FileEntry> {
+        let mut data = vec![];
+        recursive_listing_in(fs, Path::new(root), Path::new(root), &mut data, false, Preserve::default(), &Filters::default());
+        data.sort_by(|a, b| a.name.cmp(&b.name));
+        data
+    }
+
+    fn action_of<'a>(plan: &'a [crate::plan::PlannedEntry], name: &str) -> &'a Action {
+        &plan.iter().find(|planned| planned.entry.name == name).unwrap().action
+    }
+
+    #[test]
+    fn files_directories_and_links() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/a/b")).unwrap();
+        fs.write(Path::new("a/b/file"), b"data").unwrap();
+        assert_eq!(fs.read(Path::new("/a/./b/file")).unwrap(), b"data");
+        assert!(fs.write(Path::new("missing/file"), b"").is_err());
+
+        fs.symlink(Path::new("b/file"), Path::new("a/link")).unwrap();
+        assert_eq!(fs.read(Path::new("a/link")).unwrap(), b"data");
+        let mut names: Vec<String> = fs.read_dir(Path::new("a")).unwrap().into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        assert_eq!(names, ["b", "link"]);
+
+        assert!(fs.remove(Path::new("a/b")).is_err());
+        fs.rename(Path::new("a/b"), Path::new("c")).unwrap();
+        assert_eq!(fs.read(Path::new("c/file")).unwrap(), b"data");
+        assert!(!fs.exists(Path::new("a/b/file")));
+        // The link now points to nothing, and is left out of the listing
+        assert_eq!(fs.read_dir(Path::new("a")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn sources_are_listed_without_hidden_entries() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("src/docs")).unwrap();
+        fs.write(Path::new("src/docs/a.txt"), b"a").unwrap();
+        fs.write(Path::new("src/.hidden"), b"h").unwrap();
+        fs.write(Path::new("src/b.txt"), b"bb").unwrap();
+
+        let data = listing(&fs, "src");
+        let names: Vec<&str> = data.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["b.txt", "docs", "docs/a.txt"]);
+        assert_eq!(data[0].length, 2);
+        assert_eq!(data[1].e_type, EntryType::Dir);
+    }
+
+    #[test]
+    fn backups_are_planned_and_run_in_memory() {
+        let (src_fs, dest_fs) = (MemoryFs::new(), MemoryFs::new());
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let new = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        src_fs.create_dir_all(Path::new("src/docs")).unwrap();
+        src_fs.write(Path::new("src/docs/new.txt"), b"new file").unwrap();
+        src_fs.write(Path::new("src/same.txt"), b"same").unwrap();
+        src_fs.write(Path::new("src/changed.txt"), b"changed").unwrap();
+        dest_fs.create_dir_all(Path::new("dest")).unwrap();
+        dest_fs.write(Path::new("dest/same.txt"), b"same").unwrap();
+        dest_fs.write(Path::new("dest/changed.txt"), b"before").unwrap();
+        for path in ["src/same.txt", "src/changed.txt"] {
+            src_fs.set_times(Path::new(path), new).unwrap();
+        }
+        dest_fs.set_times(Path::new("dest/same.txt"), new).unwrap();
+        dest_fs.set_times(Path::new("dest/changed.txt"), old).unwrap();
+
+        let (src_meta, dest_meta) = (listing(&src_fs, "src"), listing(&dest_fs, "dest"));
+        let plan = plan_backup(&src_meta, &dest_meta, "src", &Filters::default(), ConflictPolicy::Rename, Preserve::default(), Duration::ZERO, DestFs::Native);
+        assert_eq!(action_of(&plan, "docs"), &Action::CreateDir);
+        assert_eq!(action_of(&plan, "docs/new.txt"), &Action::Copy);
+        assert_eq!(action_of(&plan, "same.txt"), &Action::Identical);
+        let Action::CopyAs(renamed) = action_of(&plan, "changed.txt").clone() else {
+            panic!("changed.txt isn't renamed");
+        };
+
+        let summary = execute_plan_in(&src_fs, Path::new("src"), &dest_fs, Path::new("dest"), plan, &DirConfig::default(), &NoReporter).unwrap();
+        assert_eq!((summary.copied, summary.renamed, summary.identical, summary.dirs_created), (1, 1, 1, 1));
+        assert_eq!(dest_fs.read(Path::new("dest/docs/new.txt")).unwrap(), b"new file");
+        assert_eq!(dest_fs.read(&Path::new("dest").join(&renamed)).unwrap(), b"changed");
+        assert_eq!(dest_fs.read(Path::new("dest/changed.txt")).unwrap(), b"before");
+        // The copies keep the modified time of their source, so the next backup finds nothing to do
+        let plan = plan_backup(&src_meta, &listing(&dest_fs, "dest"), "src", &Filters::default(), ConflictPolicy::Skip, Preserve::default(), Duration::ZERO, DestFs::Native);
+        assert_eq!(action_of(&plan, "docs/new.txt"), &Action::Identical);
+    }
+
+    #[test]
+    fn large_overwritten_files_are_updated_with_a_delta() {
+        let (src_fs, dest_fs) = (MemoryFs::new(), MemoryFs::new());
+        let mut data: Vec<u8> = (0..2 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        dest_fs.create_dir_all(Path::new("dest")).unwrap();
+        dest_fs.write(Path::new("dest/disk.img"), &data).unwrap();
+        dest_fs.set_times(Path::new("dest/disk.img"), SystemTime::UNIX_EPOCH).unwrap();
+        data[500_000..500_010].fill(0);
+        src_fs.create_dir_all(Path::new("src")).unwrap();
+        src_fs.write(Path::new("src/disk.img"), &data).unwrap();
+
+        let plan = plan_backup(&listing(&src_fs, "src"), &listing(&dest_fs, "dest"), "src", &Filters::default(), ConflictPolicy::Overwrite, Preserve::default(), Duration::ZERO, DestFs::Native);
+        assert_eq!(action_of(&plan, "disk.img"), &Action::Overwrite);
+        let events = Events::default();
+        let summary = execute_plan_in(&src_fs, Path::new("src"), &dest_fs, Path::new("dest"), plan, &DirConfig::default(), &events).unwrap();
+        assert_eq!(summary.overwritten, 1);
+        assert!(matches!(events.0.lock().unwrap()[..], [Event::Overwritten { method: Some(CopyMethod::Delta), .. }]));
+        assert_eq!(dest_fs.read(Path::new("dest/disk.img")).unwrap(), data);
+        assert_eq!(dest_fs.read_dir(Path::new("dest")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn attribute_hashes_are_only_read_when_preserved() {
+        let root = std::env::temp_dir().join(format!("ebod-xattrs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("tagged"), "data").unwrap();
+        if LocalFs.set_xattr(&root.join("tagged"), "user.ebod.test", b"value").is_err() {
+            eprintln!("The temporary directory has no extended attributes, skipping the test");
+            let _ = std::fs::remove_dir_all(&root);
+            return;
+        }
+        let list = |preserve: Preserve| {
+            let mut data = vec![];
+            recursive_listing_in(&LocalFs, &root, &root, &mut data, false, preserve, &Filters::default());
+            data.pop().unwrap()
+        };
+        assert_eq!(list(Preserve::default()).xattrs, None);
+        assert_eq!(list(Preserve { xattrs: false, acls: true }).xattrs, None);
+        assert!(list(Preserve { xattrs: true, acls: false }).xattrs.is_some());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
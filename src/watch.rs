@@ -0,0 +1,277 @@
+//! Continuous backup of a directory.
+//!
+//! `ebod watch` backs up the source once, and then waits for the changes reported by the filesystem (inotify on Linux,
+//! through the `notify` crate). The changes are debounced: once a change arrives, ebod waits until no change has
+//! arrived for the debounce delay, and then backs up only the touched paths with the same planner and copier as
+//! `backup`. When events were lost, like when the inotify queue overflowed, the whole source is scanned again.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::api::{Backup, BackupOptions};
+use crate::config::DirConfig;
+use crate::drive::DRIVE_ID_FILE;
+use crate::fat::NameMap;
+use crate::filter::Filters;
+use crate::output::OutputReporter;
+use crate::plan::plan_dirs;
+use crate::vfs::LocalFs;
+use crate::xattrs::Preserve;
+use crate::{EntryType, FileEntry, LogType, execute_plan, file_entry, ignore_rules_for, listing_with_rules, log};
+
+/// A batch of debounced changes is backed up at the latest after this many debounce delays, even if the source keeps
+/// changing.
+const MAX_DELAYS_PER_BATCH: u32 = 10;
+
+/// What the backup does for an event of the watcher.
+///
+/// # Values in Enum
+/// * `Ignored` -> Nothing to back up, like a file that was only read or a change under `.ebod`
+/// * `Paths(Vec<PathBuf>)` -> The paths of the source to back up
+/// * `Rescan` -> Events were lost, so the whole source is scanned again
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    Ignored,
+    Paths(Vec<PathBuf>),
+    Rescan,
+}
+
+// The change that `event` makes to the source `src`
+fn change_of(src: &Path, event: Event) -> Change {
+    if event.need_rescan() {
+        return Change::Rescan;
+    }
+    if matches!(event.kind, EventKind::Access(_)) {
+        return Change::Ignored;
+    }
+    let paths: Vec<PathBuf> = event
+        .paths
+        .into_iter()
+        .filter(|path| path.starts_with(src) && !path.starts_with(src.join(".ebod")))
+        .inspect(|path| log(LogType::Trace, &format!("{:?} reported for {}", event.kind, path.to_string_lossy())))
+        .collect();
+    if paths.is_empty() { Change::Ignored } else { Change::Paths(paths) }
+}
+
+/// The changes collected during a debounce window.
+#[derive(Debug, Default)]
+struct Batch {
+    paths: BTreeSet<PathBuf>,
+    rescan: bool,
+}
+
+impl Batch {
+    fn add(&mut self, src: &Path, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                log(LogType::Err, &format!("Error from the watcher: {}", err));
+                self.rescan = true;
+                return;
+            }
+        };
+        match change_of(src, event) {
+            Change::Ignored => {},
+            Change::Paths(paths) => self.paths.extend(paths),
+            Change::Rescan => self.rescan = true,
+        }
+    }
+}
+
+// Waits for the next event from `rx`, and collects the events that follow it until none arrived for `debounce`, or
+// for at most `MAX_DELAYS_PER_BATCH` debounce delays
+fn next_batch(rx: &Receiver<notify::Result<Event>>, src: &Path, debounce: Duration) -> Result<Batch, String> {
+    let first = rx.recv().map_err(|_| String::from("The watcher stopped"))?;
+    let mut batch = Batch::default();
+    batch.add(src, first);
+    let started = Instant::now();
+    while started.elapsed() < debounce * MAX_DELAYS_PER_BATCH {
+        match rx.recv_timeout(debounce) {
+            Ok(event) => batch.add(src, event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(batch)
+}
+
+/// Backs up `src` into `dest`, and then keeps backing up the changes made in `src` until the process is stopped.
+///
+/// # Input
+/// * `src: &Path` -> The source directory
+/// * `dest: &Path` -> The destination directory
+/// * `filters: &Filters` -> The filters given with `--include`, `--exclude`, `--min-size`, etc.
+/// * `config: &DirConfig` -> The settings merged from `.ebod/config.toml` and the command line
+/// * `debounce: Duration` -> How long the source must be quiet before the changes are backed up
+///
+/// # Output: `Result<(), String>`
+/// Only returns when the watcher couldn't be started or stopped working.
+///
+/// # Rules followed:
+/// 1. Files deleted from the source are kept in the destination, like `backup` keeps them.
+/// 2. Changed files are handled like in `backup`, following the `conflict` setting.
+/// 3. A failed backup of a batch is logged, and the watching goes on.
+pub fn watch(src: &Path, dest: &Path, filters: &Filters, config: &DirConfig, debounce: Duration) -> Result<(), String> {
+    let src = fs::canonicalize(src).map_err(|_| format!("Couldn't resolve {}", src.to_string_lossy()))?;
+    full_backup(&src, dest, filters, config)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(|e| format!("Couldn't start the watcher: {}", e))?;
+    watcher
+        .watch(&src, RecursiveMode::Recursive)
+        .map_err(|e| format!("Couldn't watch {}: {}", src.to_string_lossy(), e))?;
+    log(LogType::Info, &format!("Watching {} for changes. Press Ctrl+C to stop", src.to_string_lossy()));
+
+    loop {
+        let batch = next_batch(&rx, &src, debounce)?;
+        let result = if batch.rescan {
+            log(LogType::Info, &format!("Some changes were missed. Scanning {} again", src.to_string_lossy()));
+            full_backup(&src, dest, filters, config)
+        } else if batch.paths.is_empty() {
+            continue;
+        } else {
+            incremental_backup(&src, dest, &batch.paths, filters, config)
+        };
+        if let Err(err) = result {
+            log(LogType::Err, &err);
+        }
+    }
+}
+
+// Scans both directories completely and backs up everything, like `ebod backup`
+fn full_backup(src: &Path, dest: &Path, filters: &Filters, config: &DirConfig) -> Result<(), String> {
+    let options = BackupOptions::from_config(config).filters(filters.clone());
+    let report = Backup::new(src, dest).options(options).run(&OutputReporter)?;
+    log(LogType::Ok, &format!("{} was backed up into {} ({} bytes copied)", src.to_string_lossy(), dest.to_string_lossy(), report.summary.bytes));
+    Ok(())
+}
+
+// Backs up only the touched paths. Deleted paths are skipped, and touched directories are listed with their contents,
+// so that a directory moved into the source is backed up as a whole.
+fn incremental_backup(src: &Path, dest: &Path, paths: &BTreeSet<PathBuf>, filters: &Filters, config: &DirConfig) -> Result<(), String> {
+    let mut entries: BTreeMap<String, FileEntry> = BTreeMap::new();
+    for path in paths {
+        for entry in list_touched(src, path, config.include_hidden, config.preserve(), filters) {
+            entries.insert(entry.name.clone(), entry);
+        }
+    }
+    // Directories that already exist in the destination (like the parents of a touched file) need no action
+    let src_meta: Vec<FileEntry> = entries
+        .into_values()
+        .filter(|entry| entry.e_type == EntryType::File || !dest.join(&entry.name).is_dir())
+        .collect();
+    if src_meta.is_empty() {
+        return Ok(());
+    }
+    // On FAT destinations, the entries can be stored under other paths
+    let names = NameMap::load_in(&LocalFs, dest);
+    let dest_meta: Vec<FileEntry> = src_meta
+        .iter()
+        .flat_map(|entry| {
+            let stored = names.stored_paths(&entry.name);
+            if stored.is_empty() { vec![entry.name.clone()] } else { stored }
+        })
+        .filter_map(|name| {
+            let path = dest.join(&name);
+            fs::metadata(&path).ok().map(|meta| file_entry(&path, dest, &meta, config.preserve()))
+        })
+        .collect();
+    let dest_meta = names.restore(dest_meta);
+
+    let plan = plan_dirs(&src_meta, &dest_meta, &LocalFs, dest, "src", filters, config);
+    let summary = execute_plan(src, dest, plan, config, &OutputReporter)?;
+    log(
+        LogType::Ok,
+        &format!("{} changed entries were backed up ({} bytes copied)", summary.copied + summary.renamed + summary.overwritten + summary.dirs_created, summary.bytes),
+    );
+    Ok(())
+}
+
+// Lists `path` and the directories leading to it from `src`, applying the same hidden, .ebodignore and filter rules
+// as `recursive_listing_with_filters`. A directory is listed with its contents.
+fn list_touched(src: &Path, path: &Path, include_hidden: bool, preserve: Preserve, filters: &Filters) -> Vec<FileEntry> {
+    let mut data = vec![];
+    let Ok(relative) = path.strip_prefix(src) else {
+        return data;
+    };
+    let mut rules = ignore_rules_for(src, src);
+
+    let mut current = src.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        let hidden = component.as_os_str().to_string_lossy().starts_with('.');
+        let Ok(meta) = fs::metadata(&current) else {
+            return data;
+        };
+        if (!include_hidden && hidden) || rules.is_ignored(&current, meta.is_dir()) {
+            return data;
+        }
+        if current.parent() == Some(src) && component.as_os_str() == DRIVE_ID_FILE {
+            return data;
+        }
+        let entry = file_entry(&current, src, &meta, preserve);
+        if filters.check(&entry).is_err() {
+            return data;
+        }
+        data.push(entry);
+        if meta.is_dir() {
+            rules.enter(&current);
+        }
+    }
+    if path.is_dir() {
+        listing_with_rules(&LocalFs, path, src, &mut data, include_hidden, preserve, filters, &mut rules);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, Flag, ModifyKind};
+
+    fn event(kind: EventKind, paths: &[&str]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| event.add_path(PathBuf::from(path)))
+    }
+
+    #[test]
+    fn events_are_mapped_to_changes() {
+        let src = Path::new("/src");
+        let created = event(EventKind::Create(CreateKind::File), &["/src/a.txt"]);
+        assert_eq!(change_of(src, created), Change::Paths(vec![PathBuf::from("/src/a.txt")]));
+        let renamed = event(EventKind::Modify(ModifyKind::Any), &["/src/old", "/src/new"]);
+        assert_eq!(change_of(src, renamed), Change::Paths(vec![PathBuf::from("/src/old"), PathBuf::from("/src/new")]));
+
+        // Reads, the metadata of ebod and the paths outside of the source are left alone
+        assert_eq!(change_of(src, event(EventKind::Access(AccessKind::Any), &["/src/a.txt"])), Change::Ignored);
+        assert_eq!(change_of(src, event(EventKind::Any, &["/src/.ebod/metadata.json"])), Change::Ignored);
+        assert_eq!(change_of(src, event(EventKind::Any, &["/srcs/a.txt", "/other"])), Change::Ignored);
+        let mixed = event(EventKind::Any, &["/src/.ebod/config.toml", "/src/.ebodignore"]);
+        assert_eq!(change_of(src, mixed), Change::Paths(vec![PathBuf::from("/src/.ebodignore")]));
+
+        assert_eq!(change_of(src, Event::new(EventKind::Other).set_flag(Flag::Rescan)), Change::Rescan);
+    }
+
+    #[test]
+    fn events_are_collected_into_batches() {
+        let src = Path::new("/src");
+        let (tx, rx) = mpsc::channel();
+        tx.send(Ok(event(EventKind::Any, &["/src/a.txt"]))).unwrap();
+        tx.send(Ok(event(EventKind::Any, &["/src/b.txt", "/src/a.txt"]))).unwrap();
+        tx.send(Ok(event(EventKind::Access(AccessKind::Any), &["/src/c.txt"]))).unwrap();
+        let batch = next_batch(&rx, src, Duration::from_millis(10)).unwrap();
+        assert_eq!(batch.paths, BTreeSet::from([PathBuf::from("/src/a.txt"), PathBuf::from("/src/b.txt")]));
+        assert!(!batch.rescan);
+
+        // An error of the watcher means that events may have been lost
+        tx.send(Err(notify::Error::generic("queue overflow"))).unwrap();
+        tx.send(Ok(event(EventKind::Any, &["/src/d.txt"]))).unwrap();
+        let batch = next_batch(&rx, src, Duration::from_millis(10)).unwrap();
+        assert!(batch.rescan);
+
+        drop(tx);
+        assert!(next_batch(&rx, src, Duration::from_millis(10)).is_err());
+    }
+}
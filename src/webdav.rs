@@ -0,0 +1,519 @@
+//! WebDAV backend.
+//!
+//! `WebDavFs` reaches a WebDAV server (Nextcloud, ownCloud, Apache `mod_dav`, nginx…) over HTTP: directories are
+//! listed with `PROPFIND`, files are read with `GET` and written with `PUT`, directories are created with `MKCOL`,
+//! and files are renamed with `MOVE`. The paths given to the backend are the paths of the URLs on the server, like
+//! `/remote.php/dav/files/me/photos` for Nextcloud.
+//!
+//! The user is taken from the URL (`davs://me@cloud.example.com/...`), and the password from the `EBOD_WEBDAV_PASSWORD`
+//! environment variable. They are sent with basic authentication, so `davs://` (HTTPS) should be used over networks
+//! that aren't trusted.
+//!
+//! Modified times are set with a `PROPPATCH` of `lastmodified`, which Nextcloud and ownCloud accept. Other servers
+//! keep the time of the upload, which is why the stored index of the destination records the time of the source.
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ureq::http::{Request, Response};
+use ureq::{Agent, AsSendBody, Body};
+
+use crate::http::{dir_stat, element, elements, file_stat, parse_http_date, percent_decode, uri_encode};
+use crate::vfs::{Stat, Vfs, VfsEntry};
+
+/// The environment variable holding the password of the WebDAV user.
+pub const PASSWORD_VAR: &str = "EBOD_WEBDAV_PASSWORD";
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/></d:prop></d:propfind>"#;
+
+// Numbers the temporary files of the uploads
+static SPOOL_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// A WebDAV server.
+///
+/// # Members
+/// * `secure: bool` -> Whether the server is reached over HTTPS
+/// * `host: String` -> The host of the server, with its port if it isn't the default one
+/// * `user: Option<String>` -> The user to log in as
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DavTarget {
+    pub secure: bool,
+    pub host: String,
+    pub user: Option<String>,
+}
+
+impl DavTarget {
+    fn base_url(&self) -> String {
+        format!("{}://{}", if self.secure { "https" } else { "http" }, self.host)
+    }
+}
+
+// The path of the URL of `path`, without the trailing `/`
+fn url_path(path: &Path) -> String {
+    let parts: Vec<String> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    format!("/{}", parts.join("/"))
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, format!("WebDAV doesn't support {}", what))
+}
+
+/// A directory tree of a WebDAV server, seen as a filesystem.
+pub struct WebDavFs {
+    base: String,
+    agent: Agent,
+    authorization: Option<String>,
+}
+
+impl WebDavFs {
+    /// Connects to the server of `target`, and checks that the credentials are accepted for `root`.
+    pub fn connect(target: &DavTarget, root: &Path) -> Result<WebDavFs, String> {
+        let authorization = match &target.user {
+            Some(user) => {
+                let password = std::env::var(PASSWORD_VAR).map_err(|_| format!("{} isn't set for the user {}", PASSWORD_VAR, user))?;
+                Some(format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password))))
+            },
+            None => None,
+        };
+        let config = Agent::config_builder()
+            .http_status_as_error(false)
+            .allow_non_standard_methods(true)
+            .timeout_global(Some(Duration::from_secs(300)))
+            .build();
+        let fs = WebDavFs { base: target.base_url(), agent: config.into(), authorization };
+        // The destination doesn't have to exist yet, but the server must answer and let us in
+        match fs.stat(root) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(format!("Couldn't reach {}{}: {}", fs.base, url_path(root), err)),
+            _ => Ok(fs),
+        }
+    }
+
+    fn url(&self, path: &Path) -> String {
+        format!("{}{}", self.base, uri_encode(&url_path(path), true))
+    }
+
+    fn request(&self, method: &str, url: &str, headers: &[(&str, &str)], body: impl AsSendBody) -> io::Result<Response<Body>> {
+        let mut builder = Request::builder().method(method).uri(url);
+        if let Some(authorization) = &self.authorization {
+            builder = builder.header("authorization", authorization.as_str());
+        }
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let request = builder.body(body).map_err(io::Error::other)?;
+        self.agent.run(request).map_err(io::Error::other)
+    }
+
+    // Sends a request and turns the error statuses into errors
+    fn checked(&self, method: &str, url: &str, headers: &[(&str, &str)], body: impl AsSendBody) -> io::Result<Response<Body>> {
+        let response = self.request(method, url, headers, body)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let kind = match status.as_u16() {
+            404 => ErrorKind::NotFound,
+            401 | 403 => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Other,
+        };
+        Err(io::Error::new(kind, format!("{} {}: HTTP status {}", method, url, status)))
+    }
+
+    // The entries of the `PROPFIND` of `url`: their decoded paths, without the trailing `/`, and their metadata
+    fn propfind(&self, url: &str, depth: &str) -> io::Result<Vec<(String, Stat)>> {
+        let headers = [("depth", depth), ("content-type", "application/xml; charset=utf-8")];
+        let mut response = self.checked("PROPFIND", url, &headers, PROPFIND_BODY.as_bytes())?;
+        let text = response.body_mut().with_config().limit(u64::MAX).read_to_string().map_err(io::Error::other)?;
+        let mut entries = vec![];
+        for entry in elements(&text, "response") {
+            let Some(href) = element(entry, "href") else {
+                continue;
+            };
+            // The href is a path, or a whole URL for some servers
+            let path = match href.split_once("://") {
+                Some((_, rest)) => rest.find('/').map(|start| &rest[start..]).unwrap_or("/").to_string(),
+                None => href,
+            };
+            let path = percent_decode(&path).trim_end_matches('/').to_string();
+            let modified = element(entry, "getlastmodified").and_then(|date| parse_http_date(&date)).unwrap_or(UNIX_EPOCH);
+            let is_dir = elements(entry, "resourcetype").iter().any(|kind| !elements(kind, "collection").is_empty());
+            let stat = if is_dir {
+                dir_stat(modified)
+            } else {
+                file_stat(element(entry, "getcontentlength").and_then(|length| length.parse().ok()).unwrap_or(0), modified)
+            };
+            entries.push((path, stat));
+        }
+        Ok(entries)
+    }
+}
+
+// Uploads a file. The data is spooled into a temporary file, so that it is sent with a known length, which every
+// server accepts. The upload is done by `flush`, or when the writer is dropped.
+struct DavWriter<'a> {
+    fs: &'a WebDavFs,
+    url: String,
+    spool: File,
+    spool_path: PathBuf,
+    finished: bool,
+}
+
+impl DavWriter<'_> {
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.spool.seek(SeekFrom::Start(0))?;
+        self.fs.checked("PUT", &self.url, &[], &self.spool).map(|_| ())
+    }
+}
+
+impl Write for DavWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other(format!("The upload of {} is already done", self.url)));
+        }
+        self.spool.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.finish()
+    }
+}
+
+impl Drop for DavWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+        let _ = remove_file(&self.spool_path);
+    }
+}
+
+impl Vfs for WebDavFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsEntry>> {
+        let dir = url_path(path);
+        let url = format!("{}/", self.url(path).trim_end_matches('/'));
+        let entries = self.propfind(&url, "1")?;
+        Ok(entries
+            .into_iter()
+            .filter(|(entry, _)| entry.trim_end_matches('/') != dir.trim_end_matches('/'))
+            .filter_map(|(entry, stat)| {
+                let name = entry.rsplit('/').next()?.to_string();
+                (!name.is_empty()).then_some(VfsEntry { name, stat })
+            })
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Stat> {
+        let entries = self.propfind(&self.url(path), "0")?;
+        entries
+            .into_iter()
+            .next()
+            .map(|(_, stat)| stat)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("{} doesn't exist", url_path(path))))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let response = self.checked("GET", &self.url(path), &[], ())?;
+        Ok(Box::new(response.into_body().into_reader()))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + '_>> {
+        let count = SPOOL_COUNT.fetch_add(1, Ordering::Relaxed);
+        let spool_path = std::env::temp_dir().join(format!("ebod-webdav-{}-{}", std::process::id(), count));
+        let spool = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&spool_path)?;
+        Ok(Box::new(DavWriter { fs: self, url: self.url(path), spool, spool_path, finished: false }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.stat(path).is_ok_and(|stat| stat.is_dir()) {
+            return Ok(());
+        }
+        let mut dir = PathBuf::from("/");
+        for component in path.components() {
+            if let Component::Normal(name) = component {
+                dir.push(name);
+            } else {
+                continue;
+            }
+            let url = format!("{}/", self.url(&dir));
+            let response = self.request("MKCOL", &url, &[], ())?;
+            // 405 Method Not Allowed: the collection already exists
+            match response.status().as_u16() {
+                200..=299 | 405 => {},
+                status => return Err(io::Error::other(format!("MKCOL {}: HTTP status {}", url, status))),
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let destination = self.url(to);
+        self.checked("MOVE", &self.url(from), &[("destination", &destination), ("overwrite", "T")], ()).map(|_| ())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.checked("DELETE", &self.url(path), &[], ()).map(|_| ())
+    }
+
+    fn set_times(&self, path: &Path, modified: SystemTime) -> io::Result<()> {
+        let secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?><d:propertyupdate xmlns:d="DAV:"><d:set><d:prop><d:lastmodified>{}</d:lastmodified></d:prop></d:set></d:propertyupdate>"#,
+            secs
+        );
+        let headers = [("content-type", "application/xml; charset=utf-8")];
+        let mut response = self.checked("PROPPATCH", &self.url(path), &headers, body.as_bytes())?;
+        let text = response.body_mut().read_to_string().map_err(io::Error::other)?;
+        // Every property gets its own status in the multistatus response
+        match elements(&text, "status").iter().all(|status| status.contains(" 200 ")) {
+            true => Ok(()),
+            false => Err(unsupported("setting the modified time")),
+        }
+    }
+
+    fn symlink(&self, _target: &Path, _link: &Path) -> io::Result<()> {
+        Err(unsupported("symbolic links"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::test_server::{self, TestRequest, TestResponse};
+    use crate::utc_datetime;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    fn target(address: &str) -> DavTarget {
+        DavTarget { secure: false, host: address.to_string(), user: None }
+    }
+
+    fn http_date(secs: u64) -> String {
+        const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        let (year, month, day, hour, minute, second) = utc_datetime(secs);
+        // The day of the week isn't read
+        format!("Mon, {:02} {} {} {:02}:{:02}:{:02} GMT", day, MONTHS[month as usize - 1], year, hour, minute, second)
+    }
+
+    // An entry of the mock server: the contents of a file, or `None` for a collection, and its modified time
+    #[derive(Debug, Clone)]
+    struct Node {
+        data: Option<Vec<u8>>,
+        modified: u64,
+    }
+
+    // A WebDAV server kept in memory. Paths are decoded and have no trailing `/`, the root is `""`.
+    #[derive(Debug, Default)]
+    struct MockDav {
+        nodes: BTreeMap<String, Node>,
+        // Every request, with its decoded path and without its body
+        requests: Vec<TestRequest>,
+        // Whether PROPPATCH of `lastmodified` is refused, like Apache does
+        refuse_proppatch: bool,
+    }
+
+    fn parent(path: &str) -> &str {
+        path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+    }
+
+    fn multistatus(responses: String) -> TestResponse {
+        TestResponse::new(207).header("Content-Type", "application/xml; charset=utf-8").body(format!(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">{}</d:multistatus>"#, responses))
+    }
+
+    impl MockDav {
+        fn exists_dir(&self, path: &str) -> bool {
+            path.is_empty() || self.nodes.get(path).is_some_and(|node| node.data.is_none())
+        }
+
+        fn handle(&mut self, request: TestRequest) -> TestResponse {
+            let path = percent_decode(&request.path).trim_end_matches('/').to_string();
+        let (method, query, headers) = (request.method.clone(), request.query.clone(), request.headers.clone());
+        self.requests.push(TestRequest { method, path: path.clone(), query, headers, body: vec![] });
+            let node = self.nodes.get(&path).cloned();
+            match request.method.as_str() {
+                "PROPFIND" => {
+                    if node.is_none() && !path.is_empty() {
+                        return TestResponse::new(404);
+                    }
+                    let mut responses = self.propstat(&path, &request);
+                    if request.header("depth") == Some("1") {
+                        let children: Vec<String> = self.nodes.keys().filter(|key| parent(key) == path && !key.is_empty()).cloned().collect();
+                        for child in children {
+                            responses.push_str(&self.propstat(&child, &request));
+                        }
+                    }
+                    multistatus(responses)
+                },
+                "MKCOL" if node.is_some() => TestResponse::new(405),
+                "MKCOL" | "PUT" if !self.exists_dir(parent(&path)) => TestResponse::new(409),
+                "MKCOL" => {
+                    self.nodes.insert(path, Node { data: None, modified: 1_700_000_000 });
+                    TestResponse::new(201)
+                },
+                "PUT" => {
+                    self.nodes.insert(path, Node { data: Some(request.body), modified: 1_700_000_000 });
+                    TestResponse::new(201)
+                },
+                "GET" => match node.and_then(|node| node.data) {
+                    Some(data) => TestResponse::new(200).body(data),
+                    None => TestResponse::new(404),
+                },
+                "DELETE" => match self.nodes.remove(&path) {
+                    Some(_) => TestResponse::new(204),
+                    None => TestResponse::new(404),
+                },
+                "MOVE" => {
+                    let destination = request.header("destination").unwrap_or_default();
+                    let destination = destination.split_once("://").and_then(|(_, rest)| rest.find('/').map(|start| &rest[start..])).unwrap_or(destination);
+                    let destination = percent_decode(destination).trim_end_matches('/').to_string();
+                    if node.is_none() {
+                        return TestResponse::new(404);
+                    }
+                    let existed = self.nodes.contains_key(&destination);
+                    if existed && request.header("overwrite") != Some("T") {
+                        return TestResponse::new(412);
+                    }
+                    let moved: Vec<String> = self.nodes.keys().filter(|key| **key == path || key.starts_with(&format!("{}/", path))).cloned().collect();
+                    for key in moved {
+                        let node = self.nodes.remove(&key).unwrap();
+                        self.nodes.insert(format!("{}{}", destination, &key[path.len()..]), node);
+                    }
+                    TestResponse::new(if existed { 204 } else { 201 })
+                },
+                "PROPPATCH" => {
+                    let body = String::from_utf8_lossy(&request.body).to_string();
+                    let status = match (element(&body, "lastmodified").and_then(|secs| secs.parse().ok()), self.nodes.get_mut(&path)) {
+                        (Some(secs), Some(node)) if !self.refuse_proppatch => {
+                            node.modified = secs;
+                            "HTTP/1.1 200 OK"
+                        },
+                        _ => "HTTP/1.1 403 Forbidden",
+                    };
+                    multistatus(format!("<d:response><d:href>{}</d:href><d:propstat><d:prop><d:lastmodified/></d:prop><d:status>{}</d:status></d:propstat></d:response>", request.path, status))
+                },
+                _ => TestResponse::new(405),
+            }
+        }
+
+        // The `response` element of `path`. Files are given with a whole URL, like some servers do.
+        fn propstat(&self, path: &str, request: &TestRequest) -> String {
+            let node = self.nodes.get(path).cloned().unwrap_or(Node { data: None, modified: 0 });
+            let (href, props) = match &node.data {
+                None => (format!("{}/", uri_encode(path, true)), String::from("<d:resourcetype><d:collection/></d:resourcetype>")),
+                Some(data) => (
+                    format!("http://{}{}", request.header("host").unwrap_or_default(), uri_encode(path, true)),
+                    format!("<d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>", data.len()),
+                ),
+            };
+            format!(
+                "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}<d:getlastmodified>{}</d:getlastmodified></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+                href,
+                props,
+                http_date(node.modified)
+            )
+        }
+    }
+
+    fn mock_server() -> (WebDavFs, Arc<Mutex<MockDav>>) {
+        let mock = Arc::new(Mutex::new(MockDav::default()));
+        let state = Arc::clone(&mock);
+        let address = test_server::start(move |request| state.lock().unwrap().handle(request));
+        (WebDavFs::connect(&target(&address), Path::new("/")).unwrap(), mock)
+    }
+
+    #[test]
+    fn propfind_responses_are_parsed() {
+        // A listing in the style of Nextcloud: prefixed elements, encoded hrefs, and a property that isn't found
+        let listing = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+ <d:response><d:href>/remote.php/dav/files/me/photos/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:getlastmodified>Sat, 31 Jan 2026 23:59:59 GMT</d:getlastmodified></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+  <d:propstat><d:prop><d:getcontentlength/></d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>
+ </d:response>
+ <d:response><d:href>/remote.php/dav/files/me/photos/summer%202025/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:getlastmodified>Sat, 31 Jan 2026 23:59:59 GMT</d:getlastmodified></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+ </d:response>
+ <d:response><d:href>https://cloud.example.com/remote.php/dav/files/me/photos/caf%C3%A9%20%26%20co.jpg</d:href>
+  <d:propstat><d:prop><d:resourcetype/><d:getcontentlength>1234</d:getcontentlength><d:getlastmodified>Thu, 01 Jan 2026 00:00:10 GMT</d:getlastmodified></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+ </d:response>
+</d:multistatus>"#;
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&requests);
+        let address = test_server::start(move |request| {
+            let depth = request.header("depth").map(String::from);
+            seen.lock().unwrap().push((request.method, request.path, depth));
+            TestResponse::new(207).body(listing)
+        });
+        let fs = WebDavFs::connect(&target(&address), Path::new("/remote.php/dav/files/me/photos")).unwrap();
+        let mut entries = fs.read_dir(Path::new("/remote.php/dav/files/me/photos")).unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "café & co.jpg");
+        assert!(!entries[0].stat.is_dir());
+        assert_eq!(entries[0].stat.length, 1234);
+        assert_eq!(entries[0].stat.modified, UNIX_EPOCH + Duration::from_secs(1_767_225_610));
+        assert_eq!(entries[1].name, "summer 2025");
+        assert!(entries[1].stat.is_dir());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.last().unwrap(), &(String::from("PROPFIND"), String::from("/remote.php/dav/files/me/photos/"), Some(String::from("1"))));
+    }
+
+    #[test]
+    fn missing_parents_are_created_in_order() {
+        let (fs, mock) = mock_server();
+        fs.create_dir_all(Path::new("/backup")).unwrap();
+        mock.lock().unwrap().requests.clear();
+
+        fs.create_dir_all(Path::new("/backup/new dir/photos")).unwrap();
+        let mock = mock.lock().unwrap();
+        let mkcols: Vec<&str> = mock.requests.iter().filter(|request| request.method == "MKCOL").map(|request| request.path.as_str()).collect();
+        // The existing collection answers 405, which isn't an error
+        assert_eq!(mkcols, ["/backup", "/backup/new dir", "/backup/new dir/photos"]);
+        assert!(mock.exists_dir("/backup/new dir/photos"));
+    }
+
+    #[test]
+    fn files_are_written_and_moved_over_existing_ones() {
+        let (fs, mock) = mock_server();
+        fs.create_dir_all(Path::new("/backup")).unwrap();
+        fs.write(Path::new("/backup/a.txt"), b"new").unwrap();
+        fs.write(Path::new("/backup/b.txt"), b"old").unwrap();
+        assert_eq!(fs.read(Path::new("/backup/a.txt")).unwrap(), b"new");
+        assert_eq!(fs.stat(Path::new("/backup/a.txt")).unwrap().length, 3);
+
+        fs.rename(Path::new("/backup/a.txt"), Path::new("/backup/b.txt")).unwrap();
+        assert!(!fs.exists(Path::new("/backup/a.txt")));
+        assert_eq!(fs.read(Path::new("/backup/b.txt")).unwrap(), b"new");
+        let mock = mock.lock().unwrap();
+        let request = mock.requests.iter().find(|request| request.method == "MOVE").unwrap();
+        assert_eq!(request.header("overwrite"), Some("T"));
+        assert!(request.header("destination").unwrap().ends_with("/backup/b.txt"));
+    }
+
+    #[test]
+    fn modified_times_are_set_with_proppatch() {
+        let (fs, mock) = mock_server();
+        fs.write(Path::new("/a.txt"), b"a").unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs.set_times(Path::new("/a.txt"), modified).unwrap();
+        assert_eq!(fs.stat(Path::new("/a.txt")).unwrap().modified, modified);
+
+        // A server that refuses the property doesn't support modified times
+        mock.lock().unwrap().refuse_proppatch = true;
+        let err = fs.set_times(Path::new("/a.txt"), UNIX_EPOCH).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(fs.stat(Path::new("/a.txt")).unwrap().modified, modified);
+    }
+}
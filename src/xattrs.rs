@@ -0,0 +1,106 @@
+//! Extended attributes and POSIX ACLs.
+//!
+//! Linux stores the ACLs of a file in the extended attributes `system.posix_acl_access` and
+//! `system.posix_acl_default`, so both are read and written with the same system calls:
+//! * `--xattrs` copies every other attribute: `user.*`, SELinux labels (`security.selinux`), and `trusted.*` when ebod
+//!   runs as root
+//! * `--acls` copies the ACLs
+//!
+//! The listing records a hash of each set in `FileEntry`, so that a file whose attributes alone changed is updated.
+//! Destinations without extended attributes, like FAT and exFAT or the remote backends, get a warning and the
+//! attributes are left out.
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::FileEntry;
+use crate::vfs::Vfs;
+
+/// The extended attributes that hold the POSIX ACLs.
+pub const ACL_NAMES: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+fn is_acl(name: &str) -> bool {
+    ACL_NAMES.contains(&name)
+}
+
+/// The attributes copied by a backup, set with `--xattrs` and `--acls`.
+///
+/// # Members
+/// * `xattrs: bool` -> Copies the extended attributes that aren't ACLs
+/// * `acls: bool` -> Copies the POSIX ACLs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Preserve {
+    pub xattrs: bool,
+    pub acls: bool,
+}
+
+impl Preserve {
+    /// Whether any attribute is copied.
+    pub fn any(&self) -> bool {
+        self.xattrs || self.acls
+    }
+
+    fn wants(&self, name: &str) -> bool {
+        if is_acl(name) { self.acls } else { self.xattrs }
+    }
+
+    /// Whether the copied attributes of the source entry `src` differ from the ones of the destination entry `dest`.
+    pub fn differs(&self, src: &FileEntry, dest: &FileEntry) -> bool {
+        (self.xattrs && src.xattrs != dest.xattrs) || (self.acls && src.acls != dest.acls)
+    }
+}
+
+// A short hash of a set of attributes, or `None` if it is empty
+fn hash<'a>(attrs: impl Iterator<Item = &'a (String, Vec<u8>)>) -> Option<String> {
+    let mut hasher = blake3::Hasher::new();
+    let mut empty = true;
+    for (name, value) in attrs {
+        hasher.update(name.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value);
+        empty = false;
+    }
+    (!empty).then(|| hasher.finalize().to_hex()[..16].to_string())
+}
+
+/// The hashes of the extended attributes and of the ACLs of `path`: `(xattrs, acls)`. A hash is `None` if the set is
+/// empty, or if `vfs` has no extended attributes.
+pub fn hashes<V: Vfs + ?Sized>(vfs: &V, path: &Path) -> (Option<String>, Option<String>) {
+    let Ok(mut attrs) = vfs.xattrs(path) else {
+        return (None, None);
+    };
+    attrs.sort();
+    (hash(attrs.iter().filter(|(name, _)| !is_acl(name))), hash(attrs.iter().filter(|(name, _)| is_acl(name))))
+}
+
+/// Copies the attributes of `src` selected by `preserve` to `dest`. The selected attributes of `dest` that `src`
+/// doesn't have are removed.
+///
+/// # Output: `io::Result<()>`
+/// `ErrorKind::Unsupported` if either side has no extended attributes. The other attributes are still copied when one
+/// of them fails, and the first error is returned.
+pub fn copy<S: Vfs + ?Sized, D: Vfs + ?Sized>(src_vfs: &S, src: &Path, dest_vfs: &D, dest: &Path, preserve: Preserve) -> io::Result<()> {
+    if !preserve.any() {
+        return Ok(());
+    }
+    let wanted: Vec<(String, Vec<u8>)> = src_vfs.xattrs(src)?.into_iter().filter(|(name, _)| preserve.wants(name)).collect();
+    let existing = dest_vfs.xattrs(dest)?;
+    let mut result = Ok(());
+    for (name, _) in existing.iter().filter(|(name, _)| preserve.wants(name) && !wanted.iter().any(|(wanted, _)| wanted == name)) {
+        if let Err(err) = dest_vfs.remove_xattr(dest, name) {
+            result = result.and(Err(io::Error::new(err.kind(), format!("Couldn't remove {}: {}", name, err))));
+        }
+    }
+    for (name, value) in &wanted {
+        if existing.iter().any(|(other, other_value)| other == name && other_value == value) {
+            continue;
+        }
+        if let Err(err) = dest_vfs.set_xattr(dest, name, value) {
+            if err.kind() == ErrorKind::Unsupported {
+                return Err(err);
+            }
+            result = result.and(Err(io::Error::new(err.kind(), format!("Couldn't set {}: {}", name, err))));
+        }
+    }
+    result
+}
@@ -0,0 +1,361 @@
+//! Zip archive output target with incremental updates.
+//!
+//! The `FileEntry` metadata of the archived files is stored in the comment of the zip file. When it doesn't fit into
+//! the comment (65535 bytes), it is stored in the member `.ebod/metadata.json` instead.
+//!
+//! On every backup the stored metadata is compared with the metadata of the source directory:
+//! * If only new files have to be added, they are appended to the existing zip file in place.
+//! * If files were modified, the zip file is rebuilt. The unchanged members are copied over without being
+//!   decompressed and compressed again, and only the modified files are read from the source.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::archive::{METADATA_MEMBER, inside_symlink};
+use crate::output::{self, Event};
+use crate::{EntryType, FileEntry, LogType, log, read_metadata, utc_datetime};
+
+/// The prefix of the zip comment that holds the metadata.
+const COMMENT_PREFIX: &str = "ebod-metadata:";
+/// The zip comment that tells that the metadata is stored in the `.ebod/metadata.json` member.
+const COMMENT_MEMBER: &str = "ebod-metadata-member";
+const MAX_COMMENT_LEN: usize = u16::MAX as usize;
+
+// Zip member names always use `/` as the separator, and directories (but not symlinks to them) end with it.
+fn member_name(src: &Path, file: &FileEntry) -> String {
+    let name = file.name.replace('\\', "/");
+    if file.e_type == EntryType::Dir && !src.join(&file.name).is_symlink() { format!("{}/", name) } else { name }
+}
+
+// A directory member has no content, so it isn't rewritten just because files were added to the directory
+fn unchanged(old: &FileEntry, new: &FileEntry) -> bool {
+    old.name == new.name
+        && old.e_type == new.e_type
+        && (new.e_type == EntryType::Dir || (old.mtime_diff(new).is_zero() && old.length == new.length))
+}
+
+// Converts the UNIX timestamp into the MS-DOS date and time used by zip. MS-DOS times have no time zone and are shown
+// as local times by the zip tools, so the local time is written. Zip can't represent dates before 1980.
+fn zip_time(secs: u64) -> DateTime {
+    let (year, month, day, hour, minute, second) = utc_datetime(secs.saturating_add_signed(local_offset(secs)));
+    DateTime::from_date_and_time(year, month, day, hour, minute, second).unwrap_or_default()
+}
+
+// The offset of the local time zone from UTC at `secs`, in seconds
+#[cfg(unix)]
+fn local_offset(secs: u64) -> i64 {
+    let time = secs as libc::time_t;
+    // SAFETY: `tm` is a plain C struct for which all zeroes is valid, and localtime_r only writes into it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    // `c_long` is only 32 bits wide on some targets
+    #[allow(clippy::useless_conversion)]
+    i64::from(tm.tm_gmtoff)
+}
+
+// Without the time zone database, the times are written in UTC
+#[cfg(not(unix))]
+fn local_offset(_secs: u64) -> i64 {
+    0
+}
+
+/// Reads the metadata stored in a zip file that was written by `backup_to_zip`.
+///
+/// # Input
+/// * `archive: &mut ZipArchive<R>` -> The opened zip file
+///
+/// # Output: `Result<Vec<FileEntry>, String>`
+/// The stored metadata or an error message if the zip file doesn't contain it.
+pub fn read_zip_metadata<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Vec<FileEntry>, String> {
+    let comment = String::from_utf8_lossy(archive.comment()).to_string();
+    if let Some(json) = comment.strip_prefix(COMMENT_PREFIX) {
+        return serde_json::from_str(json).map_err(|_| String::from("Error in parsing the metadata in the zip comment"));
+    }
+    if let Ok(mut member) = archive.by_name(METADATA_MEMBER) {
+        let mut content = String::new();
+        member.read_to_string(&mut content).map_err(|_| String::from("Error in reading the metadata member"))?;
+        return serde_json::from_str(&content).map_err(|_| String::from("Error in parsing the metadata member"));
+    }
+    Err(String::from("The zip file doesn't contain ebod metadata"))
+}
+
+/// Backs up the initialized `src` directory into a zip file. If the zip file exists, only the new and modified files
+/// are written into it.
+///
+/// # Input
+/// * `src: &Path` -> The source directory. It must be initialized
+/// * `zip_path: &Path` -> The path of the zip file
+///
+/// # Output: `Result<(), String>`
+///
+/// # Rules followed:
+/// 1. Entries under `.ebod/` of the source and the contents of symlinked directories are never archived.
+/// 2. A file is written if it isn't in the stored metadata, or if its type, `modified_at` or `length` differs.
+///    A directory is only written if it isn't in the stored metadata.
+/// 3. Members of files that were deleted from the source are kept, like `backup` keeps them in the destination.
+pub fn backup_to_zip(src: &Path, zip_path: &Path) -> Result<(), String> {
+    let src_meta: Vec<FileEntry> = read_metadata(&src.join(".ebod/metadata.json"))?
+        .into_iter()
+        .filter(|file| !Path::new(&file.name).starts_with(".ebod") && !inside_symlink(src, &file.name))
+        .collect();
+
+    let mut prev_meta: Vec<FileEntry> = vec![];
+    let mut has_member = false;
+    if zip_path.exists() {
+        let file = File::open(zip_path).map_err(|_| format!("Couldn't open {}", zip_path.to_string_lossy()))?;
+        let mut archive = ZipArchive::new(file).map_err(|_| format!("{} is not a valid zip file", zip_path.to_string_lossy()))?;
+        prev_meta = read_zip_metadata(&mut archive)?;
+        has_member = archive.index_for_name(METADATA_MEMBER).is_some();
+    }
+
+    let prev_by_name: HashMap<&str, &FileEntry> = prev_meta.iter().map(|old| (old.name.as_str(), old)).collect();
+    let changed: Vec<&FileEntry> = src_meta.iter().filter(|file| !prev_by_name.get(file.name.as_str()).is_some_and(|old| unchanged(old, file))).collect();
+    // The members of the previous versions of the changed entries, which a rebuild leaves out. An entry that became a
+    // directory or stopped being one changes its member name, and whether an old directory was a symlink isn't
+    // recorded, so both names are left out
+    let modified: HashSet<String> = changed
+        .iter()
+        .filter_map(|file| prev_by_name.get(file.name.as_str()))
+        .flat_map(|old| {
+            let name = old.name.replace('\\', "/");
+            [format!("{}/", name), name]
+        })
+        .collect();
+    if zip_path.exists() && changed.is_empty() {
+        log(LogType::Info, &format!("{} is up to date", zip_path.to_string_lossy()));
+        return Ok(());
+    }
+
+    // The new metadata describes every member of the zip file, including the files deleted from the source
+    let src_names: HashSet<&str> = src_meta.iter().map(|file| file.name.as_str()).collect();
+    let mut new_meta: Vec<FileEntry> = prev_meta.into_iter().filter(|old| !src_names.contains(old.name.as_str())).collect();
+    new_meta.extend(src_meta.iter().cloned());
+    let json = serde_json::to_string(&new_meta).map_err(|_| String::from("Error during serializing metadata"))?;
+    let in_comment = COMMENT_PREFIX.len() + json.len() <= MAX_COMMENT_LEN;
+
+    // Appending can't remove the metadata member, so a zip file that has one is rebuilt
+    if zip_path.exists() && modified.is_empty() && !has_member {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(zip_path)
+            .map_err(|_| format!("Couldn't open {}", zip_path.to_string_lossy()))?;
+        let writer = ZipWriter::new_append(file).map_err(|_| format!("Couldn't append to {}", zip_path.to_string_lossy()))?;
+        finish_zip(writer, src, &changed, &json, in_comment)?;
+        log(LogType::Ok, &format!("{} entries were appended to {}", changed.len(), zip_path.to_string_lossy()));
+        return Ok(());
+    }
+
+    let mut tmp_name = zip_path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+    let result = rebuild_zip(zip_path, &tmp, src, &changed, &modified, &json, in_comment);
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    fs::rename(&tmp, zip_path).map_err(|_| format!("Couldn't write {}", zip_path.to_string_lossy()))?;
+    log(LogType::Ok, &format!("{} entries were written into {}", changed.len(), zip_path.to_string_lossy()));
+    Ok(())
+}
+
+fn rebuild_zip(
+    zip_path: &Path,
+    tmp: &Path,
+    src: &Path,
+    changed: &[&FileEntry],
+    modified: &HashSet<String>,
+    json: &str,
+    in_comment: bool,
+) -> Result<(), String> {
+    let output = File::create(tmp).map_err(|_| format!("Couldn't create {}", tmp.to_string_lossy()))?;
+    let mut writer = ZipWriter::new(output);
+    if zip_path.exists() {
+        let file = File::open(zip_path).map_err(|_| format!("Couldn't open {}", zip_path.to_string_lossy()))?;
+        let mut archive = ZipArchive::new(file).map_err(|_| format!("{} is not a valid zip file", zip_path.to_string_lossy()))?;
+        let mut kept = 0;
+        for idx in 0..archive.len() {
+            let member = archive.by_index_raw(idx).map_err(|_| String::from("Error in reading a zip member"))?;
+            if member.name() == METADATA_MEMBER || modified.contains(member.name()) {
+                continue;
+            }
+            writer.raw_copy_file(member).map_err(|_| String::from("Error in copying a zip member"))?;
+            kept += 1;
+        }
+        log(LogType::Info, &format!("{} unchanged members were kept without compressing them again", kept));
+    }
+    finish_zip(writer, src, changed, json, in_comment)
+}
+
+fn finish_zip<W: Write + Seek>(mut writer: ZipWriter<W>, src: &Path, files: &[&FileEntry], json: &str, in_comment: bool) -> Result<(), String> {
+    for file in files {
+        write_member(&mut writer, src, file).map_err(|_| format!("Error in archiving {}", &file.name))?;
+        log(LogType::Ok, &format!("Archived: {}", &file.name));
+        output::emit(if file.e_type == EntryType::Dir {
+            Event::DirCreated { path: file.name.clone() }
+        } else {
+            Event::Copied { path: file.name.clone(), bytes: file.length, method: None }
+        });
+    }
+    if in_comment {
+        writer.set_comment(format!("{}{}", COMMENT_PREFIX, json)).map_err(|_| String::from("Error in storing the metadata"))?;
+    } else {
+        writer
+            .start_file(METADATA_MEMBER, SimpleFileOptions::default())
+            .and_then(|_| writer.write_all(json.as_bytes()).map_err(Into::into))
+            .map_err(|_| String::from("Error in storing the metadata"))?;
+        writer.set_comment(COMMENT_MEMBER).map_err(|_| String::from("Error in storing the metadata"))?;
+    }
+    writer.finish().map_err(|_| String::from("Error in finishing the zip file"))?;
+    Ok(())
+}
+
+fn write_member<W: Write + Seek>(writer: &mut ZipWriter<W>, src: &Path, file: &FileEntry) -> zip::result::ZipResult<()> {
+    let path = src.join(&file.name);
+    let meta = fs::symlink_metadata(&path)?;
+    #[allow(unused_mut)]
+    let mut options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(zip_time(file.modified_at))
+        .large_file(file.length >= u32::MAX as u64);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options = options.unix_permissions(meta.permissions().mode() & 0o7777);
+    }
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(&path)?;
+        writer.add_symlink(member_name(src, file), target.to_string_lossy(), options)
+    } else if file.e_type == EntryType::Dir {
+        writer.add_directory(member_name(src, file), options)
+    } else {
+        writer.start_file(member_name(src, file), options)?;
+        let mut input = File::open(&path)?;
+        io::copy(&mut input, writer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialize_dir;
+    use std::time::{Duration, SystemTime};
+
+    // A source directory and the zip file it is backed up into, removed when the test ends
+    struct Fixture {
+        root: PathBuf,
+        src: PathBuf,
+        zip: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let root = std::env::temp_dir().join(format!("ebod-zip-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("src")).unwrap();
+            Fixture { src: root.join("src"), zip: root.join("backup.zip"), root }
+        }
+
+        // Writes `content` into `name` of the source, modified `age` seconds ago
+        fn write(&self, name: &str, content: &str, age: u64) {
+            let path = self.src.join(name);
+            fs::write(&path, content).unwrap();
+            File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+        }
+
+        fn backup(&self) {
+            initialize_dir(&self.src, false);
+            backup_to_zip(&self.src, &self.zip).unwrap();
+        }
+
+        fn open(&self) -> ZipArchive<File> {
+            ZipArchive::new(File::open(&self.zip).unwrap()).unwrap()
+        }
+
+        fn members(&self) -> Vec<String> {
+            let mut names: Vec<String> = self.open().file_names().map(String::from).collect();
+            names.sort();
+            names
+        }
+
+        fn read(&self, name: &str) -> String {
+            let mut content = String::new();
+            self.open().by_name(name).unwrap().read_to_string(&mut content).unwrap();
+            content
+        }
+
+        #[cfg(unix)]
+        fn inode(&self) -> u64 {
+            std::os::unix::fs::MetadataExt::ino(&fs::metadata(&self.zip).unwrap())
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn zip_files_are_written_appended_and_rebuilt() {
+        let fixture = Fixture::new("updates");
+        fs::create_dir(fixture.src.join("docs")).unwrap();
+        fixture.write("a.txt", "first", 3600);
+        fixture.write("docs/b.txt", "b", 3600);
+        fixture.backup();
+        assert_eq!(fixture.members(), ["a.txt", "docs/", "docs/b.txt"]);
+        assert!(fixture.open().comment().starts_with(COMMENT_PREFIX.as_bytes()));
+        assert_eq!(read_zip_metadata(&mut fixture.open()).unwrap().len(), 3);
+        #[cfg(unix)]
+        let inode = fixture.inode();
+
+        // A new file is appended in place
+        fixture.write("c.txt", "c", 3600);
+        fixture.backup();
+        assert_eq!(fixture.members(), ["a.txt", "c.txt", "docs/", "docs/b.txt"]);
+        #[cfg(unix)]
+        assert_eq!(fixture.inode(), inode);
+
+        // A modified file makes the zip file rebuilt, with a single member for it
+        fixture.write("a.txt", "second version", 60);
+        fixture.backup();
+        assert_eq!(fixture.members(), ["a.txt", "c.txt", "docs/", "docs/b.txt"]);
+        assert_eq!(fixture.read("a.txt"), "second version");
+        assert_eq!(fixture.read("docs/b.txt"), "b");
+        #[cfg(unix)]
+        assert_ne!(fixture.inode(), inode);
+
+        // A file that became a directory replaces its old member
+        fs::remove_file(fixture.src.join("c.txt")).unwrap();
+        fs::create_dir(fixture.src.join("c.txt")).unwrap();
+        fixture.backup();
+        assert_eq!(fixture.members(), ["a.txt", "c.txt/", "docs/", "docs/b.txt"]);
+    }
+
+    #[test]
+    fn large_metadata_is_stored_in_a_member() {
+        let fixture = Fixture::new("member");
+        for i in 0..600 {
+            fixture.write(&format!("file-with-a-rather-long-name-{:04}.txt", i), "x", 3600);
+        }
+        fixture.backup();
+        assert_eq!(fixture.open().comment(), COMMENT_MEMBER.as_bytes());
+        assert!(fixture.members().contains(&String::from(METADATA_MEMBER)));
+        assert_eq!(read_zip_metadata(&mut fixture.open()).unwrap().len(), 600);
+
+        // The zip file is rebuilt rather than appended to, so that it keeps a single metadata member
+        fixture.write("new.txt", "new", 3600);
+        fixture.backup();
+        let members = fixture.members();
+        assert_eq!(members.iter().filter(|name| *name == METADATA_MEMBER).count(), 1);
+        assert_eq!(members.len(), 602);
+        assert_eq!(read_zip_metadata(&mut fixture.open()).unwrap().len(), 601);
+    }
+}
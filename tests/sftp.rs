@@ -0,0 +1,129 @@
+//! Backups to an SFTP destination, served by a local OpenSSH `sftp-server` through `EBOD_SFTP_COMMAND`.
+//!
+//! The tests are skipped when no `sftp-server` binary is found. `EBOD_TEST_SFTP_SERVER` can point to one that isn't in
+//! a standard location.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use serde_json::Value;
+
+// Where the distributions install `sftp-server`
+const SERVER_PATHS: [&str; 4] = ["/usr/lib/openssh/sftp-server", "/usr/libexec/openssh/sftp-server", "/usr/libexec/sftp-server", "/usr/lib/ssh/sftp-server"];
+
+fn sftp_server() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("EBOD_TEST_SFTP_SERVER") {
+        return Some(PathBuf::from(path));
+    }
+    let in_path = std::env::var_os("PATH").into_iter().flat_map(|path| std::env::split_paths(&path).collect::<Vec<_>>()).map(|dir| dir.join("sftp-server"));
+    SERVER_PATHS.iter().map(PathBuf::from).chain(in_path).find(|path| path.is_file())
+}
+
+// A directory removed when the test ends
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("ebod-sftp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Runs `ebod backup` from `src` into `dest` over SFTP, and returns the summary of the run
+fn backup(server: &Path, src: &Path, dest: &Path, args: &[&str]) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_ebod"))
+        .args(["--output", "json", "backup"])
+        .arg(src)
+        .arg(format!("sftp://localhost{}", dest.to_string_lossy()))
+        .args(args)
+        .env("EBOD_SFTP_COMMAND", server)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let record: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(record["summary"]["errors"], 0, "{}", String::from_utf8_lossy(&output.stderr));
+    record["summary"].clone()
+}
+
+// The entry `name` of the index stored in the destination
+fn indexed(dest: &Path, name: &str) -> Option<Value> {
+    let index: Vec<Value> = serde_json::from_str(&fs::read_to_string(dest.join(".ebod/metadata.json")).unwrap()).unwrap();
+    index.into_iter().find(|entry| entry["name"] == name)
+}
+
+fn set_modified(path: &Path, modified: SystemTime) {
+    fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+}
+
+#[test]
+fn backup_over_sftp() {
+    let Some(server) = sftp_server() else {
+        eprintln!("No sftp-server binary was found, skipping the SFTP test");
+        return;
+    };
+    let src = TempDir::new("src");
+    let dest = TempDir::new("dest");
+    let dest = dest.0.join("backup");
+    let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    fs::create_dir_all(src.0.join("docs")).unwrap();
+    fs::write(src.0.join("a.txt"), "first version").unwrap();
+    fs::write(src.0.join("docs/b.txt"), "b").unwrap();
+    set_modified(&src.0.join("a.txt"), hour_ago);
+
+    // The files are uploaded with the modified time of their source, and the index of the destination is written
+    let summary = backup(&server, &src.0, &dest, &[]);
+    assert_eq!(summary["copied"], 2);
+    assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "first version");
+    assert_eq!(fs::read_to_string(dest.join("docs/b.txt")).unwrap(), "b");
+    let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    assert_eq!(modified(&dest.join("a.txt")), modified(&src.0.join("a.txt")));
+    assert_eq!(indexed(&dest, "a.txt").unwrap()["length"], 13);
+    assert!(indexed(&dest, "docs/b.txt").is_some());
+
+    // Nothing changed, so nothing is uploaded
+    let summary = backup(&server, &src.0, &dest, &[]);
+    assert_eq!(summary["copied"], 0);
+    assert_eq!(summary["overwritten"], 0);
+
+    // A modified file is overwritten, a new one is uploaded, and both are recorded in the index
+    fs::write(src.0.join("a.txt"), "second version!").unwrap();
+    fs::write(src.0.join("c.txt"), "c").unwrap();
+    let summary = backup(&server, &src.0, &dest, &["--conflict", "overwrite"]);
+    assert_eq!(summary["overwritten"], 1);
+    assert_eq!(summary["copied"], 1);
+    assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "second version!");
+    assert_eq!(indexed(&dest, "a.txt").unwrap()["length"], 15);
+    assert!(indexed(&dest, "c.txt").is_some());
+}
+
+#[test]
+fn large_modified_files_are_replaced_over_sftp() {
+    let Some(server) = sftp_server() else {
+        eprintln!("No sftp-server binary was found, skipping the SFTP test");
+        return;
+    };
+    let src = TempDir::new("delta-src");
+    let dest = TempDir::new("delta-dest");
+    let mut data: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    fs::write(src.0.join("image.bin"), &data).unwrap();
+    set_modified(&src.0.join("image.bin"), SystemTime::now() - Duration::from_secs(3600));
+    backup(&server, &src.0, &dest.0, &[]);
+
+    // Updated with a delta when the server supports `copy-data`, and in full otherwise
+    data[1_000_000..1_000_100].fill(0);
+    data.extend_from_slice(b"tail");
+    fs::write(src.0.join("image.bin"), &data).unwrap();
+    let summary = backup(&server, &src.0, &dest.0, &["--conflict", "overwrite"]);
+    assert_eq!(summary["overwritten"], 1);
+    assert_eq!(fs::read(dest.0.join("image.bin")).unwrap(), data);
+    assert!(!dest.0.join(".image.bin.ebod-delta").exists());
+}